@@ -0,0 +1,8 @@
+create table if not exists review_queue (
+	id serial primary key,
+	item_type varchar(16) not null,
+	item_id int not null,
+	field varchar(32) not null,
+	matched_words text [] not null,
+	created_on timestamp not null default now()
+);
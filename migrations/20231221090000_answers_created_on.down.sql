@@ -0,0 +1,4 @@
+drop index if exists answers_created_on_id_idx;
+drop index if exists questions_created_on_id_idx;
+
+alter table answers drop column if exists created_on;
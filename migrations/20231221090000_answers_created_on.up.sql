@@ -0,0 +1,4 @@
+alter table answers add column if not exists created_on timestamp not null default now();
+
+create index if not exists questions_created_on_id_idx on questions (created_on, id);
+create index if not exists answers_created_on_id_idx on answers (created_on, id);
@@ -0,0 +1,15 @@
+drop index if exists answers_updated_at_id_idx;
+drop index if exists answers_created_at_id_idx;
+drop index if exists questions_updated_at_id_idx;
+drop index if exists questions_created_at_id_idx;
+
+alter table answers drop column if exists updated_at;
+alter table answers alter column created_at type timestamp using created_at at time zone 'UTC';
+alter table answers rename column created_at to created_on;
+
+alter table questions drop column if exists updated_at;
+alter table questions alter column created_at type timestamp using created_at at time zone 'UTC';
+alter table questions rename column created_at to created_on;
+
+create index if not exists questions_created_on_id_idx on questions (created_on, id);
+create index if not exists answers_created_on_id_idx on answers (created_on, id);
@@ -0,0 +1,22 @@
+alter table questions rename column created_on to created_at;
+alter table questions alter column created_at type timestamptz using created_at at time zone 'UTC';
+alter table questions alter column created_at set default now();
+alter table questions add column updated_at timestamptz;
+update questions set updated_at = created_at;
+alter table questions alter column updated_at set not null;
+alter table questions alter column updated_at set default now();
+
+alter table answers rename column created_on to created_at;
+alter table answers alter column created_at type timestamptz using created_at at time zone 'UTC';
+alter table answers alter column created_at set default now();
+alter table answers add column updated_at timestamptz;
+update answers set updated_at = created_at;
+alter table answers alter column updated_at set not null;
+alter table answers alter column updated_at set default now();
+
+drop index if exists questions_created_on_id_idx;
+drop index if exists answers_created_on_id_idx;
+create index if not exists questions_created_at_id_idx on questions (created_at, id);
+create index if not exists questions_updated_at_id_idx on questions (updated_at, id);
+create index if not exists answers_created_at_id_idx on answers (created_at, id);
+create index if not exists answers_updated_at_id_idx on answers (updated_at, id);
@@ -0,0 +1,7 @@
+create table if not exists users (
+	id serial primary key,
+	email varchar(255) not null unique,
+	password varchar(255) not null,
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
@@ -0,0 +1,5 @@
+drop index if exists answers_account_id_idx;
+drop index if exists questions_account_id_idx;
+
+alter table answers drop column if exists account_id;
+alter table questions drop column if exists account_id;
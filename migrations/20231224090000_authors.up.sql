@@ -0,0 +1,7 @@
+-- Rows written before accounts existed have no author and can only be
+-- changed by moderators
+alter table questions add column if not exists account_id int references users on delete set null;
+alter table answers add column if not exists account_id int references users on delete set null;
+
+create index if not exists questions_account_id_idx on questions (account_id);
+create index if not exists answers_account_id_idx on answers (account_id);
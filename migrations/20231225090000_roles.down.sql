@@ -0,0 +1,7 @@
+alter table questions drop column if exists closed_at;
+
+drop table if exists role_audit_log;
+drop table if exists user_roles;
+drop table if exists role_permissions;
+drop table if exists permissions;
+drop table if exists roles;
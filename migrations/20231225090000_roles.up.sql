@@ -0,0 +1,48 @@
+create table if not exists roles (
+	name varchar(32) primary key
+);
+
+create table if not exists permissions (
+	name varchar(64) primary key
+);
+
+create table if not exists role_permissions (
+	role varchar(32) not null references roles on delete cascade,
+	permission varchar(64) not null references permissions on delete cascade,
+	primary key (role, permission)
+);
+
+-- The first admin has to be granted directly, e.g.
+--   insert into user_roles (account_id, role) values (1, 'admin');
+create table if not exists user_roles (
+	account_id int not null references users on delete cascade,
+	role varchar(32) not null references roles on delete cascade,
+	granted_by int references users on delete set null,
+	granted_at timestamptz not null default now(),
+	primary key (account_id, role)
+);
+
+-- Every grant and revoke, kept after the user or role is gone
+create table if not exists role_audit_log (
+	id serial primary key,
+	actor_id int,
+	account_id int not null,
+	role varchar(32) not null,
+	action varchar(8) not null check (action in ('grant', 'revoke')),
+	created_at timestamptz not null default now()
+);
+
+insert into roles (name) values ('moderator'), ('admin') on conflict do nothing;
+insert into permissions (name) values ('content.moderate'), ('roles.manage'), ('questions.close')
+on conflict do nothing;
+insert into role_permissions (role, permission) values
+	('moderator', 'content.moderate'),
+	('admin', 'content.moderate'),
+	('admin', 'roles.manage'),
+	('moderator', 'questions.close'),
+	('admin', 'questions.close')
+on conflict do nothing;
+
+-- Closed questions take no new answers
+alter table questions add column if not exists closed_at timestamptz;
+
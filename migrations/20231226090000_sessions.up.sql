@@ -0,0 +1,14 @@
+-- One row per login. Only a hash of the refresh token is kept, so a leak of
+-- this table can't be used to mint access tokens.
+create table if not exists sessions (
+	id serial primary key,
+	account_id int not null references users on delete cascade,
+	refresh_token_hash varchar(64) not null unique,
+	user_agent varchar(255),
+	created_at timestamptz not null default now(),
+	last_used_at timestamptz not null default now(),
+	expires_at timestamptz not null,
+	revoked_at timestamptz
+);
+
+create index if not exists sessions_account_id_idx on sessions (account_id);
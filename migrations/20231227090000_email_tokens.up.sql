@@ -0,0 +1,23 @@
+alter table users add column if not exists email_verified_at timestamptz;
+
+-- Links mailed for verifying an email address or resetting a password. Like
+-- refresh tokens, only a hash is kept. A token is spent by setting used_at.
+create table if not exists email_verification_tokens (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	created_at timestamptz not null default now(),
+	expires_at timestamptz not null,
+	used_at timestamptz
+);
+
+create index if not exists email_verification_tokens_account_id_idx on email_verification_tokens (account_id);
+
+create table if not exists password_reset_tokens (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	created_at timestamptz not null default now(),
+	expires_at timestamptz not null,
+	used_at timestamptz
+);
+
+create index if not exists password_reset_tokens_account_id_idx on password_reset_tokens (account_id);
@@ -0,0 +1,4 @@
+drop table if exists oidc_logins;
+drop table if exists user_identities;
+delete from users where password is null;
+alter table users alter column password set not null;
@@ -0,0 +1,25 @@
+-- Accounts created through single sign-on have no password until they set
+-- one with a reset link
+alter table users alter column password drop not null;
+
+-- Which provider account signs in to which local account
+create table if not exists user_identities (
+	issuer varchar(255) not null,
+	subject varchar(255) not null,
+	account_id int not null references users on delete cascade,
+	created_at timestamptz not null default now(),
+	last_login_at timestamptz not null default now(),
+	primary key (issuer, subject)
+);
+
+create index if not exists user_identities_account_id_idx on user_identities (account_id);
+
+-- Logins started at the provider but not finished yet, keyed by the state
+-- parameter sent along
+create table if not exists oidc_logins (
+	state varchar(64) primary key,
+	pkce_verifier varchar(128) not null,
+	nonce varchar(64) not null,
+	created_at timestamptz not null default now(),
+	expires_at timestamptz not null
+);
@@ -0,0 +1,30 @@
+-- TOTP second factor. The secret has to be readable to check codes, so it
+-- is kept as is; totp_enabled_at stays null until enrollment is confirmed
+-- with a valid code. totp_last_step stops a code being used twice.
+alter table users
+	add column if not exists totp_secret varchar(64),
+	add column if not exists totp_enabled_at timestamptz,
+	add column if not exists totp_last_step bigint;
+
+-- One-off codes for when the authenticator is lost, stored hashed
+create table if not exists recovery_codes (
+	account_id int not null references users on delete cascade,
+	code_hash varchar(64) not null,
+	created_at timestamptz not null default now(),
+	used_at timestamptz,
+	primary key (account_id, code_hash)
+);
+
+-- Logins that passed the first factor and wait for a TOTP or recovery code
+create table if not exists login_challenges (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	user_agent varchar(255),
+	created_at timestamptz not null default now(),
+	expires_at timestamptz not null,
+	failed_attempts int not null default 0,
+	used_at timestamptz
+);
+
+-- Whether a session was started with a second factor
+alter table sessions add column if not exists mfa boolean not null default false;
@@ -0,0 +1,9 @@
+-- Failed logins, counted per key: `account:<email>` for the account tried
+-- (also for emails without one, so lockouts don't reveal which exist) and
+-- `ip:<address>` for the client. Shared by all instances.
+create table if not exists login_failures (
+	key varchar(300) primary key,
+	failures int not null default 0,
+	last_failure_at timestamptz not null default now(),
+	locked_until timestamptz
+);
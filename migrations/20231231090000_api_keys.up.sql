@@ -0,0 +1,16 @@
+-- Keys for automated clients, sent in the X-Api-Key header. Only a hash of
+-- the key is kept; prefix is its first characters, to tell keys apart.
+create table if not exists api_keys (
+	id serial primary key,
+	account_id int not null references users on delete cascade,
+	name varchar(100) not null,
+	prefix varchar(16) not null,
+	key_hash varchar(64) not null unique,
+	scopes varchar(32)[] not null,
+	created_at timestamptz not null default now(),
+	last_used_at timestamptz,
+	expires_at timestamptz,
+	revoked_at timestamptz
+);
+
+create index if not exists api_keys_account_id_idx on api_keys (account_id);
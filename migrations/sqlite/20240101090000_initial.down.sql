@@ -0,0 +1,19 @@
+drop table if exists api_keys;
+drop table if exists login_failures;
+drop table if exists login_challenges;
+drop table if exists recovery_codes;
+drop table if exists oidc_logins;
+drop table if exists user_identities;
+drop table if exists password_reset_tokens;
+drop table if exists email_verification_tokens;
+drop table if exists sessions;
+drop table if exists role_audit_log;
+drop table if exists user_roles;
+drop table if exists role_permissions;
+drop table if exists permissions;
+drop table if exists roles;
+drop table if exists review_queue;
+drop table if exists answers;
+drop table if exists question_tags;
+drop table if exists questions;
+drop table if exists users;
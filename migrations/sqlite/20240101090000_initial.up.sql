@@ -0,0 +1,201 @@
+-- The whole Postgres schema as of 20231231090000_api_keys, for SQLite.
+-- Timestamps are RFC 3339 text in UTC with exactly six decimals and a Z,
+-- e.g. 2024-01-03T09:00:00.123456Z, always written by the store. Fixed
+-- width, so they compare correctly as text. Tags get a table of their own; other arrays
+-- are stored as JSON text.
+create table if not exists users (
+	id integer primary key autoincrement,
+	email varchar(255) not null unique,
+	password varchar(255),
+	created_at text not null,
+	updated_at text not null,
+	email_verified_at text,
+	totp_secret varchar(64),
+	totp_enabled_at text,
+	totp_last_step bigint
+);
+
+create table if not exists questions (
+	id integer primary key autoincrement,
+	title varchar(255) not null,
+	content text,
+	account_id int references users on delete set null,
+	created_at text not null,
+	updated_at text not null,
+	-- Closed questions take no new answers
+	closed_at text
+);
+
+create index if not exists questions_created_at_id_idx on questions (created_at, id);
+create index if not exists questions_updated_at_id_idx on questions (updated_at, id);
+create index if not exists questions_account_id_idx on questions (account_id);
+
+-- A question's tags in the order they were given
+create table if not exists question_tags (
+	question_id int not null references questions on delete cascade,
+	position int not null,
+	tag text not null,
+	primary key (question_id, position)
+);
+
+create index if not exists question_tags_tag_idx on question_tags (tag);
+
+create table if not exists answers (
+	id integer primary key autoincrement,
+	content text,
+	question_id int references questions,
+	account_id int references users on delete set null,
+	created_at text not null,
+	updated_at text not null
+);
+
+create index if not exists answers_created_at_id_idx on answers (created_at, id);
+create index if not exists answers_updated_at_id_idx on answers (updated_at, id);
+create index if not exists answers_question_id_idx on answers (question_id);
+create index if not exists answers_account_id_idx on answers (account_id);
+
+create table if not exists review_queue (
+	id integer primary key autoincrement,
+	item_type varchar(16) not null,
+	item_id int not null,
+	field varchar(32) not null,
+	matched_words text not null,
+	created_at text not null
+);
+
+create table if not exists roles (
+	name varchar(32) primary key
+);
+
+create table if not exists permissions (
+	name varchar(64) primary key
+);
+
+create table if not exists role_permissions (
+	role varchar(32) not null references roles on delete cascade,
+	permission varchar(64) not null references permissions on delete cascade,
+	primary key (role, permission)
+);
+
+-- The first admin has to be granted directly, e.g.
+--   insert into user_roles (account_id, role, granted_at)
+--   values (1, 'admin', strftime('%Y-%m-%dT%H:%M:%f000Z'));
+create table if not exists user_roles (
+	account_id int not null references users on delete cascade,
+	role varchar(32) not null references roles on delete cascade,
+	granted_by int references users on delete set null,
+	granted_at text not null,
+	primary key (account_id, role)
+);
+
+create table if not exists role_audit_log (
+	id integer primary key autoincrement,
+	actor_id int,
+	account_id int not null,
+	role varchar(32) not null,
+	action varchar(8) not null check (action in ('grant', 'revoke')),
+	created_at text not null
+);
+
+insert or ignore into roles (name) values ('moderator'), ('admin');
+insert or ignore into permissions (name) values
+	('content.moderate'), ('roles.manage'), ('questions.close');
+insert or ignore into role_permissions (role, permission) values
+	('moderator', 'content.moderate'),
+	('admin', 'content.moderate'),
+	('admin', 'roles.manage'),
+	('moderator', 'questions.close'),
+	('admin', 'questions.close');
+
+create table if not exists sessions (
+	id integer primary key autoincrement,
+	account_id int not null references users on delete cascade,
+	refresh_token_hash varchar(64) not null unique,
+	user_agent varchar(255),
+	created_at text not null,
+	last_used_at text not null,
+	expires_at text not null,
+	revoked_at text,
+	mfa boolean not null default false
+);
+
+create index if not exists sessions_account_id_idx on sessions (account_id);
+
+create table if not exists email_verification_tokens (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	created_at text not null,
+	expires_at text not null,
+	used_at text
+);
+
+create index if not exists email_verification_tokens_account_id_idx on email_verification_tokens (account_id);
+
+create table if not exists password_reset_tokens (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	created_at text not null,
+	expires_at text not null,
+	used_at text
+);
+
+create index if not exists password_reset_tokens_account_id_idx on password_reset_tokens (account_id);
+
+create table if not exists user_identities (
+	issuer varchar(255) not null,
+	subject varchar(255) not null,
+	account_id int not null references users on delete cascade,
+	created_at text not null,
+	last_login_at text not null,
+	primary key (issuer, subject)
+);
+
+create index if not exists user_identities_account_id_idx on user_identities (account_id);
+
+create table if not exists oidc_logins (
+	state varchar(64) primary key,
+	pkce_verifier varchar(128) not null,
+	nonce varchar(64) not null,
+	created_at text not null,
+	expires_at text not null
+);
+
+create table if not exists recovery_codes (
+	account_id int not null references users on delete cascade,
+	code_hash varchar(64) not null,
+	created_at text not null,
+	used_at text,
+	primary key (account_id, code_hash)
+);
+
+create table if not exists login_challenges (
+	token_hash varchar(64) primary key,
+	account_id int not null references users on delete cascade,
+	user_agent varchar(255),
+	created_at text not null,
+	expires_at text not null,
+	failed_attempts int not null default 0,
+	used_at text
+);
+
+create table if not exists login_failures (
+	key varchar(300) primary key,
+	failures int not null default 0,
+	last_failure_at text not null,
+	locked_until text
+);
+
+create table if not exists api_keys (
+	id integer primary key autoincrement,
+	account_id int not null references users on delete cascade,
+	name varchar(100) not null,
+	prefix varchar(16) not null,
+	key_hash varchar(64) not null unique,
+	scopes text not null,
+	created_at text not null,
+	last_used_at text,
+	expires_at text,
+	revoked_at text
+);
+
+create index if not exists api_keys_account_id_idx on api_keys (account_id);
@@ -0,0 +1,605 @@
+#[cfg(test)]
+pub mod mock_idp;
+mod oidc;
+mod session_cache;
+mod throttle;
+pub mod totp;
+
+use std::{sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::{Filter, Rejection};
+
+use crate::{
+    config::AuthConfig,
+    error::Error,
+    store::Store,
+    types::{
+        api_key::{ApiKeyId, ApiScope},
+        role::Permission,
+        totp::LoginChallenge,
+        user::{AccessToken, AccountId, LinkPurpose},
+    },
+};
+
+pub use oidc::Oidc;
+pub use session_cache::SessionCache;
+pub use throttle::LoginThrottle;
+
+/// What the caller of a request authenticated with.
+#[derive(Debug, Clone, Copy)]
+pub enum Credential {
+    /// An access token of this login session
+    Login(i32),
+    /// This API key
+    ApiKey(ApiKeyId),
+}
+
+impl std::fmt::Display for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Login(id) => write!(f, "session {}", id),
+            Credential::ApiKey(id) => write!(f, "api key {}", id),
+        }
+    }
+}
+
+/// The authenticated caller of a request.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub credential: Credential,
+    pub account_id: AccountId,
+    /// Whether the login passed a second factor
+    pub mfa: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    /// Account id
+    sub: String,
+    /// Session id
+    sid: i32,
+    iat: i64,
+    exp: i64,
+    /// Logged in with a second factor
+    #[serde(default)]
+    mfa: bool,
+}
+
+impl Session {
+    /// The login session, for routes that manage it. Those only take access
+    /// tokens, so this fails only if a route is set up wrong.
+    pub fn login_id(&self) -> Result<i32, Error> {
+        match self.credential {
+            Credential::Login(id) => Ok(id),
+            Credential::ApiKey(_) => Err(Error::Forbidden(
+                "this needs a login, not an API key".to_string(),
+            )),
+        }
+    }
+
+    pub async fn require_permission(
+        &self,
+        store: &dyn Store,
+        permission: Permission,
+    ) -> Result<(), Error> {
+        match store.has_permission(&self.account_id, permission).await {
+            // Every permission is about other people's content or accounts
+            Ok(true) if !self.mfa => Err(Error::Forbidden(format!(
+                "permission {} needs a login with two-factor authentication",
+                permission.as_str()
+            ))),
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::Forbidden(format!(
+                "missing permission {}",
+                permission.as_str()
+            ))),
+            Err(e) => Err(Error::DatabaseQueryError(e)),
+        }
+    }
+
+    /// Whether the session may change other people's content, as far as
+    /// `require_owner` is concerned.
+    pub async fn may_moderate(&self, store: &dyn Store) -> Result<bool, Error> {
+        match self
+            .require_permission(store, Permission::ModerateContent)
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(Error::Forbidden(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lets the author of a row, or anyone allowed to moderate content,
+    /// change it. Takes what `may_moderate` said, looked up beforehand, so
+    /// the row can be checked inside a unit of work without going back to
+    /// the store, which the unit of work may be holding.
+    pub fn require_owner(
+        &self,
+        may_moderate: bool,
+        owner: Option<&AccountId>,
+        item: &str,
+    ) -> Result<(), Error> {
+        if owner == Some(&self.account_id) || may_moderate {
+            return Ok(());
+        }
+
+        Err(Error::Forbidden(format!(
+            "{} belongs to another account",
+            item
+        )))
+    }
+}
+
+/// Issues and checks tokens. Access tokens are JWTs signed with HS256 using
+/// the configured secret; each belongs to a login session in the database,
+/// which can be revoked. Refresh tokens are random and stored hashed.
+///
+/// Also issues the single-use tokens mailed in links. Those are random too,
+/// plus a signature over their purpose, so forged or mixed-up tokens are
+/// turned away without a database lookup.
+#[derive(Clone)]
+pub struct Tokens {
+    encoding: Arc<EncodingKey>,
+    decoding: Arc<DecodingKey>,
+    validation: Arc<Validation>,
+    link_secret: Arc<[u8]>,
+    ttl_secs: i64,
+    refresh_ttl_secs: i64,
+    email_verification_ttl_secs: i64,
+    password_reset_ttl_secs: i64,
+    login_challenge_ttl_secs: i64,
+    totp_issuer: Arc<str>,
+    store: Arc<dyn Store>,
+    sessions: Arc<SessionCache>,
+}
+
+impl Tokens {
+    pub fn new(config: &AuthConfig, store: Arc<dyn Store>) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        validation.set_required_spec_claims(&["sub", "exp"]);
+
+        Tokens {
+            encoding: Arc::new(EncodingKey::from_secret(config.token_secret.as_bytes())),
+            decoding: Arc::new(DecodingKey::from_secret(config.token_secret.as_bytes())),
+            validation: Arc::new(validation),
+            link_secret: config.token_secret.as_bytes().into(),
+            ttl_secs: config.token_ttl_secs as i64,
+            refresh_ttl_secs: config.refresh_token_ttl_secs as i64,
+            email_verification_ttl_secs: config.email_verification_ttl_secs as i64,
+            password_reset_ttl_secs: config.password_reset_ttl_secs as i64,
+            login_challenge_ttl_secs: config.login_challenge_ttl_secs as i64,
+            totp_issuer: config.totp_issuer.as_str().into(),
+            store,
+            sessions: Arc::new(SessionCache::new(Duration::from_secs(
+                config.revocation_cache_ttl_secs,
+            ))),
+        }
+    }
+
+    /// Starts a session for an account that has just logged in, with or
+    /// without a second factor.
+    pub async fn login(
+        &self,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+        mfa: bool,
+    ) -> Result<AccessToken, Error> {
+        let refresh_token = random_token();
+
+        let session_id = self
+            .store
+            .add_session(
+                account_id,
+                &hash_token(&refresh_token),
+                user_agent,
+                self.refresh_expiry(),
+                mfa,
+            )
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        self.issue(session_id, account_id, mfa, refresh_token)
+    }
+
+    pub fn totp_issuer(&self) -> &str {
+        &self.totp_issuer
+    }
+
+    /// For an account with two-factor authentication on, that got the first
+    /// factor right: a token to send along with the second.
+    pub async fn challenge(
+        &self,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+    ) -> Result<LoginChallenge, Error> {
+        let challenge_token = random_token();
+
+        self.store
+            .add_login_challenge(
+                &hash_token(&challenge_token),
+                account_id,
+                user_agent,
+                Utc::now() + chrono::Duration::seconds(self.login_challenge_ttl_secs),
+            )
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        Ok(LoginChallenge {
+            challenge_token,
+            expires_in: self.login_challenge_ttl_secs,
+        })
+    }
+
+    /// The account an open challenge is for.
+    pub async fn challenged_account(&self, challenge_token: &str) -> Result<AccountId, Error> {
+        match self
+            .store
+            .get_login_challenge(&hash_token(challenge_token), MAX_CHALLENGE_ATTEMPTS)
+            .await
+        {
+            Ok((account_id, _)) => Ok(account_id),
+            Err(sqlx::Error::RowNotFound) => Err(Error::ChallengeExpired),
+            Err(e) => Err(Error::DatabaseQueryError(e)),
+        }
+    }
+
+    /// Starts a session if `code` is the account's current TOTP code or one
+    /// of its recovery codes. A challenge is spent once answered, or after a
+    /// few wrong codes.
+    pub async fn answer_challenge(
+        &self,
+        challenge_token: &str,
+        code: &str,
+    ) -> Result<AccessToken, Error> {
+        let token_hash = hash_token(challenge_token);
+
+        let (account_id, user_agent) = match self
+            .store
+            .get_login_challenge(&token_hash, MAX_CHALLENGE_ATTEMPTS)
+            .await
+        {
+            Ok(challenge) => challenge,
+            Err(sqlx::Error::RowNotFound) => return Err(Error::ChallengeExpired),
+            Err(e) => return Err(Error::DatabaseQueryError(e)),
+        };
+
+        let secret = self
+            .store
+            .get_totp_secret(&account_id)
+            .await
+            .map_err(Error::DatabaseQueryError)?
+            // Turned off since the challenge was issued
+            .ok_or(Error::ChallengeExpired)?;
+
+        if !totp::check_code(self.store.as_ref(), &account_id, &secret, code).await? {
+            self.store
+                .fail_login_challenge(&token_hash)
+                .await
+                .map_err(Error::DatabaseQueryError)?;
+
+            tracing::warn!(%account_id, "wrong second factor");
+
+            return Err(Error::WrongSecondFactor);
+        }
+
+        if !self
+            .store
+            .complete_login_challenge(&token_hash)
+            .await
+            .map_err(Error::DatabaseQueryError)?
+        {
+            return Err(Error::ChallengeExpired);
+        }
+
+        self.login(&account_id, user_agent, true).await
+    }
+
+    /// Trades a refresh token for a new access and refresh token.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<AccessToken, Error> {
+        let new_refresh_token = random_token();
+
+        let (session_id, account_id, mfa) = match self
+            .store
+            .refresh_session(
+                &hash_token(refresh_token),
+                &hash_token(&new_refresh_token),
+                self.refresh_expiry(),
+            )
+            .await
+        {
+            Ok(session) => session,
+            Err(sqlx::Error::RowNotFound) => return Err(Error::InvalidToken),
+            Err(e) => return Err(Error::DatabaseQueryError(e)),
+        };
+
+        self.issue(session_id, &account_id, mfa, new_refresh_token)
+    }
+
+    pub async fn revoke(&self, session: &Session) -> Result<(), Error> {
+        let session_id = session.login_id()?;
+
+        self.store
+            .revoke_session(session_id)
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        self.sessions.revoke(&[session_id]);
+
+        Ok(())
+    }
+
+    /// Ends every session of an account, returning how many there were.
+    pub async fn revoke_all(&self, account_id: &AccountId) -> Result<usize, Error> {
+        let session_ids = self
+            .store
+            .revoke_sessions(account_id)
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        self.sessions.revoke(&session_ids);
+
+        Ok(session_ids.len())
+    }
+
+    /// Creates a token to mail to an account, valid once for `purpose`.
+    pub async fn issue_link(
+        &self,
+        purpose: LinkPurpose,
+        account_id: &AccountId,
+    ) -> Result<String, Error> {
+        let random = random_token();
+        let signature = self.link_mac(purpose, &random).finalize().into_bytes();
+        let token = format!("{}.{}", random, URL_SAFE_NO_PAD.encode(signature));
+
+        let ttl_secs = match purpose {
+            LinkPurpose::VerifyEmail => self.email_verification_ttl_secs,
+            LinkPurpose::ResetPassword => self.password_reset_ttl_secs,
+        };
+
+        self.store
+            .add_link_token(
+                purpose,
+                account_id,
+                &hash_token(&token),
+                Utc::now() + chrono::Duration::seconds(ttl_secs),
+            )
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        Ok(token)
+    }
+
+    /// Spends a token from a mailed link, returning the account it was for.
+    pub async fn redeem_link(&self, purpose: LinkPurpose, token: &str) -> Result<AccountId, Error> {
+        let (random, signature) = token.split_once('.').ok_or(Error::InvalidLinkToken)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::InvalidLinkToken)?;
+
+        self.link_mac(purpose, random)
+            .verify_slice(&signature)
+            .map_err(|_| Error::InvalidLinkToken)?;
+
+        match self
+            .store
+            .redeem_link_token(purpose, &hash_token(token))
+            .await
+        {
+            Ok(account_id) => Ok(account_id),
+            Err(sqlx::Error::RowNotFound) => Err(Error::InvalidLinkToken),
+            Err(e) => Err(Error::DatabaseQueryError(e)),
+        }
+    }
+
+    fn link_mac(&self, purpose: LinkPurpose, random: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.link_secret)
+            .expect("hmac accepts keys of any size");
+        mac.update(purpose.as_str().as_bytes());
+        mac.update(b".");
+        mac.update(random.as_bytes());
+        mac
+    }
+
+    fn refresh_expiry(&self) -> chrono::DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(self.refresh_ttl_secs)
+    }
+
+    fn issue(
+        &self,
+        session_id: i32,
+        account_id: &AccountId,
+        mfa: bool,
+        refresh_token: String,
+    ) -> Result<AccessToken, Error> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: account_id.to_string(),
+            sid: session_id,
+            iat: now,
+            exp: now + self.ttl_secs,
+            mfa,
+        };
+
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+            .map_err(|e| Error::TokenSigning(e.to_string()))?;
+
+        Ok(AccessToken {
+            access_token: token,
+            token_type: "Bearer".to_string(),
+            expires_in: self.ttl_secs,
+            refresh_token,
+        })
+    }
+
+    async fn verify(&self, token: &str) -> Result<Session, Error> {
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding, &self.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => Error::TokenExpired,
+                _ => Error::InvalidToken,
+            })?
+            .claims;
+
+        let account_id = claims.sub.parse().map_err(|_| Error::InvalidToken)?;
+
+        let active = match self.sessions.get(claims.sid) {
+            Some(active) => active,
+            None => {
+                let active = self
+                    .store
+                    .is_session_active(claims.sid)
+                    .await
+                    .map_err(Error::DatabaseQueryError)?;
+                self.sessions.insert(claims.sid, active);
+                active
+            }
+        };
+
+        if !active {
+            return Err(Error::SessionRevoked);
+        }
+
+        Ok(Session {
+            credential: Credential::Login(claims.sid),
+            account_id: AccountId(account_id),
+            mfa: claims.mfa,
+        })
+    }
+
+    /// Creates an API key, returning the key to hand out once along with
+    /// its prefix and the hash to store.
+    pub fn new_api_key(&self) -> (String, String, String) {
+        let key = format!("{}{}", API_KEY_PREFIX, random_token());
+        let prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+        let hash = hash_token(&key);
+
+        (key, prefix, hash)
+    }
+
+    /// Checks an API key and that it was given `scope`. Keys never count as
+    /// a second factor, so they get no permissions.
+    async fn verify_api_key(&self, key: &str, scope: ApiScope) -> Result<Session, Error> {
+        if !key.starts_with(API_KEY_PREFIX) {
+            return Err(Error::InvalidToken);
+        }
+
+        let (api_key_id, account_id, scopes) = match self.store.use_api_key(&hash_token(key)).await
+        {
+            Ok(api_key) => api_key,
+            Err(sqlx::Error::RowNotFound) => return Err(Error::InvalidToken),
+            Err(e) => return Err(Error::DatabaseQueryError(e)),
+        };
+
+        if !scopes.contains(&scope) {
+            return Err(Error::Forbidden(format!(
+                "API key lacks scope {}",
+                scope.as_str()
+            )));
+        }
+
+        Ok(Session {
+            credential: Credential::ApiKey(api_key_id),
+            account_id,
+            mfa: false,
+        })
+    }
+}
+
+/// Starts every API key, so leaked ones are easy to spot
+const API_KEY_PREFIX: &str = "qa_";
+
+/// Wrong codes allowed per login challenge, after which logging in has to
+/// start over with the password
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// 256 random bits, URL-safe.
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Refresh tokens are random enough that a plain SHA-256 is as good as a
+/// password hash, and lets us look them up.
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Requires an `Authorization: Bearer <token>` header with a valid access
+/// token, extracting the caller's session.
+pub fn auth(tokens: Tokens) -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let tokens = tokens.clone();
+
+        async move {
+            let result = match header.as_deref().and_then(bearer_token) {
+                Some(token) => tokens.verify(token).await,
+                None => Err(Error::MissingToken),
+            };
+
+            result.map_err(warp::reject::custom)
+        }
+    })
+}
+
+/// Like `auth`, but also takes an `X-Api-Key` header with a key that has
+/// `scope`, for the routes automated clients use.
+pub fn auth_or_api_key(
+    tokens: Tokens,
+    scope: ApiScope,
+) -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-api-key")
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |api_key: Option<String>, header: Option<String>| {
+            let tokens = tokens.clone();
+
+            async move {
+                let result = match (api_key, header.as_deref().and_then(bearer_token)) {
+                    (Some(api_key), _) => tokens.verify_api_key(api_key.trim(), scope).await,
+                    (None, Some(token)) => tokens.verify(token).await,
+                    (None, None) => Err(Error::MissingToken),
+                };
+
+                result.map_err(warp::reject::custom)
+            }
+        })
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Like `auth`, but also requires the caller to hold a role granting
+/// `permission`.
+pub fn require_permission(
+    permission: Permission,
+    tokens: Tokens,
+    store: Arc<dyn Store>,
+) -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
+    auth(tokens).and_then(move |session: Session| {
+        let store = store.clone();
+
+        async move {
+            session
+                .require_permission(store.as_ref(), permission)
+                .await
+                .map_err(warp::reject::custom)?;
+
+            Ok::<_, Rejection>(session)
+        }
+    })
+}
@@ -0,0 +1,520 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    AuthorizationCode, ClaimsVerificationError, ClientId, ClientSecret, CsrfToken, HttpRequest,
+    HttpResponse, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
+    RequestTokenError, Scope, TokenResponse,
+};
+use reqwest::Url;
+use tokio::sync::RwLock;
+
+use super::hash_token;
+use crate::{
+    client::ApiClient,
+    config::OidcConfig,
+    error::Error,
+    store::Store,
+    types::user::{OidcCallback, User},
+};
+
+/// Logs users in through an OpenID Connect provider, using the authorization
+/// code flow with PKCE. The provider's endpoints and keys are discovered on
+/// first use, and again if it stops accepting them. All calls to it go
+/// through its own `ApiClient`.
+#[derive(Clone)]
+pub struct Oidc {
+    config: Arc<OidcConfig>,
+    client: Arc<RwLock<Option<CoreClient>>>,
+    api_client: Arc<ApiClient>,
+    store: Arc<dyn Store>,
+}
+
+impl Oidc {
+    /// Cookie tying a login to the browser that started it, so a callback
+    /// from a login someone else started is refused
+    pub const STATE_COOKIE: &'static str = "oidc_state";
+
+    pub fn new(config: &OidcConfig, api_client: Arc<ApiClient>, store: Arc<dyn Store>) -> Self {
+        Oidc {
+            config: Arc::new(config.clone()),
+            client: Arc::new(RwLock::new(None)),
+            api_client,
+            store,
+        }
+    }
+
+    /// Where to send the browser to log in at the provider, and the value
+    /// of `STATE_COOKIE` to set in it: a hash of the login's `state`.
+    pub async fn authorize_url(&self) -> Result<(Url, String), Error> {
+        let client = self.client().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (url, state, nonce) = self
+            .config
+            .scopes
+            .iter()
+            .fold(
+                client.authorize_url(
+                    CoreAuthenticationFlow::AuthorizationCode,
+                    CsrfToken::new_random,
+                    Nonce::new_random,
+                ),
+                |request, scope| request.add_scope(Scope::new(scope.clone())),
+            )
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        self.store
+            .add_oidc_login(
+                state.secret(),
+                pkce_verifier.secret(),
+                nonce.secret(),
+                Utc::now() + chrono::Duration::seconds(self.config.login_ttl_secs as i64),
+            )
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        Ok((url, hash_token(state.secret())))
+    }
+
+    /// `Set-Cookie` value for `STATE_COOKIE`, lasting as long as the login.
+    /// Lax, so it comes along when the provider sends the browser back.
+    pub fn state_cookie(&self, value: &str) -> String {
+        self.cookie(value, self.config.login_ttl_secs)
+    }
+
+    /// `Set-Cookie` value removing `STATE_COOKIE` once the login is over.
+    pub fn clear_state_cookie(&self) -> String {
+        self.cookie("", 0)
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let secure = if self.config.redirect_url.starts_with("https:") {
+            "; Secure"
+        } else {
+            ""
+        };
+
+        format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{}",
+            Self::STATE_COOKIE,
+            value,
+            max_age,
+            secure
+        )
+    }
+
+    /// Finishes a login the provider redirected back from, returning the
+    /// local account it signs in to. `state_cookie` is the browser's
+    /// `STATE_COOKIE`, which has to belong to the same login. The account is
+    /// found by the provider identity, else linked by email, else created.
+    pub async fn callback(
+        &self,
+        callback: OidcCallback,
+        state_cookie: Option<String>,
+    ) -> Result<User, Error> {
+        // Checked first, so someone else's callback doesn't spend the login
+        if state_cookie != Some(hash_token(&callback.state)) {
+            return Err(Error::SsoFailed(
+                "login was started in another browser".to_string(),
+            ));
+        }
+
+        let (pkce_verifier, nonce) = match self.store.take_oidc_login(&callback.state).await {
+            Ok(login) => login,
+            Err(sqlx::Error::RowNotFound) => {
+                return Err(Error::SsoFailed("unknown or expired login".to_string()))
+            }
+            Err(e) => return Err(Error::DatabaseQueryError(e)),
+        };
+
+        if let Some(error) = callback.error {
+            return Err(Error::SsoFailed(format!("provider returned {}", error)));
+        }
+
+        let code = callback
+            .code
+            .ok_or_else(|| Error::SsoFailed("provider returned no code".to_string()))?;
+
+        let client = self.client().await?;
+        let api_client = self.api_client.clone();
+
+        let token_response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(move |request| http_client(api_client, request))
+            .await
+            .map_err(|e| match e {
+                // `ApiClient` turns the provider's error response into an
+                // error of its own before `openidconnect` can parse it
+                RequestTokenError::Request(Error::ExternalApiError(e))
+                    if e.status().is_some_and(|status| status.is_client_error()) =>
+                {
+                    Error::SsoFailed(format!("provider rejected the code: {}", e))
+                }
+                RequestTokenError::Request(e) => e,
+                e => Error::SsoFailed(format!("provider rejected the code: {}", e)),
+            })?;
+
+        let id_token = token_response
+            .id_token()
+            .ok_or_else(|| Error::SsoFailed("provider returned no id token".to_string()))?;
+
+        let nonce = Nonce::new(nonce);
+
+        // The provider may have rotated its signing keys since we fetched
+        // them
+        let client = if matches!(
+            id_token.claims(&client.id_token_verifier(), &nonce),
+            Err(ClaimsVerificationError::SignatureVerification(_))
+        ) {
+            self.discover().await?
+        } else {
+            client
+        };
+
+        let verifier = client.id_token_verifier();
+        let claims = id_token
+            .claims(&verifier, &nonce)
+            .map_err(|e| Error::SsoFailed(format!("id token rejected: {}", e)))?;
+
+        let issuer = claims.issuer().as_str();
+        let subject = claims.subject().as_str();
+
+        match self.store.get_user_by_identity(issuer, subject).await {
+            Ok(user) => return Ok(user),
+            Err(sqlx::Error::RowNotFound) => (),
+            Err(e) => return Err(Error::DatabaseQueryError(e)),
+        }
+
+        let email = claims
+            .email()
+            .map(|email| email.as_str().trim().to_lowercase())
+            .ok_or_else(|| {
+                Error::SsoFailed("provider did not share an email address".to_string())
+            })?;
+        let email_verified = claims.email_verified().unwrap_or(false);
+
+        match self.store.get_user_by_email(&email).await {
+            Ok(user) if self.config.link_by_email && email_verified => {
+                self.store
+                    .link_identity(issuer, subject, &user.id)
+                    .await
+                    .map_err(Error::DatabaseQueryError)?;
+
+                tracing::info!(account_id = %user.id, issuer, "linked provider identity");
+
+                Ok(user)
+            }
+            // Otherwise whoever controls the provider account could take
+            // over a local one
+            Ok(_) => Err(Error::SsoFailed(
+                "an account with this email already exists, log in with its password".to_string(),
+            )),
+            Err(sqlx::Error::RowNotFound) => {
+                let user = self
+                    .store
+                    .add_identity_user(issuer, subject, &email, email_verified)
+                    .await
+                    .map_err(Error::DatabaseQueryError)?;
+
+                tracing::info!(account_id = %user.id, issuer, "created account from provider identity");
+
+                Ok(user)
+            }
+            Err(e) => Err(Error::DatabaseQueryError(e)),
+        }
+    }
+
+    async fn client(&self) -> Result<CoreClient, Error> {
+        if !self.config.is_enabled() {
+            return Err(Error::ItemNotFound("single sign-on".to_string()));
+        }
+
+        if let Some(client) = self.client.read().await.as_ref() {
+            return Ok(client.clone());
+        }
+
+        self.discover().await
+    }
+
+    /// Fetches the provider's metadata and keys, replacing any we had.
+    async fn discover(&self) -> Result<CoreClient, Error> {
+        let issuer_url = IssuerUrl::new(self.config.issuer_url.clone())
+            .map_err(|e| Error::SsoFailed(e.to_string()))?;
+        let api_client = self.api_client.clone();
+
+        let metadata = CoreProviderMetadata::discover_async(issuer_url, move |request| {
+            http_client(api_client.clone(), request)
+        })
+        .await
+        .map_err(|e| Error::ExternalApiUnavailable(format!("oidc discovery: {}", e)))?;
+
+        let client_secret = Some(self.config.client_secret.clone())
+            .filter(|secret| !secret.is_empty())
+            .map(ClientSecret::new);
+
+        let redirect_url = RedirectUrl::new(self.config.redirect_url.clone())
+            .map_err(|e| Error::SsoFailed(e.to_string()))?;
+
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(self.config.client_id.clone()),
+            client_secret,
+        )
+        .set_redirect_uri(redirect_url);
+
+        *self.client.write().await = Some(client.clone());
+
+        Ok(client)
+    }
+}
+
+/// Sends `openidconnect`'s requests with the shared client, so they get the
+/// same timeouts, retries and circuit breaker as other external calls.
+async fn http_client(
+    api_client: Arc<ApiClient>,
+    request: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let response = api_client
+        .send(|client| {
+            client
+                .request(request.method.clone(), request.url.clone())
+                .headers(request.headers.clone())
+                .body(request.body.clone())
+        })
+        .await?;
+
+    let status_code = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await.map_err(Error::ExternalApiError)?;
+
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::mock_idp::{self, Identity, MockIdp},
+        client::mock,
+        config::{Config, FailureMode},
+        store::MemoryStore,
+        types::user::NewUser,
+    };
+
+    fn config(idp: &MockIdp) -> OidcConfig {
+        let mut config = Config::for_tests().oidc;
+        config.issuer_url = idp.url.clone();
+        config.client_id = mock_idp::CLIENT_ID.to_string();
+        config.client_secret = "secret".to_string();
+        config
+    }
+
+    fn oidc_with(config: &OidcConfig, store: Arc<dyn Store>) -> Oidc {
+        let api_client =
+            Arc::new(ApiClient::new("oidc", &mock::config(FailureMode::Closed)).unwrap());
+
+        Oidc::new(config, api_client, store)
+    }
+
+    fn oidc(idp: &MockIdp, store: Arc<dyn Store>) -> Oidc {
+        oidc_with(&config(idp), store)
+    }
+
+    async fn log_in(idp: &MockIdp, oidc: &Oidc, identity: Identity) -> Result<User, Error> {
+        let (url, cookie) = oidc.authorize_url().await.unwrap();
+
+        oidc.callback(idp.authorize(&url, identity), Some(cookie))
+            .await
+    }
+
+    fn sso_failure(result: Result<User, Error>) -> String {
+        match result {
+            Err(Error::SsoFailed(reason)) => reason,
+            result => panic!("expected the login to fail, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn creates_an_account_for_a_new_identity() {
+        let idp = MockIdp::start();
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let oidc = oidc(&idp, store.clone());
+
+        let user = log_in(&idp, &oidc, Identity::new("1", "Alice@Example.com", true))
+            .await
+            .unwrap();
+
+        assert_eq!(user.email, "alice@example.com");
+        assert!(user.email_verified_at.is_some());
+        assert!(user.password.is_none());
+
+        // Found by the identity from then on, whatever the email
+        let again = log_in(&idp, &oidc, Identity::new("1", "new@example.com", false))
+            .await
+            .unwrap();
+        assert_eq!(again.id, user.id);
+    }
+
+    #[tokio::test]
+    async fn links_a_verified_email_to_its_account() {
+        let idp = MockIdp::start();
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let oidc = oidc(&idp, store.clone());
+        let alice = store
+            .add_user(NewUser {
+                email: "alice@example.com".to_string(),
+                password: "hash".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let reason =
+            sso_failure(log_in(&idp, &oidc, Identity::new("1", "alice@example.com", false)).await);
+        assert!(reason.contains("already exists"), "{}", reason);
+
+        let user = log_in(&idp, &oidc, Identity::new("1", "alice@example.com", true))
+            .await
+            .unwrap();
+        assert_eq!(user.id, alice.id);
+
+        assert_eq!(
+            store.get_user_by_identity(&idp.url, "1").await.unwrap().id,
+            alice.id
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_link_when_turned_off() {
+        let idp = MockIdp::start();
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let mut config = config(&idp);
+        config.link_by_email = false;
+        let oidc = oidc_with(&config, store.clone());
+        store
+            .add_user(NewUser {
+                email: "alice@example.com".to_string(),
+                password: "hash".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let reason =
+            sso_failure(log_in(&idp, &oidc, Identity::new("1", "alice@example.com", true)).await);
+        assert!(reason.contains("already exists"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn checks_state() {
+        let idp = MockIdp::start();
+        let oidc = oidc(&idp, Arc::new(MemoryStore::new()));
+        let (url, cookie) = oidc.authorize_url().await.unwrap();
+        let callback = idp.authorize(&url, Identity::new("1", "alice@example.com", true));
+
+        let forged = OidcCallback {
+            state: "forged".to_string(),
+            ..callback.clone()
+        };
+        let reason = sso_failure(oidc.callback(forged, Some(hash_token("forged"))).await);
+        assert_eq!(reason, "unknown or expired login");
+
+        oidc.callback(callback.clone(), Some(cookie.clone()))
+            .await
+            .unwrap();
+
+        // Each login can only be finished once
+        let reason = sso_failure(oidc.callback(callback, Some(cookie)).await);
+        assert_eq!(reason, "unknown or expired login");
+    }
+
+    #[tokio::test]
+    async fn checks_the_login_was_started_in_the_same_browser() {
+        let idp = MockIdp::start();
+        let oidc = oidc(&idp, Arc::new(MemoryStore::new()));
+        let (url, cookie) = oidc.authorize_url().await.unwrap();
+        let (_, other_cookie) = oidc.authorize_url().await.unwrap();
+        let callback = idp.authorize(&url, Identity::new("1", "alice@example.com", true));
+
+        for state_cookie in [None, Some(other_cookie)] {
+            let reason = sso_failure(oidc.callback(callback.clone(), state_cookie).await);
+            assert_eq!(reason, "login was started in another browser");
+        }
+
+        // Refusing those didn't spend the login
+        oidc.callback(callback, Some(cookie)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_nonce() {
+        let idp = MockIdp::start();
+        let oidc = oidc(&idp, Arc::new(MemoryStore::new()));
+
+        let reason = sso_failure(
+            log_in(
+                &idp,
+                &oidc,
+                Identity {
+                    nonce: Some("replayed"),
+                    ..Identity::new("1", "alice@example.com", true)
+                },
+            )
+            .await,
+        );
+
+        assert!(reason.starts_with("id token rejected"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn checks_pkce() {
+        let idp = MockIdp::start();
+        let oidc = oidc(&idp, Arc::new(MemoryStore::new()));
+        let (victim, _) = oidc.authorize_url().await.unwrap();
+        let (attacker, attacker_cookie) = oidc.authorize_url().await.unwrap();
+
+        // A code issued for one login, injected into another, doesn't match
+        // the other's verifier
+        let stolen = idp.authorize(&victim, Identity::new("1", "alice@example.com", true));
+        let injected = OidcCallback {
+            state: idp
+                .authorize(&attacker, Identity::new("2", "mallory@example.com", true))
+                .state,
+            ..stolen
+        };
+
+        let reason = sso_failure(oidc.callback(injected, Some(attacker_cookie)).await);
+        assert!(
+            reason.starts_with("provider rejected the code"),
+            "{}",
+            reason
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_cancelled_logins() {
+        let idp = MockIdp::start();
+        let oidc = oidc(&idp, Arc::new(MemoryStore::new()));
+        let (url, cookie) = oidc.authorize_url().await.unwrap();
+        let callback = idp.authorize(&url, Identity::new("1", "alice@example.com", true));
+
+        let reason = sso_failure(
+            oidc.callback(
+                OidcCallback {
+                    code: None,
+                    error: Some("access_denied".to_string()),
+                    ..callback
+                },
+                Some(cookie),
+            )
+            .await,
+        );
+
+        assert_eq!(reason, "provider returned access_denied");
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Entries beyond this are dropped once they go stale
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry {
+    active: bool,
+    checked_at: Instant,
+}
+
+/// Remembers whether sessions were still active, so the auth filter only
+/// asks the database about a session once every `ttl`. Revocations made
+/// through this process take effect immediately, ones made by another
+/// instance within `ttl`.
+#[derive(Debug)]
+pub struct SessionCache {
+    entries: Mutex<HashMap<i32, Entry>>,
+    ttl: Duration,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        SessionCache {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Whether the session is active, if it was checked within `ttl`.
+    pub fn get(&self, session_id: i32) -> Option<bool> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(&session_id)
+            .filter(|entry| entry.checked_at.elapsed() < self.ttl)
+            .map(|entry| entry.active)
+    }
+
+    pub fn insert(&self, session_id: i32, active: bool) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.checked_at.elapsed() < ttl);
+        }
+
+        entries.insert(
+            session_id,
+            Entry {
+                active,
+                checked_at: Instant::now(),
+            },
+        );
+    }
+
+    pub fn revoke(&self, session_ids: &[i32]) {
+        for session_id in session_ids {
+            self.insert(*session_id, false);
+        }
+    }
+}
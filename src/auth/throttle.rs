@@ -0,0 +1,380 @@
+use std::{net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    config::LoginThrottleConfig,
+    error::Error,
+    store::{abort, Store},
+    types::session::LoginFailures,
+};
+
+/// Slows down and locks out password and second factor guessing. Every
+/// failed login, at either step, counts against the account tried and the
+/// client address. An account has to wait
+/// twice as long after each failure and is locked after too many; an address
+/// is blocked after too many, whichever accounts it tries. Counts are kept in
+/// the database, so they hold across instances.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    config: Arc<LoginThrottleConfig>,
+    store: Arc<dyn Store>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &LoginThrottleConfig, store: Arc<dyn Store>) -> Self {
+        LoginThrottle {
+            config: Arc::new(config.clone()),
+            store,
+        }
+    }
+
+    /// Counts a login to `email` (normalized) from `ip` before its password
+    /// is checked, unless it comes too soon after the last failure or while
+    /// the account or address is locked out. Checking and counting are one
+    /// step, so concurrent attempts can't all get through on the same count.
+    /// The attempt stays counted as a failure unless `record_success` clears
+    /// it, and a locked account can't be logged in to even with the right
+    /// password.
+    pub async fn reserve(&self, email: &str, ip: Option<IpAddr>) -> Result<LoginAttempt, Error> {
+        let account = account_key(email);
+        let ip = ip.map(ip_key);
+        let mut keys = vec![account.clone()];
+        keys.extend(ip.clone());
+
+        let mut work = self
+            .store
+            .begin()
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        let entries = match work.get_login_failures(&keys, self.window_start()).await {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::DatabaseQueryError(abort(work, e).await)),
+        };
+
+        if let Err(refusal) = self.refuse(&account, &entries) {
+            work.rollback().await.map_err(Error::DatabaseQueryError)?;
+            return Err(refusal);
+        }
+
+        let mut failures = Vec::with_capacity(keys.len());
+
+        for key in &keys {
+            match work.add_login_failure(key, self.window_start()).await {
+                Ok(count) => failures.push(count),
+                Err(e) => return Err(Error::DatabaseQueryError(abort(work, e).await)),
+            }
+        }
+
+        work.commit().await.map_err(Error::DatabaseQueryError)?;
+
+        Ok(LoginAttempt {
+            account,
+            account_failures: failures[0],
+            ip,
+            ip_failures: failures.get(1).copied().unwrap_or(0),
+        })
+    }
+
+    /// Why an attempt on `account` may not go ahead, if it may not.
+    fn refuse(&self, account: &str, entries: &[LoginFailures]) -> Result<(), Error> {
+        let now = Utc::now();
+
+        for entry in entries {
+            let is_account = entry.key == account;
+
+            if let Some(until) = entry.locked_until.filter(|until| *until > now) {
+                let retry_after = seconds_until(now, until);
+
+                tracing::warn!(key = %entry.key, retry_after, "login attempt while locked out");
+
+                return Err(if is_account {
+                    Error::AccountLocked(retry_after)
+                } else {
+                    Error::TooManyAttempts(retry_after)
+                });
+            }
+
+            if is_account && entry.failures > 0 {
+                let ready_at = entry.last_failure_at + self.delay(entry.failures);
+
+                if ready_at > now {
+                    return Err(Error::TooManyAttempts(seconds_until(now, ready_at)));
+                }
+            }
+
+            // The attempts still being checked block the address if they fail
+            if !is_account && entry.failures >= self.config.ip_max_failures as i32 {
+                return Err(Error::TooManyAttempts(self.config.ip_lockout_secs));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Leaves a wrong password or second factor counted, locking the account or blocking the
+    /// address once it has reached its limit.
+    pub async fn record_failure(&self, attempt: LoginAttempt) -> Result<(), Error> {
+        tracing::warn!(
+            key = %attempt.account,
+            ip = ?attempt.ip,
+            failures = attempt.account_failures,
+            "login failed"
+        );
+
+        if attempt.account_failures >= self.config.account_max_failures as i32 {
+            self.lock(&attempt.account, self.config.account_lockout_secs)
+                .await?;
+
+            tracing::warn!(
+                key = %attempt.account,
+                lockout_secs = self.config.account_lockout_secs,
+                "account locked after failed logins"
+            );
+        }
+
+        if let Some(ip) = attempt.ip {
+            if attempt.ip_failures >= self.config.ip_max_failures as i32 {
+                self.lock(&ip, self.config.ip_lockout_secs).await?;
+
+                tracing::warn!(
+                    key = %ip,
+                    lockout_secs = self.config.ip_lockout_secs,
+                    "client address blocked after failed logins"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes back an attempt with the right password that still has to pass
+    /// the second factor. Earlier failures stay counted until it does.
+    pub async fn release(&self, attempt: LoginAttempt) -> Result<(), Error> {
+        self.store
+            .remove_login_failure(&attempt.account)
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        if let Some(ip) = attempt.ip {
+            self.store
+                .remove_login_failure(&ip)
+                .await
+                .map_err(Error::DatabaseQueryError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the account's failures. The address only gets this attempt
+    /// back, so logging in to one account doesn't buy more guesses at others.
+    pub async fn record_success(&self, attempt: LoginAttempt) -> Result<(), Error> {
+        self.store
+            .clear_login_failures(&attempt.account)
+            .await
+            .map_err(Error::DatabaseQueryError)?;
+
+        if let Some(ip) = attempt.ip {
+            self.store
+                .remove_login_failure(&ip)
+                .await
+                .map_err(Error::DatabaseQueryError)?;
+        }
+
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str, secs: u64) -> Result<(), Error> {
+        self.store
+            .lock_login(key, Utc::now() + Duration::seconds(secs as i64))
+            .await
+            .map_err(Error::DatabaseQueryError)
+    }
+
+    fn window_start(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::seconds(self.config.failure_window_secs as i64)
+    }
+
+    /// `base_delay_secs` after the first failure, doubling with each one
+    /// after, up to `max_delay_secs`.
+    fn delay(&self, failures: i32) -> Duration {
+        let doublings = (failures - 1).clamp(0, 31) as u32;
+        let secs = self
+            .config
+            .base_delay_secs
+            .saturating_mul(1 << doublings)
+            .min(self.config.max_delay_secs);
+
+        Duration::seconds(secs as i64)
+    }
+}
+
+/// A login attempt counted against the account and address it came from,
+/// from `LoginThrottle::reserve` until its password or second factor has
+/// been checked.
+pub struct LoginAttempt {
+    account: String,
+    /// Counting this attempt
+    account_failures: i32,
+    ip: Option<String>,
+    ip_failures: i32,
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email)
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Whole seconds, rounded up, as `Retry-After` wants them.
+fn seconds_until(now: DateTime<Utc>, at: DateTime<Utc>) -> u64 {
+    ((at - now).num_milliseconds().max(0) as u64).div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, store::MemoryStore};
+
+    fn throttle(configure: impl FnOnce(&mut LoginThrottleConfig)) -> LoginThrottle {
+        let mut config = Config::for_tests().login_throttle;
+        configure(&mut config);
+
+        LoginThrottle::new(&config, Arc::new(MemoryStore::new()))
+    }
+
+    /// No wait between failures, so only the limits count.
+    fn without_delays(config: &mut LoginThrottleConfig) {
+        config.base_delay_secs = 0;
+        config.max_delay_secs = 0;
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    async fn fail(throttle: &LoginThrottle, email: &str, ip: Option<IpAddr>) {
+        let attempt = throttle.reserve(email, ip).await.unwrap();
+        throttle.record_failure(attempt).await.unwrap();
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let throttle = throttle(|config| {
+            config.base_delay_secs = 1;
+            config.max_delay_secs = 30;
+        });
+
+        let delays: Vec<_> = [1, 2, 3, 4, 5, 6, 40]
+            .into_iter()
+            .map(|failures| throttle.delay(failures).num_seconds())
+            .collect();
+
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[tokio::test]
+    async fn waits_out_the_delay_after_a_failure() {
+        let throttle = throttle(|config| {
+            config.base_delay_secs = 5;
+            config.max_delay_secs = 30;
+        });
+
+        fail(&throttle, "alice@example.com", ip(1)).await;
+
+        assert!(matches!(
+            throttle.reserve("alice@example.com", ip(2)).await,
+            Err(Error::TooManyAttempts(5))
+        ));
+        assert!(throttle.reserve("bob@example.com", ip(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_attempts_take_turns() {
+        let throttle = throttle(|config| {
+            config.base_delay_secs = 5;
+            config.max_delay_secs = 30;
+        });
+
+        // The first is counted before its password is checked, so the
+        // second has to wait as if it had already failed
+        let first = throttle.reserve("alice@example.com", ip(1)).await.unwrap();
+
+        assert!(matches!(
+            throttle.reserve("alice@example.com", ip(1)).await,
+            Err(Error::TooManyAttempts(5))
+        ));
+
+        throttle.record_success(first).await.unwrap();
+
+        assert!(throttle.reserve("alice@example.com", ip(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_after_too_many_failures() {
+        let throttle = throttle(|config| {
+            without_delays(config);
+            config.account_max_failures = 3;
+            config.account_lockout_secs = 600;
+        });
+
+        for last in 1..=3 {
+            fail(&throttle, "alice@example.com", ip(last)).await;
+        }
+
+        assert!(matches!(
+            throttle.reserve("alice@example.com", ip(4)).await,
+            Err(Error::AccountLocked(600))
+        ));
+        assert!(throttle.reserve("bob@example.com", ip(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn blocks_an_address_whichever_accounts_it_tries() {
+        let throttle = throttle(|config| {
+            without_delays(config);
+            config.ip_max_failures = 3;
+            config.ip_lockout_secs = 600;
+        });
+
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            fail(&throttle, email, ip(1)).await;
+        }
+
+        assert!(matches!(
+            throttle.reserve("d@example.com", ip(1)).await,
+            Err(Error::TooManyAttempts(600))
+        ));
+        assert!(throttle.reserve("d@example.com", ip(2)).await.is_ok());
+        assert!(throttle.reserve("a@example.com", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn success_clears_the_account_but_not_the_address() {
+        let throttle = throttle(|config| {
+            without_delays(config);
+            config.account_max_failures = 3;
+            config.ip_max_failures = 3;
+        });
+
+        fail(&throttle, "alice@example.com", ip(1)).await;
+        fail(&throttle, "alice@example.com", ip(1)).await;
+
+        let attempt = throttle.reserve("alice@example.com", ip(1)).await.unwrap();
+        throttle.record_success(attempt).await.unwrap();
+
+        // Two more failures would have locked the account, if the first two
+        // still counted; one more blocks the address
+        fail(&throttle, "alice@example.com", ip(2)).await;
+        fail(&throttle, "alice@example.com", ip(1)).await;
+
+        assert!(throttle.reserve("alice@example.com", ip(2)).await.is_ok());
+        assert!(matches!(
+            throttle.reserve("bob@example.com", ip(1)).await,
+            Err(Error::TooManyAttempts(_))
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use chrono::Utc;
+use rand::{rngs::OsRng, Rng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use super::hash_token;
+use crate::{error::Error, store::Store, types::user::AccountId};
+
+/// What authenticator apps expect by default: SHA-1, six digits and a new
+/// code every 30 seconds.
+const DIGITS: usize = 6;
+const STEP_SECS: i64 = 30;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Without characters that are easy to mix up, like `0` and `o`
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// 160 random bits, base32 encoded.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+pub fn otpauth_uri(secret: &str, account_name: &str, issuer: &str) -> Result<String, Error> {
+    Ok(totp(secret, account_name, issuer)?.get_url())
+}
+
+/// The time step whose code is `code`, allowing one step of clock drift
+/// either way.
+pub fn matching_step(secret: &str, code: &str) -> Result<Option<i64>, Error> {
+    let totp = totp(secret, "", "")?;
+    let current = Utc::now().timestamp() / STEP_SECS;
+
+    Ok((current - 1..=current + 1).find(|step| totp.check(code, (step * STEP_SECS) as u64)))
+}
+
+/// What an authenticator app shows for `secret` right now.
+#[cfg(test)]
+pub fn current_code(secret: &str) -> String {
+    totp(secret, "", "")
+        .and_then(|totp| {
+            totp.generate_current()
+                .map_err(|e| Error::TotpSecret(e.to_string()))
+        })
+        .expect("the clock is after 1970")
+}
+
+/// Checks a code from the authenticator, or else a recovery code, spending
+/// it so it can't be used again.
+pub async fn check_code(
+    store: &dyn Store,
+    account_id: &AccountId,
+    secret: &str,
+    code: &str,
+) -> Result<bool, Error> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if code.len() == DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        return match matching_step(secret, &code)? {
+            Some(step) => store
+                .use_totp_step(account_id, step)
+                .await
+                .map_err(Error::DatabaseQueryError),
+            None => Ok(false),
+        };
+    }
+
+    store
+        .use_recovery_code(account_id, &hash_recovery_code(&code))
+        .await
+        .map_err(Error::DatabaseQueryError)
+}
+
+/// Fresh recovery codes like `k7pwd-xq3ma`, each good for one login.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[OsRng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect();
+
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Ignores case and the dash, which people tend to type differently.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    hash_token(&code)
+}
+
+fn totp(secret: &str, account_name: &str, issuer: &str) -> Result<TOTP, Error> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| Error::TotpSecret(e.to_string()))?;
+
+    Ok(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECS as u64,
+        secret,
+        Some(issuer.to_string()),
+        account_name.to_string(),
+    ))
+}
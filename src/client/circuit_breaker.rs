@@ -0,0 +1,74 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+/// Stops calling a dependency after `failure_threshold` consecutive
+/// failures. Once `reset_timeout` has passed a single trial call is let
+/// through; its outcome decides whether the breaker closes again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    failure_threshold: u32,
+    reset_timeout: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_timeout: Duration) -> Self {
+        CircuitBreaker {
+            state: Mutex::new(State::Closed { failures: 0 }),
+            failure_threshold,
+            reset_timeout,
+        }
+    }
+
+    /// Whether a call may be made right now.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::Open { .. } => false,
+            // a trial call that never reported back must not wedge the breaker
+            State::HalfOpen { since } if now >= since + self.reset_timeout => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen { .. } => self.failure_threshold,
+        };
+
+        *state = if failures >= self.failure_threshold {
+            tracing::warn!(failures, "circuit breaker opened");
+
+            State::Open {
+                until: Instant::now() + self.reset_timeout,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use warp::{http::StatusCode, Filter};
+
+use crate::config::{CircuitBreakerConfig, ExternalApiConfig, FailureMode};
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    body: &'static str,
+    delay: Duration,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Self {
+        Response {
+            status,
+            body: "",
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn json(body: &'static str) -> Self {
+        Response {
+            body,
+            ..Response::status(200)
+        }
+    }
+
+    /// Waits this long before answering.
+    pub fn delayed(self, delay: Duration) -> Self {
+        Response { delay, ..self }
+    }
+}
+
+/// A local HTTP server that answers with a scripted sequence of responses,
+/// for driving `ApiClient` and its users in tests.
+pub struct MockServer {
+    pub url: String,
+    hits: Arc<AtomicUsize>,
+    script: Arc<Mutex<VecDeque<Response>>>,
+}
+
+impl MockServer {
+    /// Serves `script` one response per request, repeating the last one
+    /// once it runs out.
+    pub fn start(script: Vec<Response>) -> Self {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let script = Arc::new(Mutex::new(VecDeque::from(script)));
+
+        let routes = warp::any().and_then({
+            let hits = hits.clone();
+            let script = script.clone();
+
+            move || {
+                hits.fetch_add(1, Ordering::SeqCst);
+
+                let response = {
+                    let mut script = script.lock().unwrap();
+
+                    if script.len() > 1 {
+                        script.pop_front().unwrap()
+                    } else {
+                        script.front().cloned().expect("script is empty")
+                    }
+                };
+
+                async move {
+                    tokio::time::sleep(response.delay).await;
+
+                    Ok::<_, Infallible>(warp::reply::with_status(
+                        warp::reply::with_header(response.body, "content-type", "application/json"),
+                        StatusCode::from_u16(response.status).unwrap(),
+                    ))
+                }
+            }
+        });
+
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        MockServer {
+            url: format!("http://{}", addr),
+            hits,
+            script,
+        }
+    }
+
+    /// Replaces whatever is left of the script.
+    pub fn respond_with(&self, script: Vec<Response>) {
+        *self.script.lock().unwrap() = VecDeque::from(script);
+    }
+
+    /// Number of requests received so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+/// Short timeouts and backoff so tests don't wait around. The breaker stays
+/// open far longer than any test runs, so tests move past it with the
+/// paused clock rather than by sleeping.
+pub fn config(failure_mode: FailureMode) -> ExternalApiConfig {
+    ExternalApiConfig {
+        connect_timeout_ms: 200,
+        request_timeout_ms: 200,
+        max_retries: 2,
+        initial_backoff_ms: 1,
+        max_backoff_ms: 5,
+        circuit_breaker: CircuitBreakerConfig {
+            failure_threshold: 3,
+            reset_timeout_ms: 60_000,
+            failure_mode,
+        },
+    }
+}
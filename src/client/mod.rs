@@ -0,0 +1,348 @@
+mod circuit_breaker;
+#[cfg(test)]
+pub mod mock;
+
+use std::{
+    cmp,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+
+use crate::{
+    config::{ExternalApiConfig, FailureMode},
+    error::Error,
+};
+
+use circuit_breaker::CircuitBreaker;
+
+/// Counts of how calls to external APIs turned out.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    successes: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    retries: AtomicU64,
+    short_circuits: AtomicU64,
+}
+
+impl Metrics {
+    fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the counters of every client in the Prometheus text
+    /// exposition format.
+    pub fn render(clients: &[Arc<ApiClient>]) -> String {
+        let mut out = String::from(
+            "# HELP external_api_calls_total Outcomes of calls to external APIs\n\
+             # TYPE external_api_calls_total counter\n",
+        );
+
+        for client in clients {
+            let metrics = &client.metrics;
+
+            for (outcome, counter) in [
+                ("success", &metrics.successes),
+                ("failure", &metrics.failures),
+                ("timeout", &metrics.timeouts),
+                ("retry", &metrics.retries),
+                ("short_circuit", &metrics.short_circuits),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "external_api_calls_total{{upstream=\"{}\",outcome=\"{}\"}} {}",
+                    client.name,
+                    outcome,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// HTTP client for one third party API. Applies timeouts, retries transient
+/// failures with exponential backoff and stops calling the API once it keeps
+/// failing. Each upstream gets its own client, so one being down doesn't
+/// trip the breaker for the others.
+#[derive(Debug)]
+pub struct ApiClient {
+    name: &'static str,
+    client: reqwest::Client,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    breaker: CircuitBreaker,
+    failure_mode: FailureMode,
+    metrics: Metrics,
+}
+
+impl ApiClient {
+    pub fn new(name: &'static str, config: &ExternalApiConfig) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.request_timeout_ms))
+            .build()?;
+
+        Ok(ApiClient {
+            name,
+            client,
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            breaker: CircuitBreaker::new(
+                config.circuit_breaker.failure_threshold,
+                Duration::from_millis(config.circuit_breaker.reset_timeout_ms),
+            ),
+            failure_mode: config.circuit_breaker.failure_mode,
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// Whether callers should carry on without the API when it can't be
+    /// reached, rather than failing the request.
+    pub fn fails_open(&self) -> bool {
+        self.failure_mode == FailureMode::Open
+    }
+
+    /// Sends the request produced by `build`, which is called again for
+    /// every retry.
+    pub async fn send<F>(&self, build: F) -> Result<Response, Error>
+    where
+        F: Fn(&reqwest::Client) -> RequestBuilder,
+    {
+        if !self.breaker.allow() {
+            Metrics::incr(&self.metrics.short_circuits);
+            return Err(Error::ExternalApiUnavailable(
+                "circuit breaker is open".to_string(),
+            ));
+        }
+
+        let mut attempt = 0;
+
+        loop {
+            let (retry_after, error) = match build(&self.client).send().await {
+                Ok(res) if is_retryable(res.status()) => {
+                    (retry_after(&res), res.error_for_status().unwrap_err())
+                }
+                Ok(res) => match res.error_for_status() {
+                    Ok(res) => {
+                        Metrics::incr(&self.metrics.successes);
+                        self.breaker.record_success();
+                        return Ok(res);
+                    }
+                    // the API is up but didn't like the request
+                    Err(e) => {
+                        Metrics::incr(&self.metrics.failures);
+                        self.breaker.record_success();
+                        return Err(Error::ExternalApiError(e));
+                    }
+                },
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    if e.is_timeout() {
+                        Metrics::incr(&self.metrics.timeouts);
+                    }
+                    (None, e)
+                }
+                Err(e) => {
+                    Metrics::incr(&self.metrics.failures);
+                    self.breaker.record_failure();
+                    return Err(Error::ExternalApiError(e));
+                }
+            };
+
+            if attempt >= self.max_retries {
+                Metrics::incr(&self.metrics.failures);
+                self.breaker.record_failure();
+                return Err(Error::ExternalApiError(error));
+            }
+
+            let backoff = cmp::max(self.backoff(attempt), retry_after.unwrap_or_default());
+            let backoff = cmp::min(backoff, self.max_backoff);
+
+            tracing::warn!(upstream = self.name, attempt, ?backoff, error = %error, "retrying external API call");
+            Metrics::incr(&self.metrics.retries);
+
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether `error` means the API is down or overloaded, as opposed to it
+/// refusing the request, e.g. because of a bad API key. Only the former
+/// should be skipped over when failing open.
+pub fn is_unavailable(error: &Error) -> bool {
+    match error {
+        Error::ExternalApiUnavailable(_) => true,
+        Error::ExternalApiError(e) => {
+            e.is_timeout() || e.is_connect() || e.status().is_some_and(is_retryable)
+        }
+        _ => false,
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use mock::MockServer;
+
+    fn client(config: &ExternalApiConfig) -> ApiClient {
+        ApiClient::new("test", config).unwrap()
+    }
+
+    async fn get(client: &ApiClient, server: &MockServer) -> Result<Response, Error> {
+        client.send(|client| client.get(&server.url)).await
+    }
+
+    /// Moves the clock past the breaker's reset timeout. It only stays
+    /// paused for the jump, since a paused clock would also fire the
+    /// timeouts of requests still waiting on the mock server.
+    async fn wait_out_reset(config: &ExternalApiConfig) {
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(
+            config.circuit_breaker.reset_timeout_ms,
+        ))
+        .await;
+        tokio::time::resume();
+    }
+
+    #[tokio::test]
+    async fn retries_5xx_and_429() {
+        let server = MockServer::start(vec![
+            mock::Response::status(503),
+            mock::Response::status(429),
+            mock::Response::status(200),
+        ]);
+        let client = client(&mock::config(FailureMode::Closed));
+
+        let res = get(&client, &server).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(server.hits(), 3);
+        assert_eq!(client.metrics.retries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = MockServer::start(vec![mock::Response::status(500)]);
+        let client = client(&mock::config(FailureMode::Closed));
+
+        let err = get(&client, &server).await.unwrap_err();
+
+        assert!(is_unavailable(&err));
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_4xx() {
+        let server = MockServer::start(vec![mock::Response::status(401)]);
+        let client = client(&mock::config(FailureMode::Closed));
+
+        let err = get(&client, &server).await.unwrap_err();
+
+        assert!(!is_unavailable(&err));
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn times_out_each_attempt() {
+        let server = MockServer::start(vec![
+            mock::Response::status(200).delayed(Duration::from_secs(10))
+        ]);
+        let config = ExternalApiConfig {
+            max_retries: 1,
+            ..mock::config(FailureMode::Closed)
+        };
+        let client = client(&config);
+
+        let err = get(&client, &server).await.unwrap_err();
+
+        assert!(matches!(&err, Error::ExternalApiError(e) if e.is_timeout()));
+        assert!(is_unavailable(&err));
+        assert_eq!(server.hits(), 2);
+        assert_eq!(client.metrics.timeouts.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_then_half_opens_then_closes() {
+        let server = MockServer::start(vec![mock::Response::status(500)]);
+        let config = ExternalApiConfig {
+            max_retries: 0,
+            ..mock::config(FailureMode::Closed)
+        };
+        let client = client(&config);
+
+        for _ in 0..config.circuit_breaker.failure_threshold {
+            get(&client, &server).await.unwrap_err();
+        }
+
+        // open: calls fail without reaching the server
+        let err = get(&client, &server).await.unwrap_err();
+        assert!(matches!(err, Error::ExternalApiUnavailable(_)));
+        assert_eq!(server.hits(), 3);
+
+        // half-open: a failed trial call opens it again
+        wait_out_reset(&config).await;
+        get(&client, &server).await.unwrap_err();
+        assert_eq!(server.hits(), 4);
+        assert!(matches!(
+            get(&client, &server).await,
+            Err(Error::ExternalApiUnavailable(_))
+        ));
+
+        // half-open: a successful trial call closes it
+        server.respond_with(vec![mock::Response::status(200)]);
+        wait_out_reset(&config).await;
+        get(&client, &server).await.unwrap();
+        get(&client, &server).await.unwrap();
+        assert_eq!(server.hits(), 6);
+    }
+
+    #[tokio::test]
+    async fn breakers_are_per_client() {
+        let down = MockServer::start(vec![mock::Response::status(500)]);
+        let up = MockServer::start(vec![mock::Response::status(200)]);
+        let config = ExternalApiConfig {
+            max_retries: 0,
+            ..mock::config(FailureMode::Closed)
+        };
+        let moderation = client(&config);
+        let oidc = client(&config);
+
+        for _ in 0..=config.circuit_breaker.failure_threshold {
+            get(&moderation, &down).await.unwrap_err();
+        }
+
+        get(&oidc, &up).await.unwrap();
+    }
+}
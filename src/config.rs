@@ -0,0 +1,669 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use config::{builder::DefaultState, ConfigBuilder, ConfigError, Environment, File};
+use serde::Deserialize;
+
+/// Command line flags. Anything passed here overrides the config file and
+/// environment variables.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML or YAML config file, with or without extension
+    #[arg(short, long, default_value = "config")]
+    pub config: String,
+
+    #[arg(long)]
+    pub host: Option<IpAddr>,
+
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    #[arg(long)]
+    pub database_url: Option<String>,
+
+    #[arg(long)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub log_level: String,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cors: CorsConfig,
+    pub moderation: ModerationConfig,
+    pub external_api: ExternalApiConfig,
+    pub pagination: PaginationConfig,
+    pub auth: AuthConfig,
+    pub mailer: MailerConfig,
+    pub oidc: OidcConfig,
+    pub login_throttle: LoginThrottleConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Take the client address from the last `X-Forwarded-For` entry. Only
+    /// turn on behind a proxy that sets the header, as clients can send
+    /// their own.
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DatabaseConfig {
+    /// `postgres://...`, `sqlite://<path>`, `memory://` to keep everything in
+    /// memory, or `file://<path>` to keep questions and answers in a JSON file
+    pub url: String,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PaginationConfig {
+    /// Page size used when a request doesn't give a limit
+    pub default_page_size: i32,
+    pub max_page_size: i32,
+    /// Key used to sign cursor tokens
+    pub cursor_secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    /// Key used to sign access tokens
+    pub token_secret: String,
+    /// How long an access token is valid for
+    pub token_ttl_secs: u64,
+    /// How long a login lasts without being refreshed
+    pub refresh_token_ttl_secs: u64,
+    /// How long the auth filter trusts its cached view of whether a session
+    /// was revoked, i.e. how long a revocation may take to reach other
+    /// instances
+    pub revocation_cache_ttl_secs: u64,
+    /// How long an email verification link can be used for
+    pub email_verification_ttl_secs: u64,
+    /// How long a password reset link can be used for
+    pub password_reset_ttl_secs: u64,
+    /// How long a user has to enter a TOTP code after their password
+    pub login_challenge_ttl_secs: u64,
+    /// Shown next to the account in authenticator apps
+    pub totp_issuer: String,
+}
+
+/// Slows down and locks out password guessing on `/login`. Failures are
+/// counted per account and per client address.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginThrottleConfig {
+    /// Failed logins to an account before it is locked
+    pub account_max_failures: u32,
+    pub account_lockout_secs: u64,
+    /// Failed logins from one address before it is blocked
+    pub ip_max_failures: u32,
+    pub ip_lockout_secs: u64,
+    /// Failures older than this are forgotten
+    pub failure_window_secs: u64,
+    /// Wait required after the first failure, doubling with each further one
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+/// Single sign-on through an OpenID Connect provider.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcConfig {
+    /// Leave empty to turn single sign-on off
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends the browser back to, i.e. this API's
+    /// `/oidc/callback`. Must be registered with the provider.
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Sign in to an existing local account with the same email, if the
+    /// provider says it has verified that email
+    pub link_by_email: bool,
+    /// How long a user has to finish logging in at the provider
+    pub login_ttl_secs: u64,
+}
+
+impl OidcConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.issuer_url.trim().is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailerProvider {
+    /// Write mails to `dir` and the log instead of sending them
+    File,
+    Smtp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailerConfig {
+    pub provider: MailerProvider,
+    /// Sender address, e.g. `Rust Q&A <noreply@example.com>`
+    pub from: String,
+    /// Base URL of the front end page handling links in mails, not the API:
+    /// links are opened with a GET, while the API takes the token from the
+    /// `token` query parameter only in a POST to `/verify-email` or
+    /// `/password-reset/confirm`
+    pub app_url: String,
+    /// Directory for the file provider
+    pub dir: PathBuf,
+    pub smtp: SmtpConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    /// Leave empty for servers without authentication
+    pub username: String,
+    pub password: String,
+    /// Upgrade the connection with STARTTLS; turn off for local stand-ins
+    /// such as MailHog. Must be on with a username, so the password isn't
+    /// sent in plain text.
+    pub starttls: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationProvider {
+    Local,
+    ApiLayer,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModerationConfig {
+    pub provider: ModerationProvider,
+    /// Words censored by the local provider
+    pub words: Vec<String>,
+    /// Optional file of additional words for the local provider, one per line
+    pub words_file: Option<PathBuf>,
+    pub apilayer: ApiLayerConfig,
+    pub policy: ModerationPolicies,
+}
+
+/// What to do with a field that contains objectionable words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationPolicy {
+    /// Refuse the write
+    Reject,
+    /// Store the censored text, or drop the tag for tags
+    Censor,
+    /// Store the text as written and queue it for a moderator
+    Review,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ModerationPolicies {
+    pub question_title: ModerationPolicy,
+    pub question_content: ModerationPolicy,
+    pub question_tags: ModerationPolicy,
+    pub answer_content: ModerationPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiLayerConfig {
+    pub url: String,
+    pub api_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalApiConfig {
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub reset_timeout_ms: u64,
+    pub failure_mode: FailureMode,
+}
+
+/// How writes behave while an external API is unavailable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    /// Carry on without the API, e.g. store content unmoderated
+    Open,
+    /// Refuse the request with 503
+    Closed,
+}
+
+impl Config {
+    /// Built-in defaults for everything that has one.
+    fn defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+        config::Config::builder()
+            .set_default("log_level", "rust_q_and_a=info,warp=error")?
+            .set_default("server.host", "127.0.0.1")?
+            .set_default("server.port", 3030)?
+            .set_default("server.trust_forwarded_for", false)?
+            .set_default("database.max_connections", 5)?
+            .set_default("cors.allowed_origins", vec!["*"])?
+            .set_default("pagination.default_page_size", 20)?
+            .set_default("pagination.max_page_size", 100)?
+            .set_default("auth.token_ttl_secs", 900)?
+            .set_default("auth.refresh_token_ttl_secs", 2_592_000)?
+            .set_default("auth.revocation_cache_ttl_secs", 30)?
+            .set_default("auth.email_verification_ttl_secs", 86400)?
+            .set_default("auth.password_reset_ttl_secs", 3600)?
+            .set_default("auth.login_challenge_ttl_secs", 300)?
+            .set_default("auth.totp_issuer", "Rust Q&A")?
+            .set_default("mailer.provider", "file")?
+            .set_default("mailer.from", "Rust Q&A <noreply@localhost>")?
+            .set_default("mailer.app_url", "http://localhost:8080")?
+            .set_default("mailer.dir", "mail")?
+            .set_default("mailer.smtp.host", "localhost")?
+            .set_default("mailer.smtp.port", 1025)?
+            .set_default("mailer.smtp.username", "")?
+            .set_default("mailer.smtp.password", "")?
+            .set_default("mailer.smtp.starttls", false)?
+            .set_default("oidc.issuer_url", "")?
+            .set_default("oidc.client_id", "")?
+            .set_default("oidc.client_secret", "")?
+            .set_default("oidc.redirect_url", "http://localhost:3030/oidc/callback")?
+            .set_default("oidc.scopes", vec!["email", "profile"])?
+            .set_default("oidc.link_by_email", true)?
+            .set_default("oidc.login_ttl_secs", 600)?
+            .set_default("login_throttle.account_max_failures", 5)?
+            .set_default("login_throttle.account_lockout_secs", 900)?
+            .set_default("login_throttle.ip_max_failures", 20)?
+            .set_default("login_throttle.ip_lockout_secs", 900)?
+            .set_default("login_throttle.failure_window_secs", 900)?
+            .set_default("login_throttle.base_delay_secs", 1)?
+            .set_default("login_throttle.max_delay_secs", 30)?
+            .set_default("moderation.provider", "local")?
+            .set_default("moderation.words", Vec::<String>::new())?
+            .set_default(
+                "moderation.apilayer.url",
+                "https://api.apilayer.com/bad_words",
+            )?
+            .set_default("moderation.apilayer.api_key", "")?
+            .set_default("moderation.policy.question_title", "censor")?
+            .set_default("moderation.policy.question_content", "censor")?
+            .set_default("moderation.policy.question_tags", "censor")?
+            .set_default("moderation.policy.answer_content", "censor")?
+            .set_default("external_api.connect_timeout_ms", 2000)?
+            .set_default("external_api.request_timeout_ms", 5000)?
+            .set_default("external_api.max_retries", 2)?
+            .set_default("external_api.initial_backoff_ms", 100)?
+            .set_default("external_api.max_backoff_ms", 2000)?
+            .set_default("external_api.circuit_breaker.failure_threshold", 5)?
+            .set_default("external_api.circuit_breaker.reset_timeout_ms", 30000)?
+            .set_default("external_api.circuit_breaker.failure_mode", "closed")
+    }
+
+    /// Builds the config from, in increasing order of precedence: built-in
+    /// defaults, the config file, environment variables (`RUST_LOG` for the
+    /// log level, then `RUST_Q_AND_A_*`, e.g. `RUST_Q_AND_A_DATABASE__URL`)
+    /// and command line flags.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let config: Config = Config::defaults()?
+            .add_source(File::with_name(&args.config).required(false))
+            .add_source(
+                Environment::default().source(Some(
+                    std::env::var("RUST_LOG")
+                        .into_iter()
+                        .map(|filter| ("LOG_LEVEL".to_string(), filter))
+                        .collect(),
+                )),
+            )
+            .add_source(
+                Environment::with_prefix("RUST_Q_AND_A")
+                    .prefix_separator("_")
+                    .separator("__")
+                    .list_separator(",")
+                    .with_list_parse_key("cors.allowed_origins")
+                    .with_list_parse_key("moderation.words")
+                    .with_list_parse_key("oidc.scopes")
+                    .try_parsing(true),
+            )
+            .set_override_option("server.host", args.host.map(|host| host.to_string()))?
+            .set_override_option("server.port", args.port)?
+            .set_override_option("database.url", args.database_url.clone())?
+            .set_override_option("log_level", args.log_level.clone())?
+            .build()?
+            .try_deserialize()?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.port == 0 {
+            return Err(invalid("server.port", "must be greater than 0"));
+        }
+
+        if !(self.database.url.starts_with("postgres://")
+            || self.database.url.starts_with("postgresql://")
+            || self.database.url.starts_with("sqlite:")
+            || self.database.url.starts_with("memory://")
+            || self.database.url.starts_with("file://"))
+        {
+            return Err(invalid(
+                "database.url",
+                "must be a postgres://, sqlite:, memory:// or file:// url",
+            ));
+        }
+
+        if self.database.url == "file://" {
+            return Err(invalid("database.url", "must name a file after file://"));
+        }
+
+        if self.database.max_connections == 0 {
+            return Err(invalid(
+                "database.max_connections",
+                "must be greater than 0",
+            ));
+        }
+
+        if self.pagination.max_page_size < 1 {
+            return Err(invalid(
+                "pagination.max_page_size",
+                "must be greater than 0",
+            ));
+        }
+
+        if !(1..=self.pagination.max_page_size).contains(&self.pagination.default_page_size) {
+            return Err(invalid(
+                "pagination.default_page_size",
+                "must be between 1 and max_page_size",
+            ));
+        }
+
+        if self.pagination.cursor_secret.len() < 32 {
+            return Err(invalid(
+                "pagination.cursor_secret",
+                "must be at least 32 characters",
+            ));
+        }
+
+        if self.auth.token_secret.len() < 32 {
+            return Err(invalid(
+                "auth.token_secret",
+                "must be at least 32 characters",
+            ));
+        }
+
+        if self.auth.token_ttl_secs == 0 {
+            return Err(invalid("auth.token_ttl_secs", "must be greater than 0"));
+        }
+
+        if self.auth.refresh_token_ttl_secs < self.auth.token_ttl_secs {
+            return Err(invalid(
+                "auth.refresh_token_ttl_secs",
+                "must not be less than token_ttl_secs",
+            ));
+        }
+
+        if self.auth.email_verification_ttl_secs == 0 || self.auth.password_reset_ttl_secs == 0 {
+            return Err(invalid("auth", "link lifetimes must be greater than 0"));
+        }
+
+        if self.auth.login_challenge_ttl_secs == 0 {
+            return Err(invalid(
+                "auth.login_challenge_ttl_secs",
+                "must be greater than 0",
+            ));
+        }
+
+        // Authenticator apps split the label on ':'
+        if self.auth.totp_issuer.trim().is_empty() || self.auth.totp_issuer.contains(':') {
+            return Err(invalid(
+                "auth.totp_issuer",
+                "must be set and must not contain ':'",
+            ));
+        }
+
+        let throttle = &self.login_throttle;
+
+        if throttle.account_max_failures == 0 || throttle.ip_max_failures == 0 {
+            return Err(invalid(
+                "login_throttle",
+                "max failures must be greater than 0",
+            ));
+        }
+
+        if throttle.account_lockout_secs == 0
+            || throttle.ip_lockout_secs == 0
+            || throttle.failure_window_secs == 0
+        {
+            return Err(invalid(
+                "login_throttle",
+                "lockouts and failure_window_secs must be greater than 0",
+            ));
+        }
+
+        if throttle.max_delay_secs < throttle.base_delay_secs {
+            return Err(invalid(
+                "login_throttle.max_delay_secs",
+                "must not be less than base_delay_secs",
+            ));
+        }
+
+        if self
+            .mailer
+            .from
+            .parse::<lettre::message::Mailbox>()
+            .is_err()
+        {
+            return Err(invalid("mailer.from", "must be an email address"));
+        }
+
+        // Links in mails are built by adding to its path
+        match reqwest::Url::parse(&self.mailer.app_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && !url.cannot_be_a_base() => {}
+            _ => return Err(invalid("mailer.app_url", "must be an http or https url")),
+        }
+
+        if self.mailer.provider == MailerProvider::Smtp && self.mailer.smtp.host.trim().is_empty() {
+            return Err(invalid("mailer.smtp.host", "must be set"));
+        }
+
+        if self.mailer.provider == MailerProvider::Smtp
+            && !self.mailer.smtp.starttls
+            && !self.mailer.smtp.username.is_empty()
+        {
+            return Err(invalid(
+                "mailer.smtp.starttls",
+                "must be on to log in to the server, or the password is sent in plain text",
+            ));
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            return Err(invalid("cors.allowed_origins", "must not be empty"));
+        }
+
+        if !self.allows_any_origin() {
+            for origin in &self.cors.allowed_origins {
+                if !is_valid_origin(origin) {
+                    return Err(invalid(
+                        "cors.allowed_origins",
+                        &format!("'{}' is not a valid origin", origin),
+                    ));
+                }
+            }
+        }
+
+        match self.moderation.provider {
+            ModerationProvider::Local => {
+                if self.moderation.words.is_empty() && self.moderation.words_file.is_none() {
+                    return Err(invalid(
+                        "moderation.words",
+                        "local provider needs words or a words_file",
+                    ));
+                }
+            }
+            ModerationProvider::ApiLayer => {
+                if reqwest::Url::parse(&self.moderation.apilayer.url).is_err() {
+                    return Err(invalid("moderation.apilayer.url", "must be a valid url"));
+                }
+
+                if self.moderation.apilayer.api_key.trim().is_empty() {
+                    return Err(invalid("moderation.apilayer.api_key", "must be set"));
+                }
+            }
+        }
+
+        if self.oidc.is_enabled() {
+            if reqwest::Url::parse(&self.oidc.issuer_url).is_err() {
+                return Err(invalid("oidc.issuer_url", "must be a valid url"));
+            }
+
+            if self.oidc.client_id.trim().is_empty() {
+                return Err(invalid("oidc.client_id", "must be set"));
+            }
+
+            if reqwest::Url::parse(&self.oidc.redirect_url).is_err() {
+                return Err(invalid("oidc.redirect_url", "must be a valid url"));
+            }
+
+            if self.oidc.login_ttl_secs == 0 {
+                return Err(invalid("oidc.login_ttl_secs", "must be greater than 0"));
+            }
+        }
+
+        let external_api = &self.external_api;
+
+        if external_api.connect_timeout_ms == 0 || external_api.request_timeout_ms == 0 {
+            return Err(invalid("external_api", "timeouts must be greater than 0"));
+        }
+
+        if external_api.max_backoff_ms < external_api.initial_backoff_ms {
+            return Err(invalid(
+                "external_api.max_backoff_ms",
+                "must not be less than initial_backoff_ms",
+            ));
+        }
+
+        if external_api.circuit_breaker.failure_threshold == 0 {
+            return Err(invalid(
+                "external_api.circuit_breaker.failure_threshold",
+                "must be greater than 0",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.host, self.server.port)
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.cors.allowed_origins.iter().any(|origin| origin == "*")
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults on an empty in-memory store, with test values for what
+    /// has no default. Only "darn" counts as objectionable.
+    pub fn for_tests() -> Self {
+        let config: Config = Config::defaults()
+            .and_then(|builder| {
+                builder
+                    .set_override("database.url", "memory://")?
+                    .set_override(
+                        "pagination.cursor_secret",
+                        "test-cursor-secret-0123456789abcdef",
+                    )?
+                    .set_override("auth.token_secret", "test-token-secret-0123456789abcdef")?
+                    .set_override("moderation.words", vec!["darn"])?
+                    .set_override(
+                        "mailer.dir",
+                        std::env::temp_dir()
+                            .join("rust_q_and_a-mail")
+                            .to_string_lossy()
+                            .to_string(),
+                    )?
+                    .build()?
+                    .try_deserialize()
+            })
+            .expect("test config should load");
+
+        config.validate().expect("test config should be valid");
+
+        config
+    }
+}
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::Message(format!("invalid config value for {}: {}", key, reason))
+}
+
+/// An origin is a scheme, host and optional port, with no path.
+fn is_valid_origin(origin: &str) -> bool {
+    match reqwest::Url::parse(origin) {
+        Ok(url) => url.origin().ascii_serialization() == origin,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn app_url_must_take_a_path() {
+        for app_url in [
+            "http://localhost:3030",
+            "https://example.com/app/",
+            "https://example.com/app?x=1",
+        ] {
+            let mut config = Config::for_tests();
+            config.mailer.app_url = app_url.to_string();
+
+            assert!(config.validate().is_ok(), "{}", app_url);
+        }
+
+        for app_url in [
+            "not a url",
+            "mailto:someone@example.com",
+            "data:text/plain,x",
+            "ftp://example.com",
+        ] {
+            let mut config = Config::for_tests();
+            config.mailer.app_url = app_url.to_string();
+
+            assert!(config.validate().is_err(), "{}", app_url);
+        }
+    }
+
+    #[test]
+    fn smtp_logins_need_starttls() {
+        let mut config = Config::for_tests();
+        config.mailer.provider = MailerProvider::Smtp;
+        config.mailer.smtp.username = "mailer".to_string();
+        config.mailer.smtp.password = "secret".to_string();
+
+        config.mailer.smtp.starttls = false;
+        assert!(config.validate().is_err());
+
+        config.mailer.smtp.starttls = true;
+        assert!(config.validate().is_ok());
+
+        // Local stand-ins need neither
+        config.mailer.smtp.username = String::new();
+        config.mailer.smtp.password = String::new();
+        config.mailer.smtp.starttls = false;
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,13 +1,20 @@
 use reqwest::Error as ReqwestError;
-use sqlx::Error as SqlxError;
-use std::{fmt::Display, num::ParseIntError};
+use sqlx::{error::ErrorKind, Error as SqlxError};
+use std::{convert::Infallible, fmt::Display, num::ParseIntError};
 use warp::{
     filters::{body::BodyDeserializeError, cors::CorsForbidden},
-    http::StatusCode,
-    reject::Reject,
+    http::{
+        header::{HeaderValue, RETRY_AFTER, WWW_AUTHENTICATE},
+        HeaderMap, StatusCode,
+    },
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, Reject, UnsupportedMediaType,
+    },
+    Filter, Rejection, Reply,
 };
 
-use crate::JsonResponse;
+use crate::types::problem::{FieldError, Problem};
 
 #[derive(Debug)]
 struct InvalidId;
@@ -15,18 +22,47 @@ struct InvalidId;
 impl Reject for InvalidId {}
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     Parse(ParseIntError),
     MissingParameters(String),
     OutOfRange(String),
     ItemNotFound(String),
-    DuplicateId(String),
     DatabaseQueryError(SqlxError),
     ExternalApiError(ReqwestError),
+    ExternalApiUnavailable(String),
+    ObjectionableContent(String),
+    InvalidBody(Vec<FieldError>),
+    Validation(Vec<FieldError>),
+    InvalidCursor(String),
+    InvalidTimestamp(String),
+    WrongCredentials,
+    PasswordHashing(String),
+    MissingToken,
+    InvalidToken,
+    TokenExpired,
+    SessionRevoked,
+    TokenSigning(String),
+    TotpSecret(String),
+    Forbidden(String),
+    InvalidLinkToken,
+    MailDelivery(String),
+    SsoFailed(String),
+    Conflict(String),
+    WrongSecondFactor,
+    ChallengeExpired,
+    /// Seconds until the lockout ends
+    AccountLocked(u64),
+    /// Seconds until the client may try again
+    TooManyAttempts(u64),
 }
 
 impl Reject for Error {}
 
+/// Lets the error come back out of libraries that call into our code, like
+/// the HTTP client handed to `openidconnect`.
+impl std::error::Error for Error {}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -43,11 +79,8 @@ impl Display for Error {
                     parameter_name
                 )
             }
-            Error::ItemNotFound(ref item_id) => {
-                write!(f, "question not found: {}", item_id)
-            }
-            Error::DuplicateId(ref item_id) => {
-                write!(f, "duplicate id: {}", item_id)
+            Error::ItemNotFound(ref item) => {
+                write!(f, "item not found: {}", item)
             }
             Error::DatabaseQueryError(ref err) => {
                 write!(f, "database query could not be executed: {}", err)
@@ -55,48 +88,457 @@ impl Display for Error {
             Error::ExternalApiError(ref err) => {
                 write!(f, "error querying external API: {}", err)
             }
+            Error::ExternalApiUnavailable(ref reason) => {
+                write!(f, "external API unavailable: {}", reason)
+            }
+            Error::ObjectionableContent(ref field) => {
+                write!(f, "objectionable content in field: {}", field)
+            }
+            Error::InvalidBody(ref errors) => {
+                write!(f, "request body has {} invalid field(s)", errors.len())
+            }
+            Error::Validation(ref errors) => {
+                write!(f, "request failed {} validation rule(s)", errors.len())
+            }
+            Error::InvalidCursor(ref reason) => {
+                write!(f, "invalid cursor: {}", reason)
+            }
+            Error::InvalidTimestamp(ref parameter_name) => {
+                write!(f, "{} must be an RFC 3339 timestamp", parameter_name)
+            }
+            Error::WrongCredentials => {
+                write!(f, "wrong email or password")
+            }
+            Error::PasswordHashing(ref reason) => {
+                write!(f, "could not hash or verify password: {}", reason)
+            }
+            Error::MissingToken => {
+                write!(f, "missing bearer token")
+            }
+            Error::InvalidToken => {
+                write!(f, "invalid token")
+            }
+            Error::TokenExpired => {
+                write!(f, "bearer token has expired")
+            }
+            Error::SessionRevoked => {
+                write!(f, "session has been logged out or revoked")
+            }
+            Error::TokenSigning(ref reason) => {
+                write!(f, "could not sign token: {}", reason)
+            }
+            Error::TotpSecret(ref reason) => {
+                write!(f, "could not use totp secret: {}", reason)
+            }
+            Error::Forbidden(ref reason) => {
+                write!(f, "forbidden: {}", reason)
+            }
+            Error::InvalidLinkToken => {
+                write!(f, "link is invalid, has expired or was already used")
+            }
+            Error::MailDelivery(ref reason) => {
+                write!(f, "could not send mail: {}", reason)
+            }
+            Error::SsoFailed(ref reason) => {
+                write!(f, "single sign-on failed: {}", reason)
+            }
+            Error::Conflict(ref reason) => {
+                write!(f, "conflict: {}", reason)
+            }
+            Error::WrongSecondFactor => {
+                write!(f, "wrong two-factor authentication code")
+            }
+            Error::ChallengeExpired => {
+                write!(f, "login expired, start again with the password")
+            }
+            Error::AccountLocked(ref secs) => {
+                write!(
+                    f,
+                    "account locked after too many failed logins, try again in {} seconds",
+                    secs
+                )
+            }
+            Error::TooManyAttempts(ref secs) => {
+                write!(f, "too many failed logins, try again in {} seconds", secs)
+            }
         }
     }
 }
 
-pub async fn error_handler(r: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
-    if let Some(error) = r.find::<CorsForbidden>() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(true, Some(error.to_string()), None)),
-            StatusCode::FORBIDDEN,
-        ))
-    } else if let Some(crate::error::Error::ExternalApiError(e)) = r.find() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(true, Some(e.to_string()), None)),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+// Postgres SQLSTATE code for a value longer than its column; sqlx has no
+// `ErrorKind` for it
+const STRING_DATA_RIGHT_TRUNCATION: &str = "22001";
+
+impl Error {
+    /// Stable, machine-readable identifier for the error, returned to
+    /// clients in the `code` field. Clients may rely on these not changing:
+    ///
+    /// | code                       | status |
+    /// |----------------------------|--------|
+    /// | `invalid_body`             | 400    |
+    /// | `invalid_cursor`           | 400    |
+    /// | `invalid_link_token`       | 400    |
+    /// | `invalid_parameter`        | 400    |
+    /// | `invalid_timestamp`        | 400    |
+    /// | `missing_parameter`        | 400    |
+    /// | `out_of_range`             | 400    |
+    /// | `sso_failed`               | 400    |
+    /// | `invalid_value`            | 400    |
+    /// | `value_too_long`           | 400    |
+    /// | `challenge_expired`        | 401    |
+    /// | `invalid_credentials`      | 401    |
+    /// | `invalid_second_factor`    | 401    |
+    /// | `invalid_token`            | 401    |
+    /// | `missing_token`            | 401    |
+    /// | `session_revoked`          | 401    |
+    /// | `token_expired`            | 401    |
+    /// | `forbidden`                | 403    |
+    /// | `not_found`                | 404    |
+    /// | `conflict`                 | 409    |
+    /// | `invalid_reference`        | 409    |
+    /// | `objectionable_content`    | 422    |
+    /// | `validation_failed`        | 422    |
+    /// | `account_locked`           | 423    |
+    /// | `too_many_requests`        | 429    |
+    /// | `internal_error`           | 500    |
+    /// | `external_api_error`       | 502    |
+    /// | `database_unavailable`     | 503    |
+    /// | `external_api_unavailable` | 503    |
+    /// | `mail_unavailable`         | 503    |
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Parse(_) => "invalid_parameter",
+            Error::MissingParameters(_) => "missing_parameter",
+            Error::OutOfRange(_) => "out_of_range",
+            Error::ItemNotFound(_) => "not_found",
+            Error::DatabaseQueryError(e) => database_error(e).0,
+            Error::ExternalApiError(_) => "external_api_error",
+            Error::ExternalApiUnavailable(_) => "external_api_unavailable",
+            Error::ObjectionableContent(_) => "objectionable_content",
+            Error::InvalidBody(_) => "invalid_body",
+            Error::Validation(_) => "validation_failed",
+            Error::InvalidCursor(_) => "invalid_cursor",
+            Error::InvalidTimestamp(_) => "invalid_timestamp",
+            Error::WrongCredentials => "invalid_credentials",
+            Error::PasswordHashing(_) => "internal_error",
+            Error::MissingToken => "missing_token",
+            Error::InvalidToken => "invalid_token",
+            Error::TokenExpired => "token_expired",
+            Error::SessionRevoked => "session_revoked",
+            Error::TokenSigning(_) => "internal_error",
+            Error::TotpSecret(_) => "internal_error",
+            Error::Forbidden(_) => "forbidden",
+            Error::InvalidLinkToken => "invalid_link_token",
+            Error::MailDelivery(_) => "mail_unavailable",
+            Error::SsoFailed(_) => "sso_failed",
+            Error::Conflict(_) => "conflict",
+            Error::WrongSecondFactor => "invalid_second_factor",
+            Error::ChallengeExpired => "challenge_expired",
+            Error::AccountLocked(_) => "account_locked",
+            Error::TooManyAttempts(_) => "too_many_requests",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Parse(_)
+            | Error::MissingParameters(_)
+            | Error::OutOfRange(_)
+            | Error::InvalidBody(_)
+            | Error::InvalidCursor(_)
+            | Error::InvalidTimestamp(_)
+            | Error::InvalidLinkToken
+            | Error::SsoFailed(_) => StatusCode::BAD_REQUEST,
+            Error::WrongCredentials
+            | Error::MissingToken
+            | Error::InvalidToken
+            | Error::TokenExpired
+            | Error::SessionRevoked
+            | Error::WrongSecondFactor
+            | Error::ChallengeExpired => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::ItemNotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::ObjectionableContent(_) | Error::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::AccountLocked(_) => StatusCode::LOCKED,
+            Error::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::PasswordHashing(_) | Error::TokenSigning(_) | Error::TotpSecret(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::ExternalApiError(_) => StatusCode::BAD_GATEWAY,
+            Error::ExternalApiUnavailable(_) | Error::MailDelivery(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::DatabaseQueryError(e) => database_error(e).1,
+        }
+    }
+
+    /// The message shown to clients. Database and upstream details are only
+    /// ever logged.
+    pub fn message(&self) -> String {
+        match self {
+            Error::DatabaseQueryError(e) => match database_error(e).0 {
+                "not_found" => "item not found".to_string(),
+                "conflict" => "item already exists".to_string(),
+                "invalid_reference" => {
+                    "item references or is referenced by another item".to_string()
+                }
+                "invalid_value" => "required value missing or invalid".to_string(),
+                "value_too_long" => "value too long".to_string(),
+                "database_unavailable" => "database unavailable".to_string(),
+                _ => "internal server error".to_string(),
+            },
+            Error::ExternalApiError(_) => "error querying external API".to_string(),
+            Error::PasswordHashing(_) | Error::TokenSigning(_) | Error::TotpSecret(_) => {
+                "internal server error".to_string()
+            }
+            Error::MailDelivery(_) => "could not send mail, try again later".to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Seconds the client should wait before trying again, sent as
+    /// `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::AccountLocked(secs) | Error::TooManyAttempts(secs) => Some(*secs),
+            _ => None,
+        }
+    }
+}
+
+/// The code and status of a failed query, kept together so the two can't
+/// drift apart.
+fn database_error(error: &SqlxError) -> (&'static str, StatusCode) {
+    const INTERNAL: (&str, StatusCode) = ("internal_error", StatusCode::INTERNAL_SERVER_ERROR);
+
+    match error {
+        SqlxError::RowNotFound => ("not_found", StatusCode::NOT_FOUND),
+        SqlxError::Database(e) => match e.kind() {
+            ErrorKind::UniqueViolation => ("conflict", StatusCode::CONFLICT),
+            ErrorKind::ForeignKeyViolation => ("invalid_reference", StatusCode::CONFLICT),
+            ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                ("invalid_value", StatusCode::BAD_REQUEST)
+            }
+            _ if e.code().as_deref() == Some(STRING_DATA_RIGHT_TRUNCATION) => {
+                ("value_too_long", StatusCode::BAD_REQUEST)
+            }
+            _ => INTERNAL,
+        },
+        SqlxError::Io(_) | SqlxError::PoolTimedOut | SqlxError::PoolClosed => {
+            ("database_unavailable", StatusCode::SERVICE_UNAVAILABLE)
+        }
+        _ => INTERNAL,
+    }
+}
+
+/// Runs `routes`, turning anything they reject with into a problem response
+/// that carries the id of the request (from `X-Request-Id` if the client
+/// sent one).
+pub fn recover<F, R>(
+    routes: F,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let outcome = routes
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|r: Rejection| async move { Ok::<_, Infallible>((Err(r),)) });
+
+    warp::header::headers_cloned().and(outcome).map(
+        |headers: HeaderMap, outcome: Result<warp::reply::Response, Rejection>| match outcome {
+            Ok(response) => response,
+            Err(r) => {
+                let request_id = headers
+                    .get("x-request-id")
+                    .and_then(|id| id.to_str().ok())
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+                error_response(request_id, r)
+            }
+        },
+    )
+}
+
+fn error_response(request_id: String, r: Rejection) -> warp::reply::Response {
+    let mut errors = Vec::new();
+    let mut retry_after = None;
+
+    let (status, code, message) = if let Some(error) = r.find::<Error>() {
+        if error.status().is_server_error() {
+            tracing::error!(code = error.code(), %error, %request_id, "request failed");
+        }
+
+        if let Error::InvalidBody(field_errors) | Error::Validation(field_errors) = error {
+            errors = field_errors.clone();
+        }
+
+        retry_after = error.retry_after();
+
+        (error.status(), error.code(), error.message())
+    } else if let Some(error) = r.find::<CorsForbidden>() {
+        (StatusCode::FORBIDDEN, "cors_forbidden", error.to_string())
     } else if let Some(_invalid_id) = r.find::<InvalidId>() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(
-                true,
-                Some("no valid id provided".to_string()),
-                None,
-            )),
+        (
             StatusCode::UNPROCESSABLE_ENTITY,
-        ))
-    } else if let Some(error) = r.find::<Error>() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(true, Some(error.to_string()), None)),
-            StatusCode::RANGE_NOT_SATISFIABLE,
-        ))
+            "invalid_id",
+            "no valid id provided".to_string(),
+        )
     } else if let Some(error) = r.find::<BodyDeserializeError>() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(true, Some(error.to_string()), None)),
-            StatusCode::UNPROCESSABLE_ENTITY,
-        ))
+        (StatusCode::BAD_REQUEST, "invalid_body", error.to_string())
+    } else if let Some(error) = r.find::<InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", error.to_string())
+    } else if let Some(error) = r.find::<MissingHeader>() {
+        (StatusCode::BAD_REQUEST, "missing_header", error.to_string())
+    } else if let Some(error) = r.find::<InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, "invalid_header", error.to_string())
+    } else if let Some(error) = r.find::<LengthRequired>() {
+        (
+            StatusCode::LENGTH_REQUIRED,
+            "length_required",
+            error.to_string(),
+        )
+    } else if let Some(error) = r.find::<PayloadTooLarge>() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            error.to_string(),
+        )
+    } else if let Some(error) = r.find::<UnsupportedMediaType>() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            error.to_string(),
+        )
+    } else if let Some(error) = r.find::<MethodNotAllowed>() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            error.to_string(),
+        )
+    } else if r.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "not found".to_string())
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(
-                true,
-                Some("not found".to_string()),
-                None,
-            )),
-            StatusCode::NOT_FOUND,
-        ))
+        tracing::error!(rejection = ?r, %request_id, "unhandled rejection");
+
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "internal server error".to_string(),
+        )
+    };
+
+    let problem = Problem::new(status, code, message, request_id).with_errors(errors);
+
+    let mut response = warp::reply::with_status(
+        warp::reply::with_header(
+            warp::reply::json(&problem),
+            "content-type",
+            "application/problem+json",
+        ),
+        status,
+    )
+    .into_response();
+
+    // RFC 6750: tell the client which scheme to use, and whether the token
+    // it sent was the problem
+    if status == StatusCode::UNAUTHORIZED {
+        let challenge = match code {
+            "invalid_token" | "session_revoked" | "token_expired" => {
+                "Bearer error=\"invalid_token\""
+            }
+            _ => "Bearer",
+        };
+
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    }
+
+    if let Some(secs) = retry_after {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone {
+        let get = warp::path("items")
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(warp::reply);
+        let add = warp::path("items")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(|_: serde_json::Value| warp::reply());
+        let fail = warp::path("fail").and_then(|| async {
+            Err::<warp::reply::Response, _>(warp::reject::custom(Error::Forbidden(
+                "nope".to_string(),
+            )))
+        });
+
+        recover(get.or(add).or(fail))
+    }
+
+    async fn status(method: &str, path: &str) -> StatusCode {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("content-type", "text/plain")
+            .body("x")
+            .reply(&routes())
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn maps_rejections_to_statuses() {
+        assert_eq!(status("GET", "/items").await, StatusCode::OK);
+        assert_eq!(status("GET", "/missing").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            status("DELETE", "/items").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status("POST", "/items").await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(status("GET", "/fail").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn echoes_request_id() {
+        let res = warp::test::request()
+            .path("/missing")
+            .header("x-request-id", "abc")
+            .reply(&routes())
+            .await;
+        let problem: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+
+        assert_eq!(problem["instance"], "abc");
+    }
+
+    #[test]
+    fn database_errors_keep_code_and_status_together() {
+        let error = Error::DatabaseQueryError(SqlxError::RowNotFound);
+
+        assert_eq!(error.code(), "not_found");
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let error = Error::DatabaseQueryError(SqlxError::PoolTimedOut);
+
+        assert_eq!(error.code(), "database_unavailable");
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
     }
 }
@@ -0,0 +1,66 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde::de::DeserializeOwned;
+use serde_path_to_error::{Path, Segment};
+use warp::{Filter, Rejection};
+
+use crate::{error::Error, types::problem::FieldError};
+
+/// Like `warp::body::json`, but a body that is valid JSON of the wrong shape
+/// is rejected with the JSON pointer of the offending field.
+pub fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::body::json::<serde_json::Value>().and_then(|value| async move {
+        serde_path_to_error::deserialize::<_, T>(value).map_err(
+            |e: serde_path_to_error::Error<serde_json::Error>| {
+                let reason = e.inner().to_string();
+                let mut pointer = json_pointer(e.path());
+
+                // serde reports a missing field against its parent object
+                if let Some(field) = reason
+                    .strip_prefix("missing field `")
+                    .and_then(|rest| rest.split('`').next())
+                {
+                    pointer = format!("{}/{}", pointer, escape(field));
+                }
+
+                warp::reject::custom(Error::InvalidBody(vec![FieldError { pointer, reason }]))
+            },
+        )
+    })
+}
+
+/// The client's address: the last `X-Forwarded-For` entry when the proxy in
+/// front of us is trusted to append it, else the peer of the connection.
+pub fn client_ip(
+    trust_forwarded_for: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            move |remote: Option<SocketAddr>, forwarded: Option<String>| {
+                forwarded
+                    .filter(|_| trust_forwarded_for)
+                    .and_then(|forwarded| forwarded.rsplit(',').next()?.trim().parse().ok())
+                    .or(remote.map(|remote| remote.ip()))
+                    .map(|ip: IpAddr| ip.to_canonical())
+            },
+        )
+}
+
+fn json_pointer(path: &Path) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Seq { index } => format!("/{}", index),
+            Segment::Map { key } => format!("/{}", escape(key)),
+            Segment::Enum { variant } => format!("/{}", escape(variant)),
+            Segment::Unknown => String::new(),
+        })
+        .collect()
+}
+
+fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
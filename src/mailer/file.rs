@@ -0,0 +1,42 @@
+use std::{io, path::PathBuf};
+
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+
+use super::{message, Email, Mailer};
+use crate::error::Error;
+
+/// Writes each mail to its own `.eml` file instead of sending it, for local
+/// development.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: Mailbox) -> Result<Self, io::Error> {
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(FileMailer { dir, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), Error> {
+        let message = message(&self.from, email)?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4()
+        ));
+
+        if let Err(e) = tokio::fs::write(&path, message.formatted()).await {
+            return Err(Error::MailDelivery(e.to_string()));
+        }
+
+        tracing::info!(to = %email.to, subject = %email.subject, path = %path.display(), "wrote mail");
+
+        Ok(())
+    }
+}
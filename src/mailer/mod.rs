@@ -0,0 +1,179 @@
+mod file;
+mod smtp;
+
+use std::{io, sync::Arc};
+
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    Message,
+};
+use reqwest::Url;
+
+use crate::{
+    config::{MailerConfig, MailerProvider},
+    error::Error,
+};
+
+pub use file::FileMailer;
+pub use smtp::SmtpMailer;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), Error>;
+}
+
+/// Builds the mailer selected in config.
+pub fn from_config(config: &MailerConfig) -> Result<Arc<dyn Mailer>, io::Error> {
+    let from = config
+        .from
+        .parse::<Mailbox>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    match config.provider {
+        MailerProvider::Smtp => Ok(Arc::new(
+            SmtpMailer::new(&config.smtp, from)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        )),
+        MailerProvider::File => Ok(Arc::new(FileMailer::new(config.dir.clone(), from)?)),
+    }
+}
+
+/// Plain text message from `from`, shared by every mailer so that what ends
+/// up in a file is exactly what would have gone over SMTP.
+fn message(from: &Mailbox, email: &Email) -> Result<Message, Error> {
+    let to = email
+        .to
+        .parse::<Mailbox>()
+        .map_err(|e| Error::MailDelivery(e.to_string()))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())
+        .map_err(|e| Error::MailDelivery(e.to_string()))
+}
+
+/// Writes the account mails the API sends and hands them to the mailer.
+#[derive(Clone)]
+pub struct Notifier {
+    mailer: Arc<dyn Mailer>,
+    app_url: Url,
+}
+
+impl Notifier {
+    pub fn new(mailer: Arc<dyn Mailer>, app_url: Url) -> Self {
+        Notifier { mailer, app_url }
+    }
+
+    pub async fn send_email_verification(&self, to: &str, token: &str) -> Result<(), Error> {
+        self.mailer
+            .send(&Email {
+                to: to.to_string(),
+                subject: "Confirm your email address".to_string(),
+                body: format!(
+                    "Open this link to confirm the email address of your account:\n\n{}\n\nIf you didn't sign up, you can ignore this mail.\n",
+                    self.link("verify-email", token)?
+                ),
+            })
+            .await
+    }
+
+    pub async fn send_password_reset(&self, to: &str, token: &str) -> Result<(), Error> {
+        self.mailer
+            .send(&Email {
+                to: to.to_string(),
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Open this link to choose a new password:\n\n{}\n\nThe link can only be used once. If you didn't ask for it, you can ignore this mail; your password stays the same.\n",
+                    self.link("password-reset", token)?
+                ),
+            })
+            .await
+    }
+
+    /// `app_url` with `path` added and the token in the query. Config only
+    /// lets through URLs that can take a path, but a `Notifier` may be built
+    /// from any.
+    fn link(&self, path: &str, token: &str) -> Result<Url, Error> {
+        let mut url = self.app_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| Error::MailDelivery(format!("can't add a path to {}", self.app_url)))?
+            .pop_if_empty()
+            .push(path);
+        url.query_pairs_mut().append_pair("token", token);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Keeps what it is given to send.
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<Email>>);
+
+    #[async_trait]
+    impl Mailer for Outbox {
+        async fn send(&self, email: &Email) -> Result<(), Error> {
+            self.0.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    async fn reset_mail(app_url: &str) -> Result<Email, Error> {
+        let outbox = Arc::new(Outbox::default());
+        let notifier = Notifier::new(outbox.clone(), app_url.parse().unwrap());
+
+        notifier
+            .send_password_reset("alice@example.com", "a.b")
+            .await?;
+
+        let mut sent = outbox.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+
+        Ok(sent.remove(0))
+    }
+
+    #[tokio::test]
+    async fn links_below_the_app_url() {
+        for (app_url, link) in [
+            (
+                "http://localhost:3030",
+                "http://localhost:3030/password-reset?token=a.b",
+            ),
+            (
+                "https://example.com/app/",
+                "https://example.com/app/password-reset?token=a.b",
+            ),
+            (
+                "https://example.com/app",
+                "https://example.com/app/password-reset?token=a.b",
+            ),
+        ] {
+            let email = reset_mail(app_url).await.unwrap();
+
+            assert_eq!(email.to, "alice@example.com");
+            assert!(email.body.contains(link), "{}", email.body);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_url_to_link_below() {
+        let result = reset_mail("mailto:help@example.com").await;
+
+        assert!(matches!(result, Err(Error::MailDelivery(_))));
+    }
+}
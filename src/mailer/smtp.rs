@@ -0,0 +1,185 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::{self, authentication::Credentials},
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+
+use super::{message, Email, Mailer};
+use crate::{config::SmtpConfig, error::Error};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &SmtpConfig, from: Mailbox) -> Result<Self, smtp::Error> {
+        let builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        } else {
+            // Plain text, for local stand-ins that don't speak TLS
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        };
+
+        let builder = builder.port(config.port);
+
+        let builder = if config.username.is_empty() {
+            builder
+        } else {
+            builder.credentials(Credentials::new(
+                config.username.clone(),
+                config.password.clone(),
+            ))
+        };
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), Error> {
+        let message = message(&self.from, email)?;
+
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::MailDelivery(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    /// What an SMTP client told the stand-in server.
+    #[derive(Debug, Default)]
+    struct Received {
+        mail_from: String,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    /// Accepts one connection on a local port and speaks just enough SMTP
+    /// to take one mail, without TLS or authentication.
+    async fn smtp_stand_in() -> (u16, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = Received::default();
+
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command = line.to_ascii_uppercase();
+
+                let reply: &[u8] = if command.starts_with("EHLO") {
+                    b"250 localhost\r\n"
+                } else if command.starts_with("MAIL FROM:") {
+                    received.mail_from = line["MAIL FROM:".len()..].to_string();
+                    b"250 OK\r\n"
+                } else if command.starts_with("RCPT TO:") {
+                    received.rcpt_to.push(line["RCPT TO:".len()..].to_string());
+                    b"250 OK\r\n"
+                } else if command == "DATA" {
+                    writer.write_all(b"354 go ahead\r\n").await.unwrap();
+
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        received.data.push_str(&line);
+                        received.data.push('\n');
+                    }
+
+                    b"250 OK\r\n"
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"502 not implemented\r\n"
+                };
+
+                writer.write_all(reply).await.unwrap();
+            }
+
+            received
+        });
+
+        (port, server)
+    }
+
+    fn mailer(port: u16) -> SmtpMailer {
+        SmtpMailer::new(
+            &SmtpConfig {
+                host: "127.0.0.1".to_string(),
+                port,
+                username: String::new(),
+                password: String::new(),
+                starttls: false,
+            },
+            "Rust Q&A <noreply@localhost>".parse().unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_over_smtp() {
+        let (port, server) = smtp_stand_in().await;
+
+        mailer(port)
+            .send(&Email {
+                to: "alice@example.com".to_string(),
+                subject: "Reset your password".to_string(),
+                body: "Open this link".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("the stand-in should have taken the mail")
+            .unwrap();
+
+        assert_eq!(received.mail_from, "<noreply@localhost>");
+        assert_eq!(received.rcpt_to, ["<alice@example.com>"]);
+        assert!(received.data.contains("To: alice@example.com"));
+        assert!(received.data.contains("Subject: Reset your password"));
+        assert!(received.data.contains("Open this link"));
+    }
+
+    #[tokio::test]
+    async fn reports_refused_mail() {
+        // Nothing listens on a port just given back
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let result = mailer(port)
+            .send(&Email {
+                to: "alice@example.com".to_string(),
+                subject: "Confirm your email address".to_string(),
+                body: "Open this link".to_string(),
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::MailDelivery(_))));
+    }
+}
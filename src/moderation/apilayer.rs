@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{ContentFilter, Moderation};
+use crate::{
+    client::{self, ApiClient},
+    config::ApiLayerConfig,
+    error::Error,
+};
+
+#[derive(Debug, Deserialize)]
+struct BadWord {
+    original: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BadWordsResponse {
+    bad_words_list: Vec<BadWord>,
+    censored_content: String,
+}
+
+/// Filter backed by the apilayer bad words API.
+pub struct ApiLayerFilter {
+    client: Arc<ApiClient>,
+    url: String,
+    api_key: String,
+}
+
+impl ApiLayerFilter {
+    pub fn new(config: &ApiLayerConfig, client: Arc<ApiClient>) -> Self {
+        ApiLayerFilter {
+            client,
+            url: config.url.clone(),
+            api_key: config.api_key.clone(),
+        }
+    }
+}
+
+impl ApiLayerFilter {
+    async fn bad_words(&self, text: &str) -> Result<BadWordsResponse, Error> {
+        self.client
+            .send(|client| {
+                client
+                    .post(&self.url)
+                    .query(&[("censor_character", "*")])
+                    .header("apikey", &self.api_key)
+                    .body(text.to_owned())
+            })
+            .await?
+            .json::<BadWordsResponse>()
+            .await
+            .map_err(Error::ExternalApiError)
+    }
+}
+
+#[async_trait]
+impl ContentFilter for ApiLayerFilter {
+    async fn check(&self, text: &str) -> Result<Moderation, Error> {
+        let res = match self.bad_words(text).await {
+            Ok(res) => res,
+            Err(e) if self.client.fails_open() && client::is_unavailable(&e) => {
+                tracing::warn!(error = %e, "bad words API unavailable, skipping moderation");
+
+                return Ok(Moderation {
+                    matched_words: Vec::new(),
+                    censored: text.to_owned(),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Moderation {
+            matched_words: res
+                .bad_words_list
+                .into_iter()
+                .map(|bad_word| bad_word.original)
+                .collect(),
+            censored: res.censored_content,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::mock::{self, MockServer},
+        config::FailureMode,
+    };
+
+    fn filter(server: &MockServer, failure_mode: FailureMode) -> ApiLayerFilter {
+        let config = ApiLayerConfig {
+            url: server.url.clone(),
+            api_key: "key".to_string(),
+        };
+
+        ApiLayerFilter::new(
+            &config,
+            Arc::new(ApiClient::new("moderation", &mock::config(failure_mode)).unwrap()),
+        )
+    }
+
+    #[tokio::test]
+    async fn censors_bad_words() {
+        let server = MockServer::start(vec![mock::Response::json(
+            r#"{"bad_words_list":[{"original":"darn"}],"censored_content":"**** it"}"#,
+        )]);
+
+        let moderation = filter(&server, FailureMode::Closed)
+            .check("darn it")
+            .await
+            .unwrap();
+
+        assert_eq!(moderation.matched_words, vec!["darn"]);
+        assert_eq!(moderation.censored, "**** it");
+    }
+
+    #[tokio::test]
+    async fn fails_open_on_outage() {
+        let server = MockServer::start(vec![mock::Response::status(503)]);
+
+        let moderation = filter(&server, FailureMode::Open)
+            .check("darn it")
+            .await
+            .unwrap();
+
+        assert!(moderation.matched_words.is_empty());
+        assert_eq!(moderation.censored, "darn it");
+    }
+
+    #[tokio::test]
+    async fn fails_closed_on_outage() {
+        let server = MockServer::start(vec![mock::Response::status(503)]);
+
+        let err = filter(&server, FailureMode::Closed)
+            .check("darn it")
+            .await
+            .unwrap_err();
+
+        assert!(client::is_unavailable(&err));
+    }
+
+    #[tokio::test]
+    async fn does_not_fail_open_on_rejected_request() {
+        let server = MockServer::start(vec![mock::Response::status(401)]);
+
+        let err = filter(&server, FailureMode::Open)
+            .check("darn it")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ExternalApiError(_)));
+        assert_eq!(server.hits(), 1);
+    }
+}
@@ -0,0 +1,339 @@
+mod apilayer;
+mod word_list;
+
+use std::{fs, io, sync::Arc};
+
+use async_trait::async_trait;
+
+use crate::{
+    client::ApiClient,
+    config::{ModerationConfig, ModerationPolicies, ModerationPolicy, ModerationProvider},
+    error::Error,
+};
+
+pub use apilayer::ApiLayerFilter;
+pub use word_list::WordListFilter;
+
+/// Outcome of running a piece of text through a content filter.
+#[derive(Debug, Clone)]
+pub struct Moderation {
+    pub matched_words: Vec<String>,
+    pub censored: String,
+}
+
+#[async_trait]
+pub trait ContentFilter: Send + Sync {
+    /// Returns the offending words found in `text` along with a censored copy.
+    async fn check(&self, text: &str) -> Result<Moderation, Error>;
+}
+
+/// Builds the filter selected in config.
+pub fn from_config(
+    config: &ModerationConfig,
+    client: Arc<ApiClient>,
+) -> Result<Arc<dyn ContentFilter>, io::Error> {
+    match config.provider {
+        ModerationProvider::ApiLayer => Ok(Arc::new(ApiLayerFilter::new(&config.apilayer, client))),
+        ModerationProvider::Local => {
+            let mut words = config.words.clone();
+
+            if let Some(path) = &config.words_file {
+                words.extend(fs::read_to_string(path)?.lines().map(str::to_owned));
+            }
+
+            Ok(Arc::new(WordListFilter::new(words)))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Field {
+    QuestionTitle,
+    QuestionContent,
+    QuestionTags,
+    AnswerContent,
+}
+
+impl Field {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Field::QuestionTitle => "title",
+            Field::QuestionContent => "content",
+            Field::QuestionTags => "tags",
+            Field::AnswerContent => "content",
+        }
+    }
+}
+
+/// A field that was stored as written and needs a moderator to look at it.
+#[derive(Debug, Clone)]
+pub struct Flag {
+    pub field: Field,
+    pub matched_words: Vec<String>,
+}
+
+/// Runs every user-supplied field through the content filter and applies
+/// the configured policy for that field.
+#[derive(Clone)]
+pub struct Moderator {
+    filter: Arc<dyn ContentFilter>,
+    policies: ModerationPolicies,
+}
+
+impl Moderator {
+    pub fn new(filter: Arc<dyn ContentFilter>, policies: ModerationPolicies) -> Self {
+        Moderator { filter, policies }
+    }
+
+    fn policy(&self, field: Field) -> ModerationPolicy {
+        match field {
+            Field::QuestionTitle => self.policies.question_title,
+            Field::QuestionContent => self.policies.question_content,
+            Field::QuestionTags => self.policies.question_tags,
+            Field::AnswerContent => self.policies.answer_content,
+        }
+    }
+
+    async fn field(
+        &self,
+        field: Field,
+        text: &mut String,
+        flags: &mut Vec<Flag>,
+    ) -> Result<(), Error> {
+        let moderation = self.filter.check(text).await?;
+
+        if moderation.matched_words.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!(
+            field = field.as_str(),
+            matched_words = ?moderation.matched_words,
+            "objectionable content"
+        );
+
+        match self.policy(field) {
+            ModerationPolicy::Reject => {
+                Err(Error::ObjectionableContent(field.as_str().to_string()))
+            }
+            ModerationPolicy::Censor => {
+                *text = moderation.censored;
+                Ok(())
+            }
+            ModerationPolicy::Review => {
+                flags.push(Flag {
+                    field,
+                    matched_words: moderation.matched_words,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Moderates the parts of a new or updated question in place, returning
+    /// anything that has to be queued for review.
+    pub async fn question(
+        &self,
+        title: &mut String,
+        content: &mut String,
+        tags: &mut Option<Vec<String>>,
+    ) -> Result<Vec<Flag>, Error> {
+        let mut flags = Vec::new();
+
+        self.field(Field::QuestionTitle, title, &mut flags).await?;
+        self.field(Field::QuestionContent, content, &mut flags)
+            .await?;
+
+        if let Some(tags) = tags {
+            let mut kept = Vec::with_capacity(tags.len());
+
+            for mut tag in tags.drain(..) {
+                // a censored tag would contain `*`, which tags may not, so
+                // offending tags are dropped instead
+                if self.policy(Field::QuestionTags) == ModerationPolicy::Censor {
+                    let moderation = self.filter.check(&tag).await?;
+
+                    if !moderation.matched_words.is_empty() {
+                        tracing::info!(
+                            field = Field::QuestionTags.as_str(),
+                            matched_words = ?moderation.matched_words,
+                            "dropping objectionable tag"
+                        );
+                        continue;
+                    }
+                } else {
+                    self.field(Field::QuestionTags, &mut tag, &mut flags)
+                        .await?;
+                }
+
+                kept.push(tag);
+            }
+
+            *tags = kept;
+        }
+
+        Ok(flags)
+    }
+
+    /// Moderates the content of a new or updated answer in place.
+    pub async fn answer(&self, content: &mut String) -> Result<Vec<Flag>, Error> {
+        let mut flags = Vec::new();
+
+        self.field(Field::AnswerContent, content, &mut flags)
+            .await?;
+
+        Ok(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderator(policy: ModerationPolicy) -> Moderator {
+        Moderator::new(
+            Arc::new(WordListFilter::new(vec!["darn".to_string()])),
+            ModerationPolicies {
+                question_title: policy,
+                question_content: policy,
+                question_tags: policy,
+                answer_content: policy,
+            },
+        )
+    }
+
+    /// Only `field` of a question is objectionable.
+    fn question(field: Field) -> (String, String, Option<Vec<String>>) {
+        let mut title = "a title".to_string();
+        let mut content = "some content".to_string();
+        let mut tags = vec!["rust".to_string(), "warp".to_string()];
+
+        match field {
+            Field::QuestionTitle => title = "darn title".to_string(),
+            Field::QuestionContent => content = "darn content".to_string(),
+            Field::QuestionTags => tags.insert(1, "darn".to_string()),
+            Field::AnswerContent => unreachable!(),
+        }
+
+        (title, content, Some(tags))
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_each_field() {
+        let moderator = moderator(ModerationPolicy::Reject);
+
+        for field in [
+            Field::QuestionTitle,
+            Field::QuestionContent,
+            Field::QuestionTags,
+        ] {
+            let (mut title, mut content, mut tags) = question(field);
+
+            let err = moderator
+                .question(&mut title, &mut content, &mut tags)
+                .await
+                .unwrap_err();
+
+            assert!(matches!(err, Error::ObjectionableContent(name) if name == field.as_str()));
+        }
+
+        let err = moderator
+            .answer(&mut "darn answer".to_string())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ObjectionableContent(name) if name == "content"));
+    }
+
+    #[tokio::test]
+    async fn censor_rewrites_text_and_drops_tags() {
+        let moderator = moderator(ModerationPolicy::Censor);
+
+        let (mut title, mut content, mut tags) = question(Field::QuestionTitle);
+        let flags = moderator
+            .question(&mut title, &mut content, &mut tags)
+            .await
+            .unwrap();
+        assert!(flags.is_empty());
+        assert_eq!(title, "**** title");
+
+        let (mut title, mut content, mut tags) = question(Field::QuestionContent);
+        moderator
+            .question(&mut title, &mut content, &mut tags)
+            .await
+            .unwrap();
+        assert_eq!(content, "**** content");
+
+        let (mut title, mut content, mut tags) = question(Field::QuestionTags);
+        moderator
+            .question(&mut title, &mut content, &mut tags)
+            .await
+            .unwrap();
+        assert_eq!(tags, Some(vec!["rust".to_string(), "warp".to_string()]));
+
+        let mut answer = "darn answer".to_string();
+        let flags = moderator.answer(&mut answer).await.unwrap();
+        assert!(flags.is_empty());
+        assert_eq!(answer, "**** answer");
+    }
+
+    #[tokio::test]
+    async fn review_keeps_text_and_flags_it() {
+        let moderator = moderator(ModerationPolicy::Review);
+
+        for field in [
+            Field::QuestionTitle,
+            Field::QuestionContent,
+            Field::QuestionTags,
+        ] {
+            let written = question(field);
+            let (mut title, mut content, mut tags) = written.clone();
+
+            let flags = moderator
+                .question(&mut title, &mut content, &mut tags)
+                .await
+                .unwrap();
+
+            assert_eq!((title, content, tags), written);
+            assert_eq!(flags.len(), 1);
+            assert_eq!(flags[0].field.as_str(), field.as_str());
+            assert_eq!(flags[0].matched_words, vec!["darn"]);
+        }
+
+        let mut answer = "darn answer".to_string();
+        let flags = moderator.answer(&mut answer).await.unwrap();
+        assert_eq!(answer, "darn answer");
+        assert_eq!(flags[0].field.as_str(), "content");
+    }
+
+    #[tokio::test]
+    async fn policies_apply_per_field() {
+        let moderator = Moderator::new(
+            Arc::new(WordListFilter::new(vec!["darn".to_string()])),
+            ModerationPolicies {
+                question_title: ModerationPolicy::Reject,
+                question_content: ModerationPolicy::Review,
+                question_tags: ModerationPolicy::Censor,
+                answer_content: ModerationPolicy::Censor,
+            },
+        );
+
+        let mut title = "fine".to_string();
+        let mut content = "darn".to_string();
+        let mut tags = Some(vec!["darn".to_string()]);
+        let flags = moderator
+            .question(&mut title, &mut content, &mut tags)
+            .await
+            .unwrap();
+
+        assert_eq!(content, "darn");
+        assert_eq!(tags, Some(vec![]));
+        assert_eq!(flags.len(), 1);
+
+        let mut title = "darn".to_string();
+        assert!(moderator
+            .question(&mut title, &mut "fine".to_string(), &mut None)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+
+use super::{ContentFilter, Moderation};
+use crate::error::Error;
+
+/// Offline filter that censors whole words found in a fixed word list,
+/// ignoring case.
+pub struct WordListFilter {
+    words: HashSet<String>,
+}
+
+impl WordListFilter {
+    pub fn new<I: IntoIterator<Item = String>>(words: I) -> Self {
+        WordListFilter {
+            words: words
+                .into_iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl ContentFilter for WordListFilter {
+    async fn check(&self, text: &str) -> Result<Moderation, Error> {
+        let mut matched_words = Vec::new();
+        let mut censored = String::with_capacity(text.len());
+        let mut word = String::new();
+
+        // a trailing separator flushes the final word
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            if self.words.contains(&word.to_lowercase()) {
+                censored.extend(word.chars().map(|_| '*'));
+                matched_words.push(std::mem::take(&mut word));
+            } else {
+                censored.push_str(&word);
+                word.clear();
+            }
+
+            censored.push(c);
+        }
+
+        censored.pop();
+
+        Ok(Moderation {
+            matched_words,
+            censored,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> WordListFilter {
+        WordListFilter::new(vec![
+            " Darn ".to_string(),
+            "heck".to_string(),
+            "".to_string(),
+        ])
+    }
+
+    #[tokio::test]
+    async fn censors_listed_words() {
+        let moderation = filter().check("darn it, what the heck").await.unwrap();
+
+        assert_eq!(moderation.matched_words, vec!["darn", "heck"]);
+        assert_eq!(moderation.censored, "**** it, what the ****");
+    }
+
+    #[tokio::test]
+    async fn ignores_case_but_keeps_what_was_written() {
+        let moderation = filter().check("DARN. Heck!").await.unwrap();
+
+        assert_eq!(moderation.matched_words, vec!["DARN", "Heck"]);
+        assert_eq!(moderation.censored, "****. ****!");
+    }
+
+    #[tokio::test]
+    async fn only_matches_whole_words() {
+        let moderation = filter().check("darned heckler, adarn").await.unwrap();
+
+        assert!(moderation.matched_words.is_empty());
+        assert_eq!(moderation.censored, "darned heckler, adarn");
+    }
+
+    #[tokio::test]
+    async fn splits_words_on_punctuation() {
+        let moderation = filter().check("(darn)/heck's").await.unwrap();
+
+        assert_eq!(moderation.matched_words, vec!["darn", "heck"]);
+        assert_eq!(moderation.censored, "(****)/****'s");
+    }
+
+    #[tokio::test]
+    async fn leaves_clean_text_alone() {
+        for text in ["", "  ", "perfectly fine text "] {
+            let moderation = filter().check(text).await.unwrap();
+
+            assert!(moderation.matched_words.is_empty());
+            assert_eq!(moderation.censored, text);
+        }
+    }
+}
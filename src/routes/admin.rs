@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use crate::{
+    auth::Session,
+    error::Error,
+    store::Store,
+    types::{
+        pagination::Pagination,
+        response::{JsonResponse, ResponseType},
+        role::RoleGrant,
+    },
+};
+
+/// Lists the roles held by an account.
+pub async fn get_roles_handler(
+    account_id: i32,
+    _session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.get_roles(account_id).await {
+        Ok(roles) => Ok(warp::reply::json(&JsonResponse::new(
+            false,
+            Some("got roles".to_string()),
+            Some(ResponseType::Roles(roles)),
+        ))),
+        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+}
+
+pub async fn grant_role_handler(
+    account_id: i32,
+    session: Session,
+    store: Arc<dyn Store>,
+    grant: RoleGrant,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = store
+        .grant_role(account_id, &grant.role, &session.account_id)
+        .await
+    {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(
+        actor_id = %session.account_id,
+        account_id,
+        role = %grant.role,
+        "role granted"
+    );
+
+    get_roles_handler(account_id, session, store).await
+}
+
+pub async fn revoke_role_handler(
+    account_id: i32,
+    role: String,
+    session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Otherwise an admin could lock everyone out by accident
+    if account_id == session.account_id.0 && role == "admin" {
+        return Err(warp::reject::custom(Error::Forbidden(
+            "can't revoke your own admin role".to_string(),
+        )));
+    }
+
+    if let Err(e) = store
+        .revoke_role(account_id, &role, &session.account_id)
+        .await
+    {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(
+        actor_id = %session.account_id,
+        account_id,
+        role = %role,
+        "role revoked"
+    );
+
+    get_roles_handler(account_id, session, store).await
+}
+
+/// Lists a page of role grants and revokes.
+pub async fn get_audit_log_handler(
+    _session: Session,
+    pagination: Pagination,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (entries, next_cursor) = match store.get_role_audit_log(&pagination).await {
+        Ok(rows) => pagination.split(rows),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let total = match store
+        .count_role_audit_log(pagination.since, pagination.until)
+        .await
+    {
+        Ok(total) => total,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    Ok(warp::reply::json(
+        &JsonResponse::new(
+            false,
+            Some("got role audit log".to_string()),
+            Some(ResponseType::RoleAudit(entries)),
+        )
+        .with_page(
+            pagination.page("/admin/audit", total, next_cursor.as_deref()),
+            next_cursor,
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::routes::testing::TestApp;
+
+    #[tokio::test]
+    async fn needs_permission_to_manage_roles() {
+        let app = TestApp::new();
+        let (alice, _) = app.user("alice@example.com").await;
+        let with_mfa = app.login(&alice, true).await;
+        let (_, moderator) = app.user_with_role("mod@example.com", "moderator").await;
+
+        for authorization in [&with_mfa, &moderator] {
+            for (method, path, body) in [
+                ("GET", "/admin/audit", None),
+                ("GET", "/admin/users/1/roles", None),
+                (
+                    "POST",
+                    "/admin/users/1/roles",
+                    Some(json!({ "role": "admin" })),
+                ),
+                ("DELETE", "/admin/users/2/roles/moderator", None),
+            ] {
+                let (status, problem) = app.request(method, path, Some(authorization), body).await;
+
+                assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, path);
+                assert_eq!(
+                    problem["detail"],
+                    "forbidden: missing permission roles.manage"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn needs_two_factor_login_to_manage_roles() {
+        let app = TestApp::new();
+        let (admin, _) = app.user_with_role("admin@example.com", "admin").await;
+        let without_mfa = app.login(&admin, false).await;
+
+        let (status, _) = app
+            .request("GET", "/admin/audit", Some(&without_mfa), None)
+            .await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn audits_grants_and_revokes() {
+        let app = TestApp::new();
+        let (admin, authorization) = app.user_with_role("admin@example.com", "admin").await;
+        let (alice, _) = app.user("alice@example.com").await;
+
+        let (status, body) = app
+            .request(
+                "POST",
+                &format!("/admin/users/{}/roles", alice),
+                Some(&authorization),
+                Some(json!({ "role": "moderator" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Roles"][0]["name"], "moderator");
+
+        let (status, _) = app
+            .request(
+                "DELETE",
+                &format!("/admin/users/{}/roles/moderator", alice),
+                Some(&authorization),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = app
+            .request(
+                "GET",
+                "/admin/audit?sort=-created_at",
+                Some(&authorization),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let entries = body["data"]["RoleAudit"].as_array().unwrap();
+        let actions: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["action"].as_str().unwrap(),
+                    entry["account_id"].as_i64().unwrap(),
+                    entry["role"].as_str().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            actions,
+            [
+                ("revoke", i64::from(alice.0), "moderator"),
+                ("grant", i64::from(alice.0), "moderator"),
+                ("grant", i64::from(admin.0), "admin"),
+            ]
+        );
+        assert!(entries
+            .iter()
+            .all(|entry| entry["actor_id"] == json!(admin.0)));
+    }
+
+    #[tokio::test]
+    async fn pages_through_the_audit_log() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user_with_role("admin@example.com", "admin").await;
+        let (alice, _) = app.user("alice@example.com").await;
+
+        for _ in 0..2 {
+            let (status, _) = app
+                .request(
+                    "POST",
+                    &format!("/admin/users/{}/roles", alice),
+                    Some(&authorization),
+                    Some(json!({ "role": "moderator" })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, _) = app
+                .request(
+                    "DELETE",
+                    &format!("/admin/users/{}/roles/moderator", alice),
+                    Some(&authorization),
+                    None,
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let mut url = "/admin/audit?limit=2".to_string();
+        let mut ids = Vec::new();
+        let mut actions = Vec::new();
+
+        loop {
+            let (status, body) = app.request("GET", &url, Some(&authorization), None).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            assert_eq!(body["page"]["total"], 5);
+
+            for entry in body["data"]["RoleAudit"].as_array().unwrap() {
+                ids.push(entry["id"].as_i64().unwrap());
+                actions.push(entry["action"].as_str().unwrap().to_string());
+            }
+
+            match body["next_cursor"].as_str() {
+                Some(cursor) => url = format!("/admin/audit?limit=2&cursor={}", cursor),
+                None => break,
+            }
+        }
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+        assert_eq!(actions, ["grant", "grant", "revoke", "grant", "revoke"]);
+
+        let (status, _) = app.request("GET", "/admin/audit", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
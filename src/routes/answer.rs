@@ -1,51 +1,117 @@
+use std::sync::Arc;
+
 use crate::{
+    auth::Session,
     error::Error,
-    store::Store,
+    moderation::Moderator,
+    store::{abort, Store, UnitOfWork},
     types::{
         answer::{Answer, NewAnswer},
+        pagination::Pagination,
         response::{JsonResponse, ResponseType},
     },
+    validation,
 };
 use warp;
 use warp::http::StatusCode;
 
 pub async fn add_answer_handler(
-    store: Store,
-    answer: NewAnswer,
+    session: Session,
+    store: Arc<dyn Store>,
+    moderator: Moderator,
+    mut answer: NewAnswer,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.get_question_by_id(answer.question_id.0).await {
-        Ok(_) => match store.add_answer(answer.question_id.0, answer).await {
-            Ok(answer) => Ok(warp::reply::with_status(
-                warp::reply::json(&JsonResponse::new(
-                    false,
-                    Some("added answer to question".to_string()),
-                    Some(ResponseType::Answer(answer)),
-                )),
-                StatusCode::OK,
-            )),
-            Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
-        },
-        Err(_) => Err(warp::reject::custom(Error::ItemNotFound(
-            "no question with provided id".to_string(),
-        ))),
+    let flags = moderator.answer(&mut answer.content).await?;
+
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let question = match work.get_question_by_id(answer.question_id.0).await {
+        Ok(question) => question,
+        Err(e) => {
+            return Err(warp::reject::custom(match abort(work, e).await {
+                sqlx::Error::RowNotFound => {
+                    Error::ItemNotFound(format!("question {}", answer.question_id))
+                }
+                e => Error::DatabaseQueryError(e),
+            }))
+        }
+    };
+
+    if question.closed_at.is_some() {
+        let e = Error::Conflict(format!("question {} is closed", answer.question_id));
+        return Err(warp::reject::custom(abort(work, e).await));
+    }
+
+    let answer = match work
+        .add_answer(answer.question_id.0, answer, &session.account_id)
+        .await
+    {
+        Ok(answer) => answer,
+        Err(e) => {
+            return Err(warp::reject::custom(Error::DatabaseQueryError(
+                abort(work, e).await,
+            )))
+        }
+    };
+
+    if let Err(e) = work.queue_for_review("answer", answer.id.0, flags).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
+    }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
     }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, answer_id = answer.id.0, "answer added");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse::new(
+            false,
+            Some("added answer to question".to_string()),
+            Some(ResponseType::Answer(answer)),
+        )),
+        StatusCode::OK,
+    ))
 }
 
-pub async fn get_answers_handler(store: Store) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.get_answers().await {
-        Ok(answers) => Ok(warp::reply::json(&JsonResponse::new(
+pub async fn get_answers_handler(
+    pagination: Pagination,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (answers, next_cursor) = match store.get_answers(&pagination).await {
+        Ok(rows) => pagination.split(rows),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let total = match store
+        .count_answers(pagination.since, pagination.until)
+        .await
+    {
+        Ok(total) => total,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    Ok(warp::reply::json(
+        &JsonResponse::new(
             false,
             Some("got answers".to_string()),
             Some(ResponseType::Answers(answers)),
-        ))),
-
-        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
-    }
+        )
+        .with_page(
+            pagination.page("/answers", total, next_cursor.as_deref()),
+            next_cursor,
+        ),
+    ))
 }
 
 pub async fn get_answer_by_id_handler(
     answer_id: i32,
-    store: Store,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match store.get_answer_by_id(answer_id).await {
         Ok(answer) => Ok(warp::reply::json(&JsonResponse::new(
@@ -59,43 +125,329 @@ pub async fn get_answer_by_id_handler(
 
 pub async fn update_answer_handler(
     answer_id: i32,
-    store: Store,
-    answer: Answer,
+    session: Session,
+    mut answer: Answer,
+    store: Arc<dyn Store>,
+    moderator: Moderator,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.update_answer(answer, answer_id).await {
-        Ok(answer) => Ok(warp::reply::json(&JsonResponse::new(
-            false,
-            Some("answer updated".to_string()),
-            Some(ResponseType::Answer(answer)),
-        ))),
-        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    validation::matching_id(answer_id, &answer)?;
+
+    let flags = moderator.answer(&mut answer.content).await?;
+
+    let may_moderate = session.may_moderate(store.as_ref()).await?;
+
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if let Err(e) = check_owner(&session, may_moderate, work.as_mut(), answer_id).await {
+        return Err(warp::reject::custom(abort(work, e).await));
+    }
+
+    let answer = match work.update_answer(answer, answer_id).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            return Err(warp::reject::custom(Error::DatabaseQueryError(
+                abort(work, e).await,
+            )))
+        }
+    };
+
+    if let Err(e) = work.queue_for_review("answer", answer.id.0, flags).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
+    }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
     }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, answer_id, "answer updated");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("answer updated".to_string()),
+        Some(ResponseType::Answer(answer)),
+    )))
 }
 
 pub async fn delete_answer_handler(
     answer_id: i32,
-    store: Store,
+    session: Session,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.delete_answer(answer_id).await {
-        Ok(_) => Ok(warp::reply::json(&JsonResponse::new(
-            false,
-            Some("deleted answer".to_string()),
-            None,
-        ))),
-        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    let may_moderate = session.may_moderate(store.as_ref()).await?;
+
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if let Err(e) = check_owner(&session, may_moderate, work.as_mut(), answer_id).await {
+        return Err(warp::reject::custom(abort(work, e).await));
+    }
+
+    if let Err(e) = work.delete_answer(answer_id).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
+    }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
     }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, answer_id, "answer deleted");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("deleted answer".to_string()),
+        None,
+    )))
 }
 
 pub async fn get_answers_for_question_handler(
     question_id: i32,
-    store: Store,
+    pagination: Pagination,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.get_answers_for_question(question_id).await {
-        Ok(answers) => Ok(warp::reply::json(&JsonResponse::new(
+    let (answers, next_cursor) = match store
+        .get_answers_for_question(question_id, &pagination)
+        .await
+    {
+        Ok(rows) => pagination.split(rows),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let total = match store
+        .count_answers_for_question(question_id, pagination.since, pagination.until)
+        .await
+    {
+        Ok(total) => total,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let path = format!("/questions/{}/answers", question_id);
+
+    Ok(warp::reply::json(
+        &JsonResponse::new(
             false,
             Some("found answers to question".to_string()),
             Some(ResponseType::Answers(answers)),
-        ))),
-        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+        )
+        .with_page(
+            pagination.page(&path, total, next_cursor.as_deref()),
+            next_cursor,
+        ),
+    ))
+}
+
+/// Only the author of an answer, or a moderator, may change it. Reads the
+/// answer in `work`, so it can't change hands or go away before the change
+/// is made.
+async fn check_owner(
+    session: &Session,
+    may_moderate: bool,
+    work: &mut dyn UnitOfWork,
+    answer_id: i32,
+) -> Result<(), Error> {
+    let answer = work
+        .get_answer_by_id(answer_id)
+        .await
+        .map_err(Error::DatabaseQueryError)?;
+
+    session.require_owner(may_moderate, answer.account_id.as_ref(), "answer")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::routes::testing::TestApp;
+
+    #[tokio::test]
+    async fn moderates_updates() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+        app.request(
+            "POST",
+            "/questions",
+            Some(&authorization),
+            Some(json!({ "title": "title", "content": "content" })),
+        )
+        .await;
+
+        let (status, body) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(&authorization),
+                Some(json!({ "content": "darn answer", "question_id": 1 })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Answer"]["content"], "**** answer");
+
+        let (status, body) = app
+            .request(
+                "PUT",
+                "/answer/1",
+                Some(&authorization),
+                Some(json!({ "id": 1, "content": "darn again", "question_id": 1 })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Answer"]["content"], "**** again");
+    }
+
+    async fn add_answer(app: &TestApp, authorization: &str, content: &str) -> Value {
+        let (status, body) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(authorization),
+                Some(json!({ "question_id": 1, "content": content })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        body["data"]["Answer"].clone()
+    }
+
+    async fn with_question() -> (TestApp, String) {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+        app.request(
+            "POST",
+            "/questions",
+            Some(&authorization),
+            Some(json!({ "title": "title", "content": "content" })),
+        )
+        .await;
+
+        (app, authorization)
+    }
+
+    #[tokio::test]
+    async fn adds_lists_updates_and_deletes_answers() {
+        let (app, authorization) = with_question().await;
+
+        for content in ["first", "second", "third"] {
+            add_answer(&app, &authorization, content).await;
+        }
+
+        assert_eq!(
+            app.page_through("/answers?limit=1", "Answers").await,
+            [1, 2, 3]
+        );
+        assert_eq!(
+            app.page_through("/questions/1/answers?limit=2&sort=-created_at", "Answers")
+                .await,
+            [3, 2, 1]
+        );
+
+        let (status, body) = app
+            .request(
+                "PUT",
+                "/answer/2",
+                Some(&authorization),
+                Some(json!({ "id": 2, "content": "second, edited", "question_id": 1 })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Answer"]["content"], "second, edited");
+
+        let (status, body) = app.request("GET", "/answers/2", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Answer"]["content"], "second, edited");
+
+        let (status, _) = app
+            .request("DELETE", "/answer/2", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app.request("GET", "/answers/2", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.page_through("/answers?limit=1", "Answers").await,
+            [1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_author_or_a_moderator_changes_an_answer() {
+        let (app, alice) = with_question().await;
+        let (_, bob) = app.user("bob@example.com").await;
+        let (_, moderator) = app.user_with_role("mod@example.com", "moderator").await;
+        add_answer(&app, &alice, "answer").await;
+
+        let update = json!({ "id": 1, "content": "edited", "question_id": 1 });
+
+        let (status, problem) = app
+            .request("PUT", "/answer/1", Some(&bob), Some(update.clone()))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            problem["detail"],
+            "forbidden: answer belongs to another account"
+        );
+
+        let (status, _) = app.request("DELETE", "/answer/1", Some(&bob), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = app
+            .request("PUT", "/answer/1", Some(&moderator), Some(update))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app
+            .request("DELETE", "/answer/1", Some(&moderator), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn answers_need_an_open_question() {
+        let (app, authorization) = with_question().await;
+        let (_, moderator) = app.user_with_role("mod@example.com", "moderator").await;
+
+        let (status, problem) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(&authorization),
+                Some(json!({ "question_id": 9, "content": "answer" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(problem["detail"], "item not found: question 9");
+
+        app.request("POST", "/questions/1/close", Some(&moderator), None)
+            .await;
+
+        let (status, problem) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(&authorization),
+                Some(json!({ "question_id": 1, "content": "answer" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "conflict");
+
+        let (status, _) = app
+            .request(
+                "PUT",
+                "/answer/9",
+                Some(&authorization),
+                Some(json!({ "id": 9, "content": "answer", "question_id": 1 })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use crate::{
+    auth::{Session, Tokens},
+    error::Error,
+    store::Store,
+    types::{
+        api_key::{ApiKeyId, CreatedApiKey, NewApiKey},
+        response::{JsonResponse, ResponseType},
+    },
+};
+
+/// Creates a key for the caller's account. The key is only ever returned
+/// here. Accounts with two-factor authentication need a login that used it,
+/// as the key gets around it.
+pub async fn create_api_key(
+    session: Session,
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    new_key: NewApiKey,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = match store.get_user(&session.account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if user.totp_enabled_at.is_some() && !session.mfa {
+        return Err(warp::reject::custom(Error::Forbidden(
+            "creating an API key needs a login with two-factor authentication".to_string(),
+        )));
+    }
+
+    let mut scopes = Vec::new();
+    for scope in new_key.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let (key, prefix, key_hash) = tokens.new_api_key();
+
+    let api_key = match store
+        .add_api_key(
+            &session.account_id,
+            new_key.name.trim(),
+            &prefix,
+            &key_hash,
+            &scopes,
+            new_key.expires_at,
+        )
+        .await
+    {
+        Ok(api_key) => api_key,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    tracing::info!(account_id = %session.account_id, api_key_id = %api_key.id, "created api key");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("created API key, it won't be shown again".to_string()),
+        Some(ResponseType::CreatedApiKey(CreatedApiKey { key, api_key })),
+    )))
+}
+
+pub async fn get_api_keys(
+    session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.get_api_keys(&session.account_id).await {
+        Ok(api_keys) => Ok(warp::reply::json(&JsonResponse::new(
+            false,
+            Some("got API keys".to_string()),
+            Some(ResponseType::ApiKeys(api_keys)),
+        ))),
+        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+}
+
+/// Revokes one of the caller's keys; it stops working at once.
+pub async fn revoke_api_key(
+    id: i32,
+    session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store
+        .revoke_api_key(&session.account_id, ApiKeyId(id))
+        .await
+    {
+        Ok(()) => {
+            tracing::info!(account_id = %session.account_id, api_key_id = id, "revoked api key");
+
+            Ok(warp::reply::json(&JsonResponse::new(
+                false,
+                Some(format!("API key {} revoked", id)),
+                None,
+            )))
+        }
+        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::{
+        auth::totp,
+        routes::testing::TestApp,
+        types::{api_key::ApiScope, user::AccountId},
+    };
+
+    async fn create_key(app: &TestApp, authorization: &str, scopes: Value) -> (StatusCode, Value) {
+        app.request(
+            "POST",
+            "/account/api-keys",
+            Some(authorization),
+            Some(json!({ "name": "ci", "scopes": scopes })),
+        )
+        .await
+    }
+
+    async fn add_question(app: &TestApp, api_key: &str) -> (StatusCode, Value) {
+        app.request_with_headers(
+            "POST",
+            "/questions",
+            &[("x-api-key", api_key)],
+            Some(json!({ "title": "title", "content": "content" })),
+        )
+        .await
+    }
+
+    async fn list_keys(app: &TestApp, authorization: &str) -> Value {
+        let (status, body) = app
+            .request("GET", "/account/api-keys", Some(authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        body
+    }
+
+    async fn enable_totp(app: &TestApp, account_id: &AccountId) {
+        app.store
+            .set_totp_secret(account_id, &totp::generate_secret())
+            .await
+            .unwrap();
+        app.store.enable_totp(account_id, 0, &[]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_only_work_for_their_scopes() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (status, body) = create_key(&app, &authorization, json!(["questions.write"])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let key = body["data"]["CreatedApiKey"]["key"].as_str().unwrap();
+
+        let (status, body) = add_question(&app, key).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let (status, problem) = app
+            .request_with_headers(
+                "POST",
+                "/answers",
+                &[("x-api-key", key)],
+                Some(json!({ "content": "content", "question_id": 1 })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(problem["code"], "forbidden");
+    }
+
+    #[tokio::test]
+    async fn records_when_a_key_was_last_used() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (_, body) = create_key(&app, &authorization, json!(["questions.write"])).await;
+        let key = body["data"]["CreatedApiKey"]["key"].as_str().unwrap();
+
+        let listed = list_keys(&app, &authorization).await;
+        assert!(listed["data"]["ApiKeys"][0]["last_used_at"].is_null());
+
+        let before = Utc::now();
+        add_question(&app, key).await;
+
+        let listed = list_keys(&app, &authorization).await;
+        let last_used_at: chrono::DateTime<Utc> =
+            serde_json::from_value(listed["data"]["ApiKeys"][0]["last_used_at"].clone()).unwrap();
+        assert!(last_used_at >= before);
+    }
+
+    #[tokio::test]
+    async fn listing_shows_neither_the_key_nor_its_hash() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (_, body) = create_key(&app, &authorization, json!(["questions.write"])).await;
+        let key = body["data"]["CreatedApiKey"]["key"].as_str().unwrap();
+
+        let listed = list_keys(&app, &authorization).await;
+        let entry = listed["data"]["ApiKeys"][0].as_object().unwrap();
+
+        assert!(key.starts_with(entry["prefix"].as_str().unwrap()));
+        assert!(!entry.contains_key("key"));
+        assert!(!entry.contains_key("key_hash"));
+        assert!(!listed.to_string().contains(key));
+    }
+
+    #[tokio::test]
+    async fn refuses_revoked_keys() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (_, body) = create_key(&app, &authorization, json!(["questions.write"])).await;
+        let key = body["data"]["CreatedApiKey"]["key"].as_str().unwrap();
+        let id = body["data"]["CreatedApiKey"]["id"].as_i64().unwrap();
+
+        let (status, _) = app
+            .request(
+                "DELETE",
+                &format!("/account/api-keys/{}", id),
+                Some(&authorization),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, problem) = add_question(&app, key).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(problem["code"], "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_keys() {
+        let app = TestApp::new();
+        let (account_id, _) = app.user("alice@example.com").await;
+
+        // Keys can't be created already expired, so this one is made
+        // straight in the store
+        let (key, prefix, key_hash) = app.tokens().new_api_key();
+        app.store
+            .add_api_key(
+                &account_id,
+                "ci",
+                &prefix,
+                &key_hash,
+                &[ApiScope::QuestionsWrite],
+                Some(Utc::now() - Duration::seconds(1)),
+            )
+            .await
+            .unwrap();
+
+        let (status, problem) = add_question(&app, &key).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(problem["code"], "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn needs_a_two_factor_login_once_two_factor_is_on() {
+        let app = TestApp::new();
+        let (account_id, authorization) = app.user("alice@example.com").await;
+        enable_totp(&app, &account_id).await;
+
+        let (status, problem) = create_key(&app, &authorization, json!(["questions.write"])).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(problem["code"], "forbidden");
+
+        let with_mfa = app.login(&account_id, true).await;
+        let (status, body) = create_key(&app, &with_mfa, json!(["questions.write"])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+}
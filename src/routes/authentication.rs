@@ -0,0 +1,820 @@
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use std::{net::IpAddr, sync::Arc};
+use warp::http::{header::SET_COOKIE, StatusCode};
+
+use crate::{
+    auth::{LoginThrottle, Oidc, Session, Tokens},
+    error::Error,
+    mailer::Notifier,
+    store::Store,
+    types::{
+        response::{JsonResponse, ResponseType},
+        totp::TotpLogin,
+        user::{
+            Credentials, EmailVerification, LinkPurpose, NewUser, OidcCallback, PasswordReset,
+            PasswordResetRequest, RefreshRequest, User,
+        },
+    },
+};
+
+pub async fn register(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    notifier: Notifier,
+    mut new_user: NewUser,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    new_user.email = normalize_email(&new_user.email);
+    new_user.password = hash_password(new_user.password).await?;
+
+    let user = match store.add_user(new_user).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    // The account exists either way; if the mail doesn't go out, the user
+    // can ask for another one
+    if let Err(e) = send_email_verification(&tokens, &notifier, &user).await {
+        tracing::warn!(account_id = %user.id, error = %e, "could not send verification mail");
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse::new(
+            false,
+            Some("account registered, check your email to verify it".to_string()),
+            Some(ResponseType::User(user)),
+        )),
+        StatusCode::OK,
+    ))
+}
+
+pub async fn verify_email(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    verification: EmailVerification,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let account_id = tokens
+        .redeem_link(LinkPurpose::VerifyEmail, &verification.token)
+        .await?;
+
+    if let Err(e) = store.set_email_verified(&account_id).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(%account_id, "email verified");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("email verified".to_string()),
+        None,
+    )))
+}
+
+/// Sends the caller another verification mail.
+pub async fn resend_email_verification(
+    session: Session,
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    notifier: Notifier,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = match store.get_user(&session.account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let message = if user.email_verified_at.is_some() {
+        "email already verified"
+    } else {
+        send_email_verification(&tokens, &notifier, &user).await?;
+        "verification mail sent"
+    };
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some(message.to_string()),
+        None,
+    )))
+}
+
+/// Mails a reset link if there is an account for the email. Answers the same
+/// way, and just as fast, whether there is or not, so it can't be used to
+/// find out which emails are registered.
+pub async fn request_password_reset(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    notifier: Notifier,
+    request: PasswordResetRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let email = normalize_email(&request.email);
+
+    tokio::spawn(async move {
+        let user = match store.get_user_by_email(&email).await {
+            Ok(user) => user,
+            Err(sqlx::Error::RowNotFound) => return,
+            Err(e) => {
+                tracing::error!(error = %e, "could not look up account for password reset");
+                return;
+            }
+        };
+
+        let result = match tokens
+            .issue_link(LinkPurpose::ResetPassword, &user.id)
+            .await
+        {
+            Ok(token) => notifier.send_password_reset(&user.email, &token).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => tracing::info!(account_id = %user.id, "password reset mail sent"),
+            Err(e) => {
+                tracing::error!(account_id = %user.id, error = %e, "could not send password reset mail")
+            }
+        }
+    });
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("if an account exists for this email, a reset link is on its way".to_string()),
+        None,
+    )))
+}
+
+/// Sets a new password from a reset link and logs out every session, since
+/// whoever had the old password may still hold one.
+pub async fn reset_password(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    reset: PasswordReset,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Hash first, so a failure here doesn't spend the link
+    let password = hash_password(reset.password).await?;
+
+    let account_id = tokens
+        .redeem_link(LinkPurpose::ResetPassword, &reset.token)
+        .await?;
+
+    if let Err(e) = store.update_password(&account_id, &password).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    let revoked = tokens.revoke_all(&account_id).await?;
+
+    tracing::info!(%account_id, revoked, "password reset");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("password changed, log in again".to_string()),
+        None,
+    )))
+}
+
+/// `sessions.user_agent` is a varchar(255)
+const MAX_USER_AGENT_LENGTH: usize = 255;
+
+pub async fn login(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    throttle: LoginThrottle,
+    user_agent: Option<String>,
+    client_ip: Option<IpAddr>,
+    credentials: Credentials,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let email = normalize_email(&credentials.email);
+
+    let attempt = throttle.reserve(&email, client_ip).await?;
+
+    let user = match check_password(store.as_ref(), &email, credentials.password).await {
+        Ok(user) => user,
+        Err(Error::WrongCredentials) => {
+            throttle.record_failure(attempt).await?;
+            return Err(warp::reject::custom(Error::WrongCredentials));
+        }
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+
+    if user.totp_enabled_at.is_some() {
+        throttle.release(attempt).await?;
+    } else {
+        throttle.record_success(attempt).await?;
+    }
+
+    start_session(&tokens, &user, user_agent).await
+}
+
+/// The account with this email and password.
+async fn check_password(store: &dyn Store, email: &str, password: String) -> Result<User, Error> {
+    let user = match store.get_user_by_email(email).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => {
+            // Take as long as a real check, so response times don't reveal
+            // which emails have an account
+            verify_password(DUMMY_HASH.to_string(), password).await?;
+            return Err(Error::WrongCredentials);
+        }
+        Err(e) => return Err(Error::DatabaseQueryError(e)),
+    };
+
+    // Accounts from single sign-on have no password to log in with
+    let hash = match &user.password {
+        Some(hash) => hash.clone(),
+        None => DUMMY_HASH.to_string(),
+    };
+
+    if !verify_password(hash, password).await? || user.password.is_none() {
+        return Err(Error::WrongCredentials);
+    }
+
+    Ok(user)
+}
+
+/// Second step of logging in to an account with two-factor authentication.
+/// Wrong codes are throttled like wrong passwords.
+pub async fn login_totp(
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    throttle: LoginThrottle,
+    client_ip: Option<IpAddr>,
+    login: TotpLogin,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let account_id = tokens.challenged_account(&login.challenge_token).await?;
+
+    let user = match store.get_user(&account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let attempt = throttle.reserve(&user.email, client_ip).await?;
+
+    let token = match tokens
+        .answer_challenge(&login.challenge_token, &login.code)
+        .await
+    {
+        Ok(token) => token,
+        Err(Error::WrongSecondFactor) => {
+            throttle.record_failure(attempt).await?;
+            return Err(warp::reject::custom(Error::WrongSecondFactor));
+        }
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+
+    throttle.record_success(attempt).await?;
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("logged in".to_string()),
+        Some(ResponseType::Token(token)),
+    )))
+}
+
+/// Sends the browser to the OpenID Connect provider to log in, with a
+/// cookie the callback checks the login against.
+pub async fn oidc_login(oidc: Oidc) -> Result<impl warp::Reply, warp::Rejection> {
+    let (url, state_cookie) = oidc.authorize_url().await?;
+
+    let uri = url
+        .as_str()
+        .parse::<warp::http::Uri>()
+        .map_err(|e| Error::SsoFailed(e.to_string()))?;
+
+    Ok(warp::reply::with_header(
+        warp::redirect::found(uri),
+        SET_COOKIE,
+        oidc.state_cookie(&state_cookie),
+    ))
+}
+
+/// Where the provider sends the browser back to. Starts a session for the
+/// account the provider identity belongs to, like `login`.
+pub async fn oidc_callback(
+    oidc: Oidc,
+    tokens: Tokens,
+    user_agent: Option<String>,
+    state_cookie: Option<String>,
+    callback: OidcCallback,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = oidc.callback(callback, state_cookie).await?;
+
+    tracing::info!(account_id = %user.id, "signed in with single sign-on");
+
+    let reply = start_session(&tokens, &user, user_agent).await?;
+
+    Ok(warp::reply::with_header(
+        reply,
+        SET_COOKIE,
+        oidc.clear_state_cookie(),
+    ))
+}
+
+/// Logs in an account that passed the first factor, or asks for the second
+/// if it has one.
+async fn start_session(
+    tokens: &Tokens,
+    user: &User,
+    user_agent: Option<String>,
+) -> Result<warp::reply::Json, warp::Rejection> {
+    let user_agent =
+        user_agent.map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+    if user.totp_enabled_at.is_some() {
+        let challenge = tokens.challenge(&user.id, user_agent).await?;
+
+        return Ok(warp::reply::json(&JsonResponse::new(
+            false,
+            Some("two-factor authentication code required".to_string()),
+            Some(ResponseType::Challenge(challenge)),
+        )));
+    }
+
+    let token = tokens.login(&user.id, user_agent, false).await?;
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("logged in".to_string()),
+        Some(ResponseType::Token(token)),
+    )))
+}
+
+pub async fn refresh(
+    tokens: Tokens,
+    request: RefreshRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let token = tokens.refresh(&request.refresh_token).await?;
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("refreshed session".to_string()),
+        Some(ResponseType::Token(token)),
+    )))
+}
+
+/// Ends the session the request was made with.
+pub async fn logout(session: Session, tokens: Tokens) -> Result<impl warp::Reply, warp::Rejection> {
+    tokens.revoke(&session).await?;
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("logged out".to_string()),
+        None,
+    )))
+}
+
+/// Ends every session of the caller's account, including this one.
+pub async fn revoke_all_sessions(
+    session: Session,
+    tokens: Tokens,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let revoked = tokens.revoke_all(&session.account_id).await?;
+
+    tracing::info!(account_id = %session.account_id, revoked, "revoked all sessions");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some(format!("revoked {} session(s)", revoked)),
+        None,
+    )))
+}
+
+pub async fn get_sessions(
+    session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store
+        .get_active_sessions(&session.account_id, session.login_id()?)
+        .await
+    {
+        Ok(sessions) => Ok(warp::reply::json(&JsonResponse::new(
+            false,
+            Some("got sessions".to_string()),
+            Some(ResponseType::Sessions(sessions)),
+        ))),
+        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+}
+
+/// Verified against when there is no account for an email. Uses the same
+/// parameters as `Argon2::default()`.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$nq4DkmFE/NH7hadVRU2B6w$72ryzdStNAE66EDTpdWkC7gZ96aazXM+7rnNaiciCbs";
+
+async fn send_email_verification(
+    tokens: &Tokens,
+    notifier: &Notifier,
+    user: &User,
+) -> Result<(), Error> {
+    let token = tokens
+        .issue_link(LinkPurpose::VerifyEmail, &user.id)
+        .await?;
+
+    notifier.send_email_verification(&user.email, &token).await
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Hashes with Argon2id and a random salt. Runs on the blocking pool since
+/// it deliberately takes a while.
+async fn hash_password(password: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|e| Error::PasswordHashing(e.to_string()))?
+    .map_err(|e| Error::PasswordHashing(e.to_string()))
+}
+
+async fn verify_password(hash: String, password: String) -> Result<bool, Error> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash)?;
+
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e),
+        }
+    })
+    .await
+    .map_err(|e| Error::PasswordHashing(e.to_string()))?
+    .map_err(|e| Error::PasswordHashing(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use serde_json::{json, Value};
+    use warp::{http::StatusCode, Filter};
+
+    use crate::{
+        auth::mock_idp::{self, Identity, MockIdp},
+        config::{Config, LoginThrottleConfig},
+        routes::testing::TestApp,
+        types::user::LinkPurpose,
+    };
+
+    fn redeem(purpose: LinkPurpose, token: &str) -> (&'static str, Value) {
+        match purpose {
+            LinkPurpose::VerifyEmail => ("/verify-email", json!({ "token": token })),
+            LinkPurpose::ResetPassword => (
+                "/password-reset/confirm",
+                json!({ "token": token, "password": "a new password" }),
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn links_work_once() {
+        let app = TestApp::new();
+        let (alice, _) = app.user("alice@example.com").await;
+
+        for purpose in [LinkPurpose::VerifyEmail, LinkPurpose::ResetPassword] {
+            let token = app.tokens().issue_link(purpose, &alice).await.unwrap();
+            let (path, body) = redeem(purpose, &token);
+
+            let (status, _) = app.request("POST", path, None, Some(body.clone())).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+
+            let (status, problem) = app.request("POST", path, None, Some(body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(problem["code"], "invalid_link_token");
+        }
+
+        let user = app.store.get_user(&alice).await.unwrap();
+        assert!(user.email_verified_at.is_some());
+        assert_ne!(user.password.as_deref(), Some("not a real hash"));
+    }
+
+    #[tokio::test]
+    async fn using_a_link_spends_the_earlier_ones() {
+        let app = TestApp::new();
+        let (alice, _) = app.user("alice@example.com").await;
+        let tokens = app.tokens();
+        let first = tokens
+            .issue_link(LinkPurpose::ResetPassword, &alice)
+            .await
+            .unwrap();
+        let second = tokens
+            .issue_link(LinkPurpose::ResetPassword, &alice)
+            .await
+            .unwrap();
+
+        let (path, body) = redeem(LinkPurpose::ResetPassword, &second);
+        let (status, _) = app.request("POST", path, None, Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (path, body) = redeem(LinkPurpose::ResetPassword, &first);
+        let (status, _) = app.request("POST", path, None, Some(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_links() {
+        let mut config = Config::for_tests();
+        config.auth.email_verification_ttl_secs = 0;
+        config.auth.password_reset_ttl_secs = 0;
+        let app = TestApp::with_config(config);
+        let (alice, _) = app.user("alice@example.com").await;
+
+        for purpose in [LinkPurpose::VerifyEmail, LinkPurpose::ResetPassword] {
+            let token = app.tokens().issue_link(purpose, &alice).await.unwrap();
+            let (path, body) = redeem(purpose, &token);
+
+            let (status, problem) = app.request("POST", path, None, Some(body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(problem["code"], "invalid_link_token");
+        }
+
+        let user = app.store.get_user(&alice).await.unwrap();
+        assert!(user.email_verified_at.is_none());
+        assert_eq!(user.password.as_deref(), Some("not a real hash"));
+    }
+
+    #[tokio::test]
+    async fn links_only_work_for_their_purpose() {
+        let app = TestApp::new();
+        let (alice, _) = app.user("alice@example.com").await;
+        let token = app
+            .tokens()
+            .issue_link(LinkPurpose::VerifyEmail, &alice)
+            .await
+            .unwrap();
+
+        let (path, body) = redeem(LinkPurpose::ResetPassword, &token);
+        let (status, _) = app.request("POST", path, None, Some(body)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn signs_in_through_the_provider() {
+        let idp = MockIdp::start();
+        let mut config = Config::for_tests();
+        config.oidc.issuer_url = idp.url.clone();
+        config.oidc.client_id = mock_idp::CLIENT_ID.to_string();
+        config.oidc.client_secret = "secret".to_string();
+        let app = TestApp::with_config(config);
+
+        let res = warp::test::request()
+            .path("/oidc/login")
+            .reply(&crate::routes::routes(&app.config, app.store.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+
+        let authorize_url = res.headers()["location"].to_str().unwrap().parse().unwrap();
+        let set_cookie = res.headers()["set-cookie"].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"), "{}", set_cookie);
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        let callback = idp.authorize(
+            &authorize_url,
+            Identity::new("1", "alice@example.com", true),
+        );
+        let path = format!(
+            "/oidc/callback?state={}&code={}",
+            callback.state,
+            callback.code.unwrap()
+        );
+
+        // Only the browser that started the login can finish it
+        let (status, problem) = app.request("GET", &path, None, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "sso_failed");
+
+        let (status, body) = app
+            .request_with_headers("GET", &path, &[("cookie", cookie)], None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let authorization = format!(
+            "Bearer {}",
+            body["data"]["Token"]["access_token"].as_str().unwrap()
+        );
+        let (status, _) = app
+            .request(
+                "POST",
+                "/questions",
+                Some(&authorization),
+                Some(json!({ "title": "title", "content": "content" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// An app with alice registered, throttling logins as `configure` says.
+    async fn throttled_app(configure: impl FnOnce(&mut LoginThrottleConfig)) -> TestApp {
+        let mut config = Config::for_tests();
+        configure(&mut config.login_throttle);
+
+        let app = TestApp::with_config(config);
+        app.register("alice@example.com", "correct horse").await;
+
+        app
+    }
+
+    async fn log_in(app: &TestApp, password: &str) -> (StatusCode, Value) {
+        app.request(
+            "POST",
+            "/login",
+            None,
+            Some(json!({ "email": "alice@example.com", "password": password })),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn makes_the_next_login_wait_after_a_wrong_password() {
+        let app = throttled_app(|config| {
+            config.base_delay_secs = 60;
+            config.max_delay_secs = 60;
+        })
+        .await;
+
+        let (status, _) = log_in(&app, "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Not even the right password gets in until the wait is over
+        let (status, problem) = log_in(&app, "correct horse").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(problem["code"], "too_many_requests");
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_after_too_many_wrong_passwords() {
+        let app = throttled_app(|config| {
+            config.base_delay_secs = 0;
+            config.max_delay_secs = 0;
+            config.account_max_failures = 2;
+        })
+        .await;
+
+        for _ in 0..2 {
+            let (status, _) = log_in(&app, "wrong").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, problem) = log_in(&app, "correct horse").await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(problem["code"], "account_locked");
+    }
+
+    #[tokio::test]
+    async fn logging_in_clears_the_failures() {
+        let app = throttled_app(|config| {
+            config.base_delay_secs = 0;
+            config.max_delay_secs = 0;
+            config.account_max_failures = 2;
+        })
+        .await;
+
+        for password in ["wrong", "correct horse", "wrong", "correct horse"] {
+            log_in(&app, password).await;
+        }
+
+        let (status, body) = log_in(&app, "correct horse").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    /// An app with alice registered and the default login throttle.
+    async fn app_with_alice() -> TestApp {
+        throttled_app(|_| ()).await
+    }
+
+    /// Sends a request to one set of routes, so that requests share its
+    /// session cache the way they do on a running server.
+    async fn send(
+        routes: &(impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + 'static),
+        method: &str,
+        path: &str,
+        authorization: &str,
+    ) -> (StatusCode, Value) {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", authorization)
+            .reply(routes)
+            .await;
+
+        (
+            res.status(),
+            serde_json::from_slice(res.body()).unwrap_or(Value::Null),
+        )
+    }
+
+    /// Logs alice in, returning her token.
+    async fn tokens(app: &TestApp) -> Value {
+        let (status, body) = log_in(app, "correct horse").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        body["data"]["Token"].clone()
+    }
+
+    fn bearer(token: &Value) -> String {
+        format!("Bearer {}", token["access_token"].as_str().unwrap())
+    }
+
+    async fn refresh(app: &TestApp, token: &Value) -> (StatusCode, Value) {
+        app.request(
+            "POST",
+            "/sessions/refresh",
+            None,
+            Some(json!({ "refresh_token": token["refresh_token"] })),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn refreshing_replaces_the_refresh_token() {
+        let app = app_with_alice().await;
+        let token = tokens(&app).await;
+
+        let (status, body) = refresh(&app, &token).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let refreshed = body["data"]["Token"].clone();
+        assert_ne!(refreshed["refresh_token"], token["refresh_token"]);
+
+        let (status, problem) = refresh(&app, &token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(problem["code"], "invalid_token");
+
+        let (status, body) = refresh(&app, &refreshed).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn logging_out_ends_the_session_at_once() {
+        let app = app_with_alice().await;
+        let routes = crate::routes::routes(&app.config, app.store.clone());
+        let authorization = bearer(&tokens(&app).await);
+
+        // Remembered as active by the auth filter
+        let (status, _) = send(&routes, "GET", "/sessions", &authorization).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(&routes, "POST", "/logout", &authorization).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, problem) = send(&routes, "GET", "/sessions", &authorization).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(problem["code"], "session_revoked");
+    }
+
+    #[tokio::test]
+    async fn revoking_all_sessions_ends_every_one_at_once() {
+        let app = app_with_alice().await;
+        let routes = crate::routes::routes(&app.config, app.store.clone());
+        let first = bearer(&tokens(&app).await);
+        let second = bearer(&tokens(&app).await);
+
+        for authorization in [&first, &second] {
+            let (status, _) = send(&routes, "GET", "/sessions", authorization).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, _) = send(&routes, "POST", "/sessions/revoke-all", &first).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for authorization in [&first, &second] {
+            let (status, problem) = send(&routes, "GET", "/sessions", authorization).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(problem["code"], "session_revoked");
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_sessions_marking_the_current_one() {
+        let app = app_with_alice().await;
+        let first = bearer(&tokens(&app).await);
+        let second = bearer(&tokens(&app).await);
+
+        let mut current = Vec::new();
+
+        for authorization in [&first, &second] {
+            let (status, body) = app
+                .request("GET", "/sessions", Some(authorization), None)
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+
+            let sessions = body["data"]["Sessions"].as_array().unwrap();
+            assert_eq!(sessions.len(), 2);
+
+            let marked: Vec<_> = sessions
+                .iter()
+                .filter(|session| session["current"] == true)
+                .map(|session| session["id"].clone())
+                .collect();
+            assert_eq!(marked.len(), 1);
+
+            current.push(marked[0].clone());
+        }
+
+        assert_ne!(current[0], current[1]);
+    }
+}
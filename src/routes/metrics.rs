@@ -0,0 +1,15 @@
+use std::sync::Arc;
+
+use warp;
+
+use crate::client::{ApiClient, Metrics};
+
+pub async fn get_metrics_handler(
+    api_clients: Vec<Arc<ApiClient>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::with_header(
+        Metrics::render(&api_clients),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
@@ -1,2 +1,528 @@
+pub mod admin;
 pub mod answer;
+pub mod api_key;
+pub mod authentication;
+pub mod metrics;
 pub mod question;
+pub mod totp;
+
+#[cfg(test)]
+pub mod testing;
+
+use std::{convert::Infallible, sync::Arc};
+
+use warp::{http::Method, Filter};
+
+use crate::{
+    auth::{self, LoginThrottle, Oidc, Tokens},
+    client::ApiClient,
+    config::Config,
+    error, filters,
+    mailer::{self, Notifier},
+    moderation::{self, Moderator},
+    store::Store,
+    types::{api_key::ApiScope, pagination::pagination, role::Permission},
+    validation,
+};
+
+/// Every route of the API, with errors turned into problem responses.
+pub fn routes(
+    config: &Config,
+    store: Arc<dyn Store>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone {
+    let tokens = Tokens::new(&config.auth, store.clone());
+    let auth = auth::auth(tokens.clone());
+    let questions_write = auth::auth_or_api_key(tokens.clone(), ApiScope::QuestionsWrite);
+    let answers_write = auth::auth_or_api_key(tokens.clone(), ApiScope::AnswersWrite);
+    let manage_roles =
+        auth::require_permission(Permission::ManageRoles, tokens.clone(), store.clone());
+    let close_questions =
+        auth::require_permission(Permission::CloseQuestions, tokens.clone(), store.clone());
+    let tokens_filter = warp::any().map(move || tokens.clone());
+
+    let notifier = Notifier::new(
+        mailer::from_config(&config.mailer).expect("should be able to set up mailer"),
+        config
+            .mailer
+            .app_url
+            .parse()
+            .expect("should be able to parse mailer.app_url"),
+    );
+    let notifier_filter = warp::any().map(move || notifier.clone());
+
+    let pagination_filter = pagination(config.pagination.clone());
+
+    let moderation_client = Arc::new(
+        ApiClient::new("moderation", &config.external_api)
+            .expect("should be able to build http client"),
+    );
+    let oidc_client = Arc::new(
+        ApiClient::new("oidc", &config.external_api).expect("should be able to build http client"),
+    );
+
+    let moderator = Moderator::new(
+        moderation::from_config(&config.moderation, moderation_client.clone())
+            .expect("should be able to load content filter"),
+        config.moderation.policy,
+    );
+    let moderator_filter = warp::any().map(move || moderator.clone());
+
+    let oidc = Oidc::new(&config.oidc, oidc_client.clone(), store.clone());
+    let oidc_filter = warp::any().map(move || oidc.clone());
+
+    let throttle = LoginThrottle::new(&config.login_throttle, store.clone());
+    let throttle_filter = warp::any().map(move || throttle.clone());
+
+    let store_filter = warp::any().map(move || store.clone());
+
+    let api_clients = vec![moderation_client, oidc_client];
+    let api_clients_filter = warp::any().map(move || api_clients.clone());
+
+    let cors = warp::cors()
+        .allow_headers(["Content-Type", "Authorization", "X-Api-Key"])
+        .allow_methods(&[Method::PUT, Method::POST, Method::DELETE, Method::GET]);
+
+    let cors = if config.allows_any_origin() {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(config.cors.allowed_origins.iter().map(String::as_str))
+    };
+
+    let get_questions = warp::path("questions")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(pagination_filter.clone())
+        .and(store_filter.clone())
+        .and_then(question::get_questions_handler)
+        .with(warp::trace(|info| 
+            tracing::info_span!("get_questions request", method = %info.method(), path = %info.path(), id = %uuid::Uuid::new_v4())
+        ));
+
+    let add_question = warp::path("questions")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(questions_write.clone())
+        .and(store_filter.clone())
+        .and(moderator_filter.clone())
+        .and(validation::json_body())
+        .and_then(question::add_question_handler);
+
+    let update_question = warp::path("questions")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(questions_write.clone())
+        .and(validation::json_body())
+        .and(store_filter.clone())
+        .and(moderator_filter.clone())
+        .and_then(question::update_question_handler);
+
+    let get_question_by_id = warp::path("questions")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(question::get_question_by_id_handler);
+
+    let delete_question = warp::path("questions")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(questions_write.clone())
+        .and(store_filter.clone())
+        .and_then(question::delete_question_handler);
+
+    let close_question = warp::path("questions")
+        .and(warp::path::param::<i32>())
+        .and(warp::path("close"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(close_questions)
+        .and(store_filter.clone())
+        .and_then(question::close_question_handler);
+
+    let get_answers = warp::path("answers")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(pagination_filter.clone())
+        .and(store_filter.clone())
+        .and_then(answer::get_answers_handler);
+
+    let add_answer = warp::path("answers")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(answers_write.clone())
+        .and(store_filter.clone())
+        .and(moderator_filter.clone())
+        .and(validation::json_body())
+        .and_then(answer::add_answer_handler);
+
+    let get_answer_by_id = warp::path("answers")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(answer::get_answer_by_id_handler);
+
+    let update_answer = warp::path("answer")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(answers_write.clone())
+        .and(validation::json_body())
+        .and(store_filter.clone())
+        .and(moderator_filter.clone())
+        .and_then(answer::update_answer_handler);
+
+    let delete_answer = warp::path("answer")
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(answers_write.clone())
+        .and(store_filter.clone())
+        .and_then(answer::delete_answer_handler);
+
+    let get_answers_for_question = warp::path("questions")
+        .and(warp::path::param::<i32>())
+        .and(warp::path("answers"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(pagination_filter.clone())
+        .and(store_filter.clone())
+        .and_then(answer::get_answers_for_question_handler);
+
+    let get_metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api_clients_filter.clone())
+        .and_then(metrics::get_metrics_handler);
+
+    let registration = warp::path("registration")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(notifier_filter.clone())
+        .and(validation::json_body())
+        .and_then(authentication::register);
+
+    let verify_email = warp::path("verify-email")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(validation::json_body())
+        .and_then(authentication::verify_email);
+
+    let resend_email_verification = warp::path("verify-email")
+        .and(warp::path("resend"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(notifier_filter.clone())
+        .and_then(authentication::resend_email_verification);
+
+    let request_password_reset = warp::path("password-reset")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(notifier_filter.clone())
+        .and(validation::json_body())
+        .and_then(authentication::request_password_reset);
+
+    let reset_password = warp::path("password-reset")
+        .and(warp::path("confirm"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(validation::json_body())
+        .and_then(authentication::reset_password);
+
+    let login = warp::path("login")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(throttle_filter.clone())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(filters::client_ip(config.server.trust_forwarded_for))
+        .and(validation::json_body())
+        .and_then(authentication::login);
+
+    let login_totp = warp::path("login")
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(throttle_filter.clone())
+        .and(filters::client_ip(config.server.trust_forwarded_for))
+        .and(validation::json_body())
+        .and_then(authentication::login_totp);
+
+    let enroll_totp = warp::path("account")
+        .and(warp::path("totp"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and_then(totp::enroll);
+
+    let confirm_totp = warp::path("account")
+        .and(warp::path("totp"))
+        .and(warp::path("confirm"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(validation::json_body())
+        .and_then(totp::confirm);
+
+    let disable_totp = warp::path("account")
+        .and(warp::path("totp"))
+        .and(warp::path("disable"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and(throttle_filter.clone())
+        .and(filters::client_ip(config.server.trust_forwarded_for))
+        .and(validation::json_body())
+        .and_then(totp::disable);
+
+    let oidc_login = warp::path("oidc")
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(oidc_filter.clone())
+        .and_then(authentication::oidc_login);
+
+    let oidc_callback = warp::path("oidc")
+        .and(warp::path("callback"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(oidc_filter.clone())
+        .and(tokens_filter.clone())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::cookie::optional::<String>(Oidc::STATE_COOKIE))
+        .and(warp::query())
+        .and_then(authentication::oidc_callback);
+
+    let refresh = warp::path("sessions")
+        .and(warp::path("refresh"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(tokens_filter.clone())
+        .and(validation::json_body())
+        .and_then(authentication::refresh);
+
+    let logout = warp::path("logout")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(tokens_filter.clone())
+        .and_then(authentication::logout);
+
+    let revoke_all_sessions = warp::path("sessions")
+        .and(warp::path("revoke-all"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(tokens_filter.clone())
+        .and_then(authentication::revoke_all_sessions);
+
+    let get_sessions = warp::path("sessions")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and_then(authentication::get_sessions);
+
+    let create_api_key = warp::path("account")
+        .and(warp::path("api-keys"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and(tokens_filter.clone())
+        .and(validation::json_body())
+        .and_then(api_key::create_api_key);
+
+    let get_api_keys = warp::path("account")
+        .and(warp::path("api-keys"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and_then(api_key::get_api_keys);
+
+    let revoke_api_key = warp::path("account")
+        .and(warp::path("api-keys"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(store_filter.clone())
+        .and_then(api_key::revoke_api_key);
+
+    let get_roles = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path("roles"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(manage_roles.clone())
+        .and(store_filter.clone())
+        .and_then(admin::get_roles_handler);
+
+    let grant_role = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path("roles"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(manage_roles.clone())
+        .and(store_filter.clone())
+        .and(validation::json_body())
+        .and_then(admin::grant_role_handler);
+
+    let revoke_role = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<i32>())
+        .and(warp::path("roles"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(manage_roles.clone())
+        .and(store_filter.clone())
+        .and_then(admin::revoke_role_handler);
+
+    let get_audit_log = warp::path("admin")
+        .and(warp::path("audit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(manage_roles.clone())
+        .and(pagination_filter.clone())
+        .and(store_filter.clone())
+        .and_then(admin::get_audit_log_handler);
+
+    // TODO: generate unique (incremented?) id when adding a question
+
+    // TODO: generate a unique (incremented?) id when adding an answer
+
+    let routes = get_questions
+        .or(get_question_by_id)
+        .or(update_question)
+        .or(add_question)
+        .or(delete_question)
+        .or(close_question)
+        .or(get_answers)
+        .or(get_answer_by_id)
+        .or(update_answer)
+        .or(add_answer)
+        .or(delete_answer)
+        .or(get_answers_for_question)
+        .or(get_metrics)
+        .or(registration)
+        .or(verify_email)
+        .or(resend_email_verification)
+        .or(request_password_reset)
+        .or(reset_password)
+        .or(login)
+        .or(login_totp)
+        .or(enroll_totp)
+        .or(confirm_totp)
+        .or(disable_totp)
+        .or(oidc_login)
+        .or(oidc_callback)
+        .or(refresh)
+        .or(logout)
+        .or(revoke_all_sessions)
+        .or(get_sessions)
+        .or(create_api_key)
+        .or(get_api_keys)
+        .or(revoke_api_key)
+        .or(get_roles)
+        .or(grant_role)
+        .or(revoke_role)
+        .or(get_audit_log)
+        .with(cors);
+
+    error::recover(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::testing::TestApp;
+
+    #[tokio::test]
+    async fn unknown_paths_are_not_found() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        for (method, path) in [
+            ("GET", "/questions/abc"),
+            ("GET", "/questions/1/foo"),
+            ("PUT", "/questions/abc"),
+            ("DELETE", "/questions/1/answers/2"),
+            ("DELETE", "/answer/abc"),
+            ("GET", "/admin/users/abc/roles"),
+            ("DELETE", "/account/api-keys/abc"),
+        ] {
+            let (status, problem) = app.request(method, path, Some(&authorization), None).await;
+
+            assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, path);
+            assert_eq!(problem["code"], "not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_are_not_allowed() {
+        let app = TestApp::new();
+
+        for (method, path) in [
+            ("DELETE", "/questions"),
+            ("PUT", "/questions"),
+            ("PATCH", "/questions/1"),
+            ("POST", "/answers/1"),
+            ("DELETE", "/account/api-keys"),
+            ("PUT", "/admin/users/1/roles"),
+        ] {
+            let (status, problem) = app.request(method, path, None, None).await;
+
+            assert_eq!(
+                status,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}",
+                method,
+                path
+            );
+            assert_eq!(problem["code"], "method_not_allowed");
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_is_checked_once_the_route_matches() {
+        let app = TestApp::new();
+
+        for (method, path) in [
+            ("DELETE", "/questions/1"),
+            ("PUT", "/answer/1"),
+            ("DELETE", "/account/api-keys/1"),
+            ("GET", "/admin/users/1/roles"),
+        ] {
+            let (status, problem) = app.request(method, path, None, None).await;
+
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{} {}", method, path);
+            assert_eq!(problem["code"], "missing_token");
+        }
+    }
+}
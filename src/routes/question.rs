@@ -1,98 +1,168 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 use warp;
 use warp::http::StatusCode;
 
 use crate::{
+    auth::Session,
     error::Error,
-    store::Store,
+    moderation::Moderator,
+    store::{abort, Store, UnitOfWork},
     types::{
-        pagination::{extract_pagination, Pagination},
+        pagination::Pagination,
         question::{NewQuestion, Question},
         response::{JsonResponse, ResponseType},
     },
+    validation,
 };
 
 pub async fn get_questions_handler(
-    params: HashMap<String, String>,
-    store: Store,
+    pagination: Pagination,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut pagination = Pagination::default();
-
-    if !params.is_empty() {
-        pagination = extract_pagination(params)?;
-    }
+    let (res, next_cursor) = match store.get_questions(&pagination).await {
+        Ok(rows) => pagination.split(rows),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
 
-    let res: Vec<Question> = match store
-        .get_questions(pagination.limit, pagination.offset)
+    let total = match store
+        .count_questions(pagination.since, pagination.until)
         .await
     {
-        Ok(questions) => questions,
+        Ok(total) => total,
         Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
     };
 
-    Ok(warp::reply::json(&JsonResponse::new(
-        false,
-        Some("found questions".to_string()),
-        Some(ResponseType::Questions(res)),
-    )))
+    Ok(warp::reply::json(
+        &JsonResponse::new(
+            false,
+            Some("found questions".to_string()),
+            Some(ResponseType::Questions(res)),
+        )
+        .with_page(
+            pagination.page("/questions", total, next_cursor.as_deref()),
+            next_cursor,
+        ),
+    ))
 }
 
 pub async fn add_question_handler(
-    store: Store,
-    new_question: NewQuestion,
+    session: Session,
+    store: Arc<dyn Store>,
+    moderator: Moderator,
+    mut new_question: NewQuestion,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let client = reqwest::Client::new();
+    let flags = moderator
+        .question(
+            &mut new_question.title,
+            &mut new_question.content,
+            &mut new_question.tags,
+        )
+        .await?;
 
-    let res = client
-        .post("https://api.apilayer.com/bad_words?censor_character=*")
-        .header("apikey", "2ESAPL82dO2MpLuJ8HXBN3Y0yfG4ayaq")
-        .body(new_question.content.clone())
-        .send()
-        .await
-        .map_err(Error::ExternalApiError)?;
-
-    match res.error_for_status() {
-        Ok(res) => {
-            let res = res.text().await.map_err(Error::ExternalApiError)?;
-            println!("cleaned: {:#?}", res);
-
-            match store.add_question(new_question).await {
-                Ok(question) => Ok(warp::reply::with_status(
-                    warp::reply::json(&JsonResponse::new(
-                        false,
-                        Some("question added".to_string()),
-                        Some(ResponseType::Question(question)),
-                    )),
-                    StatusCode::OK,
-                )),
-                Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
-            }
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let question = match work.add_question(new_question, &session.account_id).await {
+        Ok(question) => question,
+        Err(e) => {
+            return Err(warp::reject::custom(Error::DatabaseQueryError(
+                abort(work, e).await,
+            )))
         }
-        Err(e) => Err(warp::reject::custom(Error::ExternalApiError(e))),
+    };
+
+    if let Err(e) = work
+        .queue_for_review("question", question.id.0, flags)
+        .await
+    {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
     }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, question_id = %question.id, "question added");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse::new(
+            false,
+            Some("question added".to_string()),
+            Some(ResponseType::Question(question)),
+        )),
+        StatusCode::OK,
+    ))
 }
 
 pub async fn update_question_handler(
     question_id: i32,
-    store: Store,
-    question: Question,
+    session: Session,
+    mut question: Question,
+    store: Arc<dyn Store>,
+    moderator: Moderator,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.update_question(question, question_id).await {
-        Ok(question) => Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(
-                false,
-                Some("updated question".to_string()),
-                Some(ResponseType::Question(question)),
-            )),
-            StatusCode::OK,
-        )),
-        Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    validation::matching_id(question_id, &question)?;
+
+    let flags = moderator
+        .question(
+            &mut question.title,
+            &mut question.content,
+            &mut question.tags,
+        )
+        .await?;
+
+    let may_moderate = session.may_moderate(store.as_ref()).await?;
+
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if let Err(e) = check_owner(&session, may_moderate, work.as_mut(), question_id).await {
+        return Err(warp::reject::custom(abort(work, e).await));
     }
+
+    let question = match work.update_question(question, question_id).await {
+        Ok(question) => question,
+        Err(e) => {
+            return Err(warp::reject::custom(Error::DatabaseQueryError(
+                abort(work, e).await,
+            )))
+        }
+    };
+
+    if let Err(e) = work
+        .queue_for_review("question", question.id.0, flags)
+        .await
+    {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
+    }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, question_id, "question updated");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse::new(
+            false,
+            Some("updated question".to_string()),
+            Some(ResponseType::Question(question)),
+        )),
+        StatusCode::OK,
+    ))
 }
 
 pub async fn get_question_by_id_handler(
     question_id: i32,
-    store: Store,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match store.get_question_by_id(question_id).await {
         Ok(question) => Ok(warp::reply::with_status(
@@ -109,17 +179,305 @@ pub async fn get_question_by_id_handler(
 
 pub async fn delete_question_handler(
     question_id: i32,
-    store: Store,
+    session: Session,
+    store: Arc<dyn Store>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match store.delete_question(question_id).await {
-        Ok(_) => Ok(warp::reply::with_status(
-            warp::reply::json(&JsonResponse::new(
-                false,
-                Some("deleted question".to_string()),
-                None,
-            )),
-            StatusCode::OK,
+    let may_moderate = session.may_moderate(store.as_ref()).await?;
+
+    let mut work = match store.begin().await {
+        Ok(work) => work,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if let Err(e) = check_owner(&session, may_moderate, work.as_mut(), question_id).await {
+        return Err(warp::reject::custom(abort(work, e).await));
+    }
+
+    if let Err(e) = work.delete_question(question_id).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(
+            abort(work, e).await,
+        )));
+    }
+
+    if let Err(e) = work.commit().await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(account_id = %session.account_id, credential = %session.credential, question_id, "question deleted");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse::new(
+            false,
+            Some("deleted question".to_string()),
+            None,
         )),
+        StatusCode::OK,
+    ))
+}
+
+/// Closes a question to new answers.
+pub async fn close_question_handler(
+    question_id: i32,
+    session: Session,
+    store: Arc<dyn Store>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.close_question(question_id).await {
+        Ok(question) => {
+            tracing::info!(account_id = %session.account_id, credential = %session.credential, question_id, "question closed");
+
+            Ok(warp::reply::json(&JsonResponse::new(
+                false,
+                Some("closed question".to_string()),
+                Some(ResponseType::Question(question)),
+            )))
+        }
         Err(e) => Err(warp::reject::custom(Error::DatabaseQueryError(e))),
     }
 }
+
+/// Only the author of a question, or a moderator, may change it. Reads the
+/// question in `work`, so it can't change hands or go away before the
+/// change is made.
+async fn check_owner(
+    session: &Session,
+    may_moderate: bool,
+    work: &mut dyn UnitOfWork,
+    question_id: i32,
+) -> Result<(), Error> {
+    let question = work
+        .get_question_by_id(question_id)
+        .await
+        .map_err(Error::DatabaseQueryError)?;
+
+    session.require_owner(may_moderate, question.account_id.as_ref(), "question")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::{config::ModerationPolicy, routes::testing::TestApp};
+
+    async fn add_question(app: &TestApp, authorization: &str, title: &str) -> Value {
+        let (status, body) = app
+            .request(
+                "POST",
+                "/questions",
+                Some(authorization),
+                Some(json!({ "title": title, "content": "content", "tags": ["rust"] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        body["data"]["Question"].clone()
+    }
+
+    #[tokio::test]
+    async fn moderates_updates() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+        let question = add_question(&app, &authorization, "darn title").await;
+
+        assert_eq!(question["title"], "**** title");
+
+        let (status, body) = app
+            .request(
+                "PUT",
+                "/questions/1",
+                Some(&authorization),
+                Some(json!({
+                    "id": 1,
+                    "title": "still a darn title",
+                    "content": "darn content",
+                    "tags": ["rust", "darn"],
+                })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Question"]["title"], "still a **** title");
+        assert_eq!(body["data"]["Question"]["content"], "**** content");
+        assert_eq!(body["data"]["Question"]["tags"], json!(["rust"]));
+    }
+
+    #[tokio::test]
+    async fn rejected_updates_change_nothing() {
+        let mut config = crate::config::Config::for_tests();
+        config.moderation.policy.question_content = ModerationPolicy::Reject;
+        let app = TestApp::with_config(config);
+        let (_, authorization) = app.user("alice@example.com").await;
+        add_question(&app, &authorization, "title").await;
+
+        let (status, problem) = app
+            .request(
+                "PUT",
+                "/questions/1",
+                Some(&authorization),
+                Some(json!({ "id": 1, "title": "new title", "content": "darn" })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["code"], "objectionable_content");
+
+        let (_, body) = app.request("GET", "/questions/1", None, None).await;
+        assert_eq!(body["data"]["Question"]["title"], "title");
+    }
+
+    #[tokio::test]
+    async fn moderators_close_questions_to_new_answers() {
+        let app = TestApp::new();
+        let (alice, authorization) = app.user("alice@example.com").await;
+        let with_mfa = app.login(&alice, true).await;
+        let (_, moderator) = app.user_with_role("mod@example.com", "moderator").await;
+        add_question(&app, &authorization, "title").await;
+
+        let (status, problem) = app
+            .request("POST", "/questions/1/close", Some(&with_mfa), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            problem["detail"],
+            "forbidden: missing permission questions.close"
+        );
+
+        let (status, body) = app
+            .request("POST", "/questions/1/close", Some(&moderator), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"]["Question"]["closed_at"].is_string());
+
+        let (status, problem) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(&authorization),
+                Some(json!({ "question_id": 1, "content": "too late" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(problem["detail"], "conflict: question 1 is closed");
+
+        let (status, _) = app
+            .request("POST", "/questions/2/close", Some(&moderator), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn adds_lists_and_deletes_questions() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        for title in ["first", "second", "third"] {
+            add_question(&app, &authorization, title).await;
+        }
+
+        assert_eq!(
+            app.page_through("/questions?limit=1", "Questions").await,
+            [1, 2, 3]
+        );
+        assert_eq!(
+            app.page_through("/questions?limit=2&sort=-created_at", "Questions")
+                .await,
+            [3, 2, 1]
+        );
+
+        let (status, body) = app.request("GET", "/questions/2", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Question"]["title"], "second");
+
+        let (status, _) = app
+            .request("DELETE", "/questions/2", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app.request("GET", "/questions/2", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            app.page_through("/questions?limit=1", "Questions").await,
+            [1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_author_or_a_moderator_changes_a_question() {
+        let app = TestApp::new();
+        let (_, alice) = app.user("alice@example.com").await;
+        let (_, bob) = app.user("bob@example.com").await;
+        let (_, moderator) = app.user_with_role("mod@example.com", "moderator").await;
+        add_question(&app, &alice, "title").await;
+
+        let update = json!({ "id": 1, "title": "new title", "content": "content" });
+
+        let (status, problem) = app
+            .request("PUT", "/questions/1", Some(&bob), Some(update.clone()))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            problem["detail"],
+            "forbidden: question belongs to another account"
+        );
+
+        let (status, _) = app
+            .request("DELETE", "/questions/1", Some(&bob), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = app
+            .request("PUT", "/questions/1", Some(&moderator), Some(update))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["Question"]["title"], "new title");
+
+        let (status, _) = app
+            .request("DELETE", "/questions/1", Some(&moderator), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn missing_questions_are_not_found() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (status, _) = app
+            .request(
+                "PUT",
+                "/questions/9",
+                Some(&authorization),
+                Some(json!({ "id": 9, "title": "title", "content": "content" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = app
+            .request("DELETE", "/questions/9", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn keeps_questions_that_have_answers() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+        add_question(&app, &authorization, "title").await;
+        app.request(
+            "POST",
+            "/answers",
+            Some(&authorization),
+            Some(json!({ "question_id": 1, "content": "answer" })),
+        )
+        .await;
+
+        let (status, problem) = app
+            .request("DELETE", "/questions/1", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "invalid_reference");
+
+        let (status, _) = app.request("GET", "/questions/1", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
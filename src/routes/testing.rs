@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use crate::{
+    auth::Tokens,
+    config::Config,
+    store::{MemoryStore, Store},
+    types::user::{AccountId, NewUser},
+};
+
+/// The full set of routes on an empty memory store, for driving handlers
+/// the way clients do.
+pub struct TestApp {
+    pub config: Config,
+    pub store: Arc<dyn Store>,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        TestApp::with_config(Config::for_tests())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        TestApp {
+            config,
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    /// Registers `email` and logs it in, returning the account and its
+    /// `Authorization` header.
+    pub async fn user(&self, email: &str) -> (AccountId, String) {
+        let user = self
+            .store
+            .add_user(NewUser {
+                email: email.to_string(),
+                password: "not a real hash".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let header = self.login(&user.id, false).await;
+
+        (user.id, header)
+    }
+
+    /// Registers `email` with `role` and logs it in with a second factor,
+    /// as permissions need.
+    pub async fn user_with_role(&self, email: &str, role: &str) -> (AccountId, String) {
+        let (account_id, _) = self.user(email).await;
+
+        self.store
+            .grant_role(account_id.0, role, &account_id)
+            .await
+            .unwrap();
+
+        let header = self.login(&account_id, true).await;
+
+        (account_id, header)
+    }
+
+    pub fn tokens(&self) -> Tokens {
+        Tokens::new(&self.config.auth, self.store.clone())
+    }
+
+    pub async fn login(&self, account_id: &AccountId, mfa: bool) -> String {
+        let token = self.tokens().login(account_id, None, mfa).await.unwrap();
+
+        format!("Bearer {}", token.access_token)
+    }
+
+    /// Registers `email` through the API, so it can log in with `password`.
+    pub async fn register(&self, email: &str, password: &str) -> AccountId {
+        let (status, body) = self
+            .request(
+                "POST",
+                "/registration",
+                None,
+                Some(json!({ "email": email, "password": password })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        serde_json::from_value(body["data"]["User"]["id"].clone()).unwrap()
+    }
+
+    /// The ids of the `item` rows on every page of `path`, following
+    /// `next_cursor` to the end.
+    pub async fn page_through(&self, path: &str, item: &str) -> Vec<i64> {
+        let mut ids = Vec::new();
+        let mut url = path.to_string();
+
+        for _ in 0..10 {
+            let (status, body) = self.request("GET", &url, None, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+
+            ids.extend(
+                body["data"][item]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|row| row["id"].as_i64().unwrap()),
+            );
+
+            match body["next_cursor"].as_str() {
+                Some(cursor) => url = format!("{}&cursor={}", path, cursor),
+                None => return ids,
+            }
+        }
+
+        panic!("{} never ended: {:?}", path, ids);
+    }
+
+    /// Sends a request, with a JSON body if one is given, returning the
+    /// status and the JSON it answered with.
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let headers: Vec<_> = authorization
+            .map(|authorization| ("authorization", authorization))
+            .into_iter()
+            .collect();
+
+        self.request_with_headers(method, path, &headers, body)
+            .await
+    }
+
+    pub async fn request_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = warp::test::request().method(method).path(path);
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        let res = request
+            .reply(&super::routes(&self.config, self.store.clone()))
+            .await;
+        let body = serde_json::from_slice(res.body()).unwrap_or(Value::Null);
+
+        (res.status(), body)
+    }
+}
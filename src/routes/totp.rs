@@ -0,0 +1,450 @@
+use std::{net::IpAddr, sync::Arc};
+
+use crate::{
+    auth::{totp, LoginThrottle, Session, Tokens},
+    error::Error,
+    store::Store,
+    types::{
+        response::{JsonResponse, ResponseType},
+        totp::{TotpCode, TotpEnrollment},
+    },
+};
+
+/// Starts turning on two-factor authentication with a new secret. Starting
+/// again before confirming replaces the secret.
+pub async fn enroll(
+    session: Session,
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = match store.get_user(&session.account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let secret = totp::generate_secret();
+
+    match store.set_totp_secret(&session.account_id, &secret).await {
+        Ok(()) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(warp::reject::custom(Error::Conflict(
+                "two-factor authentication is already on".to_string(),
+            )))
+        }
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+
+    let otpauth_uri = totp::otpauth_uri(&secret, &user.email, tokens.totp_issuer())?;
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("add the secret to an authenticator app, then confirm with a code".to_string()),
+        Some(ResponseType::TotpEnrollment(TotpEnrollment {
+            secret,
+            otpauth_uri,
+        })),
+    )))
+}
+
+/// Turns two-factor authentication on once the caller shows their
+/// authenticator produces the right codes. Returns recovery codes, and logs
+/// out every session so the next login uses the second factor. API keys
+/// are revoked too, since they get past it.
+pub async fn confirm(
+    session: Session,
+    store: Arc<dyn Store>,
+    tokens: Tokens,
+    body: TotpCode,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let no_enrollment =
+        || Error::Conflict("no two-factor authentication enrollment to confirm".to_string());
+
+    let user = match store.get_user(&session.account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    if user.totp_enabled_at.is_some() {
+        return Err(warp::reject::custom(no_enrollment()));
+    }
+
+    let secret = match store.get_totp_secret(&session.account_id).await {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return Err(warp::reject::custom(no_enrollment())),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let step = match totp::matching_step(&secret, body.code.trim())? {
+        Some(step) => step,
+        None => return Err(warp::reject::custom(Error::WrongSecondFactor)),
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect();
+
+    match store
+        .enable_totp(&session.account_id, step, &code_hashes)
+        .await
+    {
+        Ok(()) => (),
+        Err(sqlx::Error::RowNotFound) => return Err(warp::reject::custom(no_enrollment())),
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    }
+
+    let revoked = tokens.revoke_all(&session.account_id).await?;
+
+    let revoked_api_keys = match store.revoke_api_keys(&session.account_id).await {
+        Ok(revoked_api_keys) => revoked_api_keys,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    tracing::info!(account_id = %session.account_id, revoked, revoked_api_keys, "two-factor authentication on");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some(
+            "two-factor authentication on, keep the recovery codes somewhere safe, log in again and replace any API keys"
+                .to_string(),
+        ),
+        Some(ResponseType::RecoveryCodes(recovery_codes)),
+    )))
+}
+
+/// Turns two-factor authentication off, given a current code or a recovery
+/// code. Wrong codes are throttled like wrong ones when logging in.
+pub async fn disable(
+    session: Session,
+    store: Arc<dyn Store>,
+    throttle: LoginThrottle,
+    client_ip: Option<IpAddr>,
+    body: TotpCode,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = match store.get_user(&session.account_id).await {
+        Ok(user) => user,
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let secret = match store.get_totp_secret(&session.account_id).await {
+        Ok(Some(secret)) if user.totp_enabled_at.is_some() => secret,
+        Ok(_) => {
+            return Err(warp::reject::custom(Error::Conflict(
+                "two-factor authentication is not on".to_string(),
+            )))
+        }
+        Err(e) => return Err(warp::reject::custom(Error::DatabaseQueryError(e))),
+    };
+
+    let attempt = throttle.reserve(&user.email, client_ip).await?;
+
+    if !totp::check_code(store.as_ref(), &session.account_id, &secret, &body.code).await? {
+        throttle.record_failure(attempt).await?;
+        return Err(warp::reject::custom(Error::WrongSecondFactor));
+    }
+
+    throttle.record_success(attempt).await?;
+
+    if let Err(e) = store.disable_totp(&session.account_id).await {
+        return Err(warp::reject::custom(Error::DatabaseQueryError(e)));
+    }
+
+    tracing::info!(account_id = %session.account_id, "two-factor authentication off");
+
+    Ok(warp::reply::json(&JsonResponse::new(
+        false,
+        Some("two-factor authentication off".to_string()),
+        None,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::{
+        auth::totp,
+        config::{Config, LoginThrottleConfig},
+        routes::testing::TestApp,
+    };
+
+    const PASSWORD: &str = "correct horse";
+
+    /// An app with alice registered, throttling logins as `configure` says.
+    async fn app_with(configure: impl FnOnce(&mut LoginThrottleConfig)) -> TestApp {
+        let mut config = Config::for_tests();
+        configure(&mut config.login_throttle);
+
+        let app = TestApp::with_config(config);
+        app.register("alice@example.com", PASSWORD).await;
+
+        app
+    }
+
+    /// No wait after a wrong code, so codes can be tried one after another.
+    async fn app() -> TestApp {
+        app_with(|config| {
+            config.base_delay_secs = 0;
+            config.max_delay_secs = 0;
+        })
+        .await
+    }
+
+    async fn log_in(app: &TestApp, email: &str) -> Value {
+        let (status, body) = app
+            .request(
+                "POST",
+                "/login",
+                None,
+                Some(json!({ "email": email, "password": PASSWORD })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        body["data"].clone()
+    }
+
+    fn bearer(data: &Value) -> String {
+        format!("Bearer {}", data["Token"]["access_token"].as_str().unwrap())
+    }
+
+    async fn finish_login(app: &TestApp, challenge: &Value, code: &str) -> (StatusCode, Value) {
+        app.request(
+            "POST",
+            "/login/totp",
+            None,
+            Some(json!({
+                "challenge_token": challenge["Challenge"]["challenge_token"],
+                "code": code,
+            })),
+        )
+        .await
+    }
+
+    async fn add_question(app: &TestApp, api_key: &str) -> StatusCode {
+        let (status, _) = app
+            .request_with_headers(
+                "POST",
+                "/questions",
+                &[("x-api-key", api_key)],
+                Some(json!({ "title": "title", "content": "content" })),
+            )
+            .await;
+
+        status
+    }
+
+    /// Turns two-factor authentication on for the account, returning the secret,
+    /// the code it was confirmed with and the recovery codes.
+    async fn enable_totp(app: &TestApp, authorization: &str) -> (String, String, Vec<String>) {
+        let (status, body) = app
+            .request("POST", "/account/totp", Some(authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let secret = body["data"]["TotpEnrollment"]["secret"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let (status, _) = app
+            .request(
+                "POST",
+                "/account/totp/confirm",
+                Some(authorization),
+                Some(json!({ "code": "000000" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let code = totp::current_code(&secret);
+        let (status, body) = app
+            .request(
+                "POST",
+                "/account/totp/confirm",
+                Some(authorization),
+                Some(json!({ "code": code })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let recovery_codes = serde_json::from_value(body["data"]["RecoveryCodes"].clone()).unwrap();
+
+        (secret, code, recovery_codes)
+    }
+
+    #[tokio::test]
+    async fn enrolling_moves_logins_to_a_challenge() {
+        let app = app().await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+
+        let (_, body) = app
+            .request(
+                "POST",
+                "/account/api-keys",
+                Some(&authorization),
+                Some(json!({ "name": "ci", "scopes": ["questions.write"] })),
+            )
+            .await;
+        let api_key = body["data"]["CreatedApiKey"]["key"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        assert_eq!(add_question(&app, &api_key).await, StatusCode::OK);
+
+        let (_, code, _) = enable_totp(&app, &authorization).await;
+
+        // Neither the session nor the key that were made with the password
+        // alone get past the second factor
+        let (status, _) = app
+            .request("GET", "/sessions", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(add_question(&app, &api_key).await, StatusCode::UNAUTHORIZED);
+
+        let challenge = log_in(&app, "alice@example.com").await;
+        assert!(challenge["Token"].is_null());
+
+        // The code that confirmed enrollment is spent
+        let (status, _) = finish_login(&app, &challenge, &code).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = finish_login(&app, &challenge, "123").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let app = app().await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+        let (_, _, recovery_codes) = enable_totp(&app, &authorization).await;
+
+        let challenge = log_in(&app, "alice@example.com").await;
+        let (status, body) = finish_login(&app, &challenge, &recovery_codes[0]).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let (status, _) = app
+            .request("GET", "/sessions", Some(&bearer(&body["data"])), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let challenge = log_in(&app, "alice@example.com").await;
+        let (status, _) = finish_login(&app, &challenge, &recovery_codes[0]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Typed differently, it's still the same code
+        let retyped = recovery_codes[1].replace('-', "").to_uppercase();
+        let (status, _) = finish_login(&app, &challenge, &retyped).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let challenge = log_in(&app, "alice@example.com").await;
+        let (status, _) = finish_login(&app, &challenge, &recovery_codes[1]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn makes_the_next_code_wait_after_a_wrong_one() {
+        let app = app_with(|config| {
+            config.base_delay_secs = 60;
+            config.max_delay_secs = 60;
+        })
+        .await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+        let (secret, _, _) = enable_totp(&app, &authorization).await;
+
+        let challenge = log_in(&app, "alice@example.com").await;
+        let (status, _) = finish_login(&app, &challenge, "000000").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, problem) = finish_login(&app, &challenge, &totp::current_code(&secret)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(problem["code"], "too_many_requests");
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_after_too_many_wrong_codes() {
+        let app = app_with(|config| {
+            config.base_delay_secs = 0;
+            config.max_delay_secs = 0;
+            config.account_max_failures = 3;
+        })
+        .await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+        let (secret, _, _) = enable_totp(&app, &authorization).await;
+
+        let pending = log_in(&app, "alice@example.com").await;
+
+        // The right password in between doesn't clear the wrong codes
+        for _ in 0..3 {
+            let challenge = log_in(&app, "alice@example.com").await;
+            let (status, _) = finish_login(&app, &challenge, "000000").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, problem) = finish_login(&app, &pending, &totp::current_code(&secret)).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(problem["code"], "account_locked");
+    }
+
+    /// Logs in with the password and the second factor.
+    async fn log_in_with_code(app: &TestApp, code: &str) -> String {
+        let challenge = log_in(app, "alice@example.com").await;
+        let (status, body) = finish_login(app, &challenge, code).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        bearer(&body["data"])
+    }
+
+    async fn disable(app: &TestApp, authorization: &str, code: &str) -> (StatusCode, Value) {
+        app.request(
+            "POST",
+            "/account/totp/disable",
+            Some(authorization),
+            Some(json!({ "code": code })),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn disabling_takes_a_code() {
+        let app = app().await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+        let (_, _, recovery_codes) = enable_totp(&app, &authorization).await;
+        let authorization = log_in_with_code(&app, &recovery_codes[0]).await;
+
+        let (status, _) = disable(&app, &authorization, "000000").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = disable(&app, &authorization, &recovery_codes[1]).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let data = log_in(&app, "alice@example.com").await;
+        assert!(data["Challenge"].is_null());
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_after_too_many_wrong_codes_to_disable() {
+        let app = app_with(|config| {
+            config.base_delay_secs = 0;
+            config.max_delay_secs = 0;
+            config.account_max_failures = 3;
+        })
+        .await;
+        let authorization = bearer(&log_in(&app, "alice@example.com").await);
+        let (_, _, recovery_codes) = enable_totp(&app, &authorization).await;
+        let authorization = log_in_with_code(&app, &recovery_codes[0]).await;
+
+        for _ in 0..3 {
+            let (status, _) = disable(&app, &authorization, "000000").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, problem) = disable(&app, &authorization, &recovery_codes[1]).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(problem["code"], "account_locked");
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{answer::Answer, question::Question};
+
+/// Questions and their answers kept in a JSON file shaped like the
+/// `questions.json` the repo ships: an object keyed by question id whose
+/// values are questions, each with an optional `answers` list.
+pub struct JsonFile {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    question: Question,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    answers: Vec<Answer>,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        JsonFile { path }
+    }
+
+    /// Everything in the file, or nothing if it doesn't exist yet. Refuses
+    /// a file whose keys don't match the ids inside it.
+    pub fn load(&self) -> Result<(Vec<Question>, Vec<Answer>), io::Error> {
+        let json = match fs::read(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], vec![])),
+            Err(e) => return Err(e),
+        };
+
+        let entries: BTreeMap<i32, Entry> = serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut questions = vec![];
+        let mut answers = vec![];
+
+        for (id, entry) in entries {
+            if entry.question.id.0 != id {
+                return Err(invalid(format!(
+                    "question {} is stored under id {}",
+                    entry.question.id, id
+                )));
+            }
+
+            if let Some(answer) = entry
+                .answers
+                .iter()
+                .find(|answer| answer.question_id.0 != id)
+            {
+                return Err(invalid(format!(
+                    "answer {} to question {} is stored under question {}",
+                    answer.id.0, answer.question_id, id
+                )));
+            }
+
+            questions.push(entry.question);
+            answers.extend(entry.answers);
+        }
+
+        Ok((questions, answers))
+    }
+
+    /// Replaces the file in one step: writes a temporary file next to it and
+    /// renames it over the old one, so a crash leaves either the old or the
+    /// new contents, never half of them.
+    pub fn save<'a>(
+        &self,
+        questions: impl Iterator<Item = &'a Question>,
+        answers: impl Iterator<Item = &'a Answer>,
+    ) -> Result<(), io::Error> {
+        let mut entries: BTreeMap<i32, Entry> = questions
+            .map(|question| {
+                (
+                    question.id.0,
+                    Entry {
+                        question: question.clone(),
+                        answers: vec![],
+                    },
+                )
+            })
+            .collect();
+
+        for answer in answers {
+            if let Some(entry) = entries.get_mut(&answer.question_id.0) {
+                entry.answers.push(answer.clone());
+            }
+        }
+
+        let json = serde_json::to_vec_pretty(&entries)?;
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::{config::Config, routes::testing::TestApp, store::MemoryStore};
+
+    fn app_on(path: &Path) -> TestApp {
+        TestApp {
+            config: Config::for_tests(),
+            store: Arc::new(MemoryStore::open(JsonFile::new(path.to_path_buf())).unwrap()),
+        }
+    }
+
+    fn tmp_path(path: &Path) -> PathBuf {
+        path.with_file_name("questions.json.tmp")
+    }
+
+    #[test]
+    fn loads_the_shipped_questions() {
+        let file = JsonFile::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("questions.json"));
+
+        let (questions, answers) = file.load().unwrap();
+
+        let titles: Vec<_> = questions
+            .iter()
+            .map(|question| (question.id.0, question.title.as_str()))
+            .collect();
+        assert_eq!(titles, [(1, "First question"), (2, "Second question")]);
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn starts_empty_without_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (questions, answers) = JsonFile::new(dir.path().join("questions.json"))
+            .load()
+            .unwrap();
+
+        assert!(questions.is_empty() && answers.is_empty());
+    }
+
+    #[test]
+    fn refuses_questions_under_the_wrong_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("questions.json");
+        let question = json!({ "id": 2, "title": "title", "content": "content" });
+        fs::write(&path, json!({ "1": question }).to_string()).unwrap();
+
+        let error = JsonFile::new(path).load().unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "question 2 is stored under id 1");
+    }
+
+    #[test]
+    fn saves_through_a_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("questions.json");
+        let shipped = JsonFile::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("questions.json"));
+        let (questions, answers) = shipped.load().unwrap();
+        let file = JsonFile::new(path.clone());
+
+        file.save(questions.iter(), answers.iter()).unwrap();
+
+        assert!(!tmp_path(&path).exists());
+        assert_eq!(file.load().unwrap().0.len(), 2);
+
+        // With the temporary file in the way, the old contents stay whole
+        fs::create_dir(tmp_path(&path)).unwrap();
+
+        assert!(file.save(questions[..1].iter(), answers.iter()).is_err());
+        assert_eq!(file.load().unwrap().0.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_questions_and_answers_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("questions.json");
+
+        let app = app_on(&path);
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        for title in ["first", "second"] {
+            app.request(
+                "POST",
+                "/questions",
+                Some(&authorization),
+                Some(json!({ "title": title, "content": "content" })),
+            )
+            .await;
+        }
+
+        for question_id in [1, 2, 1] {
+            let (status, _) = app
+                .request(
+                    "POST",
+                    "/answers",
+                    Some(&authorization),
+                    Some(json!({ "question_id": question_id, "content": "answer" })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        app.request(
+            "PUT",
+            "/questions/1",
+            Some(&authorization),
+            Some(json!({ "id": 1, "title": "first, edited", "content": "content" })),
+        )
+        .await;
+
+        drop(app);
+
+        // Answers are kept inside their questions
+        let saved: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let answer_ids = |key: &str| -> Vec<i64> {
+            saved[key]["answers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|answer| answer["id"].as_i64().unwrap())
+                .collect()
+        };
+        assert_eq!(answer_ids("1"), [1, 3]);
+        assert_eq!(answer_ids("2"), [2]);
+
+        let app = app_on(&path);
+
+        let (_, body) = app.request("GET", "/questions/1", None, None).await;
+        assert_eq!(body["data"]["Question"]["title"], "first, edited");
+        assert_eq!(
+            app.page_through("/questions?limit=1", "Questions").await,
+            [1, 2]
+        );
+        assert_eq!(
+            app.page_through("/questions/1/answers?limit=1", "Answers")
+                .await,
+            [1, 3]
+        );
+
+        // New rows continue after the stored ones
+        let (_, authorization) = app.user("bob@example.com").await;
+        let (_, body) = app
+            .request(
+                "POST",
+                "/questions",
+                Some(&authorization),
+                Some(json!({ "title": "third", "content": "content" })),
+            )
+            .await;
+        assert_eq!(body["data"]["Question"]["id"], 3);
+    }
+}
@@ -0,0 +1,1745 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    error::Error as StdError,
+    fmt::Display,
+    io,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::error::{DatabaseError, ErrorKind};
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
+
+use super::{JsonFile, Store, UnitOfWork};
+use crate::{
+    moderation::Flag,
+    types::{
+        answer::{Answer, AnswerId, NewAnswer},
+        api_key::{ApiKey, ApiKeyId, ApiScope},
+        pagination::{Cursor, Pagination},
+        question::{NewQuestion, Question, QuestionId},
+        role::{Permission, Role, RoleAudit},
+        session::{ActiveSession, LoginFailures},
+        user::{AccountId, LinkPurpose, NewUser, User},
+    },
+};
+
+/// What the roles migration sets up
+const ROLE_PERMISSIONS: &[(&str, &str)] = &[
+    ("moderator", "content.moderate"),
+    ("admin", "content.moderate"),
+    ("admin", "roles.manage"),
+    ("moderator", "questions.close"),
+    ("admin", "questions.close"),
+];
+
+/// Keeps everything in memory, behaving like the Postgres store down to its
+/// constraints. Starts out empty and forgets everything when dropped, unless
+/// opened on a JSON file, which then holds the questions and answers across
+/// restarts. Accounts, sessions and everything else still live only in
+/// memory, so users register again after a restart.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<Data>>,
+    file: Option<Arc<JsonFile>>,
+}
+
+#[derive(Default)]
+struct Data {
+    last_ids: HashMap<&'static str, i32>,
+    questions: BTreeMap<i32, Question>,
+    answers: BTreeMap<i32, Answer>,
+    review_queue: Vec<ReviewItem>,
+    users: BTreeMap<i32, UserRow>,
+    user_roles: Vec<UserRole>,
+    role_audit_log: Vec<RoleAudit>,
+    sessions: BTreeMap<i32, SessionRow>,
+    link_tokens: Vec<LinkToken>,
+    oidc_logins: HashMap<String, OidcLogin>,
+    identities: Vec<Identity>,
+    recovery_codes: Vec<RecoveryCode>,
+    login_challenges: HashMap<String, LoginChallenge>,
+    login_failures: HashMap<String, LoginFailures>,
+    api_keys: BTreeMap<i32, ApiKeyRow>,
+}
+
+// Rows are kept whole, like the tables they stand in for, even where
+// nothing reads a column back yet
+#[allow(dead_code)]
+struct ReviewItem {
+    item_type: String,
+    item_id: i32,
+    field: &'static str,
+    matched_words: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+struct UserRow {
+    user: User,
+    totp_secret: Option<String>,
+    totp_last_step: Option<i64>,
+}
+
+struct UserRole {
+    account_id: i32,
+    role: String,
+    granted_by: Option<AccountId>,
+    granted_at: DateTime<Utc>,
+}
+
+struct SessionRow {
+    account_id: AccountId,
+    refresh_token_hash: String,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+    mfa: bool,
+}
+
+struct LinkToken {
+    purpose: LinkPurpose,
+    token_hash: String,
+    account_id: AccountId,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+struct OidcLogin {
+    pkce_verifier: String,
+    nonce: String,
+    expires_at: DateTime<Utc>,
+}
+
+struct Identity {
+    issuer: String,
+    subject: String,
+    account_id: AccountId,
+    last_login_at: DateTime<Utc>,
+}
+
+struct RecoveryCode {
+    account_id: AccountId,
+    code_hash: String,
+    used_at: Option<DateTime<Utc>>,
+}
+
+struct LoginChallenge {
+    account_id: AccountId,
+    user_agent: Option<String>,
+    expires_at: DateTime<Utc>,
+    failed_attempts: i32,
+    used_at: Option<DateTime<Utc>>,
+}
+
+struct ApiKeyRow {
+    api_key: ApiKey,
+    account_id: AccountId,
+    key_hash: String,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// Starts out with what's in `file` and writes every change to questions
+    /// and answers back to it.
+    pub fn open(file: JsonFile) -> Result<Self, io::Error> {
+        let (questions, answers) = file.load()?;
+        let mut data = Data::default();
+
+        // New ids continue after the stored ones. Accounts aren't stored, so
+        // new ones skip past every stored author instead of inheriting their
+        // questions and answers.
+        let last_question_id = questions.iter().map(|question| question.id.0).max();
+        let last_answer_id = answers.iter().map(|answer| answer.id.0).max();
+        let last_account_id = questions
+            .iter()
+            .filter_map(|question| question.account_id.as_ref())
+            .chain(
+                answers
+                    .iter()
+                    .filter_map(|answer| answer.account_id.as_ref()),
+            )
+            .map(|account_id| account_id.0)
+            .max();
+
+        data.last_ids
+            .insert("questions", last_question_id.unwrap_or_default());
+        data.last_ids
+            .insert("answers", last_answer_id.unwrap_or_default());
+        data.last_ids
+            .insert("users", last_account_id.unwrap_or_default());
+        data.questions = questions
+            .into_iter()
+            .map(|question| (question.id.0, question))
+            .collect();
+        data.answers = answers
+            .into_iter()
+            .map(|answer| (answer.id.0, answer))
+            .collect();
+
+        Ok(MemoryStore {
+            data: Arc::new(Mutex::new(data)),
+            file: Some(Arc::new(file)),
+        })
+    }
+
+    async fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().await
+    }
+
+    /// Applies `change` to questions and answers and saves them to the file,
+    /// if there is one. Undoes the change if it can't be saved, so what's
+    /// served never gets ahead of what's stored.
+    async fn change_content<T>(
+        &self,
+        change: impl FnOnce(&mut Data) -> Result<T, sqlx::Error>,
+    ) -> Result<T, sqlx::Error> {
+        let mut data = self.data().await;
+
+        let file = match &self.file {
+            Some(file) => file,
+            None => return change(&mut data),
+        };
+
+        let questions = data.questions.clone();
+        let answers = data.answers.clone();
+        let result = change(&mut data)?;
+
+        if let Err(e) = file.save(data.questions.values(), data.answers.values()) {
+            tracing::error!(error = %e, "could not save questions and answers");
+
+            data.questions = questions;
+            data.answers = answers;
+
+            return Err(sqlx::Error::Io(e));
+        }
+
+        Ok(result)
+    }
+}
+
+impl Data {
+    /// Like a `serial` column.
+    fn next_id(&mut self, table: &'static str) -> i32 {
+        let id = self.last_ids.entry(table).or_insert(0);
+        *id += 1;
+        *id
+    }
+
+    fn user(&self, account_id: &AccountId) -> Result<&UserRow, sqlx::Error> {
+        self.users
+            .get(&account_id.0)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    fn user_mut(&mut self, account_id: &AccountId) -> Result<&mut UserRow, sqlx::Error> {
+        self.users
+            .get_mut(&account_id.0)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    fn require_user(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        match self.users.contains_key(&account_id.0) {
+            true => Ok(()),
+            false => Err(violation(
+                Constraint::ForeignKey,
+                format!("account {} does not exist", account_id),
+            )),
+        }
+    }
+
+    fn login_failures(&self, keys: &[String], since: DateTime<Utc>) -> Vec<LoginFailures> {
+        let now = Utc::now();
+
+        keys.iter()
+            .filter_map(|key| self.login_failures.get(key))
+            .filter(|entry| {
+                entry.last_failure_at > since || entry.locked_until.is_some_and(|until| until > now)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn add_login_failure(&mut self, key: &str, since: DateTime<Utc>) -> i32 {
+        let now = Utc::now();
+
+        self.login_failures.retain(|other, entry| {
+            other == key
+                || entry.last_failure_at > since
+                || entry.locked_until.is_some_and(|until| until > now)
+        });
+
+        let entry = self
+            .login_failures
+            .entry(key.to_string())
+            .or_insert_with(|| LoginFailures {
+                key: key.to_string(),
+                failures: 0,
+                last_failure_at: now,
+                locked_until: None,
+            });
+
+        entry.failures = if entry.last_failure_at > since {
+            entry.failures + 1
+        } else {
+            1
+        };
+        entry.last_failure_at = now;
+
+        entry.failures
+    }
+
+    fn add_user(
+        &mut self,
+        email: &str,
+        password: Option<String>,
+        email_verified_at: Option<DateTime<Utc>>,
+    ) -> Result<User, sqlx::Error> {
+        if self.users.values().any(|row| row.user.email == email) {
+            return Err(violation(
+                Constraint::Unique,
+                "email already exists".to_string(),
+            ));
+        }
+
+        let user = User {
+            id: AccountId(self.next_id("users")),
+            email: email.to_string(),
+            password,
+            created_at: Utc::now(),
+            email_verified_at,
+            totp_enabled_at: None,
+        };
+
+        self.users.insert(
+            user.id.0,
+            UserRow {
+                user: user.clone(),
+                totp_secret: None,
+                totp_last_step: None,
+            },
+        );
+
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn count_questions(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(data
+            .questions
+            .values()
+            .filter(|question| created_between(question.created_at, since, until))
+            .count() as i64)
+    }
+
+    async fn get_questions(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Question, Cursor)>, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(list_page(
+            data.questions.values().map(|question| {
+                (
+                    question.clone(),
+                    question.id.0,
+                    question.created_at,
+                    question.updated_at,
+                )
+            }),
+            pagination,
+        ))
+    }
+
+    async fn get_question_by_id(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        self.data()
+            .await
+            .questions
+            .get(&question_id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn close_question(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        self.change_content(|data| {
+            let question = data
+                .questions
+                .get_mut(&question_id)
+                .ok_or(sqlx::Error::RowNotFound)?;
+
+            question.closed_at.get_or_insert_with(Utc::now);
+
+            Ok(question.clone())
+        })
+        .await
+    }
+
+    async fn count_answers(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(data
+            .answers
+            .values()
+            .filter(|answer| created_between(answer.created_at, since, until))
+            .count() as i64)
+    }
+
+    async fn get_answers(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(list_page(
+            data.answers.values().map(|answer| {
+                (
+                    answer.clone(),
+                    answer.id.0,
+                    answer.created_at,
+                    answer.updated_at,
+                )
+            }),
+            pagination,
+        ))
+    }
+
+    async fn get_answer_by_id(&self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        self.data()
+            .await
+            .answers
+            .get(&answer_id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn count_answers_for_question(
+        &self,
+        question_id: i32,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(data
+            .answers
+            .values()
+            .filter(|answer| answer.question_id.0 == question_id)
+            .filter(|answer| created_between(answer.created_at, since, until))
+            .count() as i64)
+    }
+
+    async fn get_answers_for_question(
+        &self,
+        question_id: i32,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(list_page(
+            data.answers
+                .values()
+                .filter(|answer| answer.question_id.0 == question_id)
+                .map(|answer| {
+                    (
+                        answer.clone(),
+                        answer.id.0,
+                        answer.created_at,
+                        answer.updated_at,
+                    )
+                }),
+            pagination,
+        ))
+    }
+
+    async fn add_user(&self, new_user: NewUser) -> Result<User, sqlx::Error> {
+        self.data()
+            .await
+            .add_user(&new_user.email, Some(new_user.password), None)
+    }
+
+    async fn has_permission(
+        &self,
+        account_id: &AccountId,
+        permission: Permission,
+    ) -> Result<bool, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(data
+            .user_roles
+            .iter()
+            .filter(|user_role| user_role.account_id == account_id.0)
+            .any(|user_role| {
+                ROLE_PERMISSIONS.contains(&(user_role.role.as_str(), permission.as_str()))
+            }))
+    }
+
+    async fn get_roles(&self, account_id: i32) -> Result<Vec<Role>, sqlx::Error> {
+        let data = self.data().await;
+
+        let mut roles: Vec<Role> = data
+            .user_roles
+            .iter()
+            .filter(|user_role| user_role.account_id == account_id)
+            .map(|user_role| Role {
+                name: user_role.role.clone(),
+                granted_by: user_role.granted_by.clone(),
+                granted_at: user_role.granted_at,
+            })
+            .collect();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(roles)
+    }
+
+    async fn grant_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+
+        if data
+            .user_roles
+            .iter()
+            .any(|user_role| user_role.account_id == account_id && user_role.role == role)
+        {
+            return Ok(());
+        }
+
+        data.require_user(&AccountId(account_id))?;
+
+        if !ROLE_PERMISSIONS.iter().any(|(name, _)| *name == role) {
+            return Err(violation(
+                Constraint::ForeignKey,
+                format!("role {} does not exist", role),
+            ));
+        }
+
+        let now = Utc::now();
+        data.user_roles.push(UserRole {
+            account_id,
+            role: role.to_string(),
+            granted_by: Some(actor.clone()),
+            granted_at: now,
+        });
+        let id = data.next_id("role_audit_log");
+        data.role_audit_log.push(RoleAudit {
+            id,
+            actor_id: Some(actor.clone()),
+            account_id: AccountId(account_id),
+            role: role.to_string(),
+            action: "grant".to_string(),
+            created_at: now,
+        });
+
+        Ok(())
+    }
+
+    async fn revoke_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let held = data.user_roles.len();
+
+        data.user_roles
+            .retain(|user_role| !(user_role.account_id == account_id && user_role.role == role));
+
+        if data.user_roles.len() == held {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let id = data.next_id("role_audit_log");
+        data.role_audit_log.push(RoleAudit {
+            id,
+            actor_id: Some(actor.clone()),
+            account_id: AccountId(account_id),
+            role: role.to_string(),
+            action: "revoke".to_string(),
+            created_at: Utc::now(),
+        });
+
+        Ok(())
+    }
+
+    async fn count_role_audit_log(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(data
+            .role_audit_log
+            .iter()
+            .filter(|entry| created_between(Some(entry.created_at), since, until))
+            .count() as i64)
+    }
+
+    async fn get_role_audit_log(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(RoleAudit, Cursor)>, sqlx::Error> {
+        let data = self.data().await;
+
+        Ok(list_page(
+            data.role_audit_log.iter().map(|entry| {
+                (
+                    entry.clone(),
+                    entry.id,
+                    Some(entry.created_at),
+                    Some(entry.created_at),
+                )
+            }),
+            pagination,
+        ))
+    }
+
+    async fn add_session(
+        &self,
+        account_id: &AccountId,
+        refresh_token_hash: &str,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+        mfa: bool,
+    ) -> Result<i32, sqlx::Error> {
+        let mut data = self.data().await;
+        data.require_user(account_id)?;
+
+        if data
+            .sessions
+            .values()
+            .any(|session| session.refresh_token_hash == refresh_token_hash)
+        {
+            return Err(violation(
+                Constraint::Unique,
+                "refresh token already exists".to_string(),
+            ));
+        }
+
+        let id = data.next_id("sessions");
+        let now = Utc::now();
+        data.sessions.insert(
+            id,
+            SessionRow {
+                account_id: account_id.clone(),
+                refresh_token_hash: refresh_token_hash.to_string(),
+                user_agent,
+                created_at: now,
+                last_used_at: now,
+                expires_at,
+                revoked_at: None,
+                mfa,
+            },
+        );
+
+        Ok(id)
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(i32, AccountId, bool), sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+
+        let (id, session) = data
+            .sessions
+            .iter_mut()
+            .find(|(_, session)| {
+                session.refresh_token_hash == refresh_token_hash
+                    && session.revoked_at.is_none()
+                    && session.expires_at > now
+            })
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        session.refresh_token_hash = new_refresh_token_hash.to_string();
+        session.expires_at = expires_at;
+        session.last_used_at = now;
+
+        Ok((*id, session.account_id.clone(), session.mfa))
+    }
+
+    async fn is_session_active(&self, session_id: i32) -> Result<bool, sqlx::Error> {
+        Ok(self
+            .data()
+            .await
+            .sessions
+            .get(&session_id)
+            .is_some_and(|session| session.revoked_at.is_none() && session.expires_at > Utc::now()))
+    }
+
+    async fn get_active_sessions(
+        &self,
+        account_id: &AccountId,
+        current_session_id: i32,
+    ) -> Result<Vec<ActiveSession>, sqlx::Error> {
+        let data = self.data().await;
+        let now = Utc::now();
+
+        let mut sessions: Vec<ActiveSession> = data
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                session.account_id == *account_id
+                    && session.revoked_at.is_none()
+                    && session.expires_at > now
+            })
+            .map(|(id, session)| ActiveSession {
+                id: *id,
+                user_agent: session.user_agent.clone(),
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+                expires_at: session.expires_at,
+                current: *id == current_session_id,
+            })
+            .collect();
+        sessions.sort_by_key(|session| Reverse(session.last_used_at));
+
+        Ok(sessions)
+    }
+
+    async fn revoke_sessions(&self, account_id: &AccountId) -> Result<Vec<i32>, sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+
+        Ok(data
+            .sessions
+            .iter_mut()
+            .filter(|(_, session)| {
+                session.account_id == *account_id && session.revoked_at.is_none()
+            })
+            .map(|(id, session)| {
+                session.revoked_at = Some(now);
+                *id
+            })
+            .collect())
+    }
+
+    async fn revoke_session(&self, session_id: i32) -> Result<(), sqlx::Error> {
+        if let Some(session) = self.data().await.sessions.get_mut(&session_id) {
+            session.revoked_at.get_or_insert_with(Utc::now);
+        }
+
+        Ok(())
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<User, sqlx::Error> {
+        self.data()
+            .await
+            .users
+            .values()
+            .find(|row| row.user.email == email)
+            .map(|row| row.user.clone())
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn get_user(&self, account_id: &AccountId) -> Result<User, sqlx::Error> {
+        Ok(self.data().await.user(account_id)?.user.clone())
+    }
+
+    async fn add_link_token(
+        &self,
+        purpose: LinkPurpose,
+        account_id: &AccountId,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        data.require_user(account_id)?;
+
+        if data
+            .link_tokens
+            .iter()
+            .any(|token| token.purpose == purpose && token.token_hash == token_hash)
+        {
+            return Err(violation(
+                Constraint::Unique,
+                "link token already exists".to_string(),
+            ));
+        }
+
+        data.link_tokens.push(LinkToken {
+            purpose,
+            token_hash: token_hash.to_string(),
+            account_id: account_id.clone(),
+            expires_at,
+            used_at: None,
+        });
+
+        Ok(())
+    }
+
+    async fn redeem_link_token(
+        &self,
+        purpose: LinkPurpose,
+        token_hash: &str,
+    ) -> Result<AccountId, sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+
+        let account_id = data
+            .link_tokens
+            .iter()
+            .find(|token| {
+                token.purpose == purpose
+                    && token.token_hash == token_hash
+                    && token.used_at.is_none()
+                    && token.expires_at > now
+            })
+            .map(|token| token.account_id.clone())
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        for token in data.link_tokens.iter_mut().filter(|token| {
+            token.purpose == purpose && token.account_id == account_id && token.used_at.is_none()
+        }) {
+            token.used_at = Some(now);
+        }
+
+        Ok(account_id)
+    }
+
+    async fn set_email_verified(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let user = &mut data.user_mut(account_id)?.user;
+
+        user.email_verified_at.get_or_insert_with(Utc::now);
+
+        Ok(())
+    }
+
+    async fn update_password(
+        &self,
+        account_id: &AccountId,
+        password_hash: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let user = &mut data.user_mut(account_id)?.user;
+
+        user.password = Some(password_hash.to_string());
+        user.email_verified_at.get_or_insert_with(Utc::now);
+
+        Ok(())
+    }
+
+    async fn add_oidc_login(
+        &self,
+        state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+
+        data.oidc_logins.retain(|_, login| login.expires_at > now);
+
+        if data.oidc_logins.contains_key(state) {
+            return Err(violation(
+                Constraint::Unique,
+                "login state already exists".to_string(),
+            ));
+        }
+
+        data.oidc_logins.insert(
+            state.to_string(),
+            OidcLogin {
+                pkce_verifier: pkce_verifier.to_string(),
+                nonce: nonce.to_string(),
+                expires_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn take_oidc_login(&self, state: &str) -> Result<(String, String), sqlx::Error> {
+        let mut data = self.data().await;
+
+        match data.oidc_logins.remove(state) {
+            Some(login) if login.expires_at > Utc::now() => Ok((login.pkce_verifier, login.nonce)),
+            _ => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, sqlx::Error> {
+        let mut data = self.data().await;
+
+        let identity = data
+            .identities
+            .iter_mut()
+            .find(|identity| identity.issuer == issuer && identity.subject == subject)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        identity.last_login_at = Utc::now();
+
+        let account_id = identity.account_id.clone();
+
+        Ok(data.user(&account_id)?.user.clone())
+    }
+
+    async fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        account_id: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        data.require_user(account_id)?;
+
+        if data
+            .identities
+            .iter()
+            .any(|identity| identity.issuer == issuer && identity.subject == subject)
+        {
+            return Err(violation(
+                Constraint::Unique,
+                "identity already linked".to_string(),
+            ));
+        }
+
+        data.identities.push(Identity {
+            issuer: issuer.to_string(),
+            subject: subject.to_string(),
+            account_id: account_id.clone(),
+            last_login_at: Utc::now(),
+        });
+
+        Ok(())
+    }
+
+    async fn add_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<User, sqlx::Error> {
+        let mut data = self.data().await;
+
+        if data
+            .identities
+            .iter()
+            .any(|identity| identity.issuer == issuer && identity.subject == subject)
+        {
+            return Err(violation(
+                Constraint::Unique,
+                "identity already linked".to_string(),
+            ));
+        }
+
+        let user = data.add_user(email, None, email_verified.then(Utc::now))?;
+
+        data.identities.push(Identity {
+            issuer: issuer.to_string(),
+            subject: subject.to_string(),
+            account_id: user.id.clone(),
+            last_login_at: Utc::now(),
+        });
+
+        Ok(user)
+    }
+
+    async fn get_totp_secret(&self, account_id: &AccountId) -> Result<Option<String>, sqlx::Error> {
+        Ok(self.data().await.user(account_id)?.totp_secret.clone())
+    }
+
+    async fn set_totp_secret(
+        &self,
+        account_id: &AccountId,
+        secret: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let row = data.user_mut(account_id)?;
+
+        if row.user.totp_enabled_at.is_some() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        row.totp_secret = Some(secret.to_string());
+        row.totp_last_step = None;
+
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        account_id: &AccountId,
+        step: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        let row = data.user_mut(account_id)?;
+
+        if row.totp_secret.is_none() || row.user.totp_enabled_at.is_some() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        row.user.totp_enabled_at = Some(Utc::now());
+        row.totp_last_step = Some(step);
+
+        data.recovery_codes
+            .retain(|code| code.account_id != *account_id);
+        data.recovery_codes
+            .extend(code_hashes.iter().map(|code_hash| RecoveryCode {
+                account_id: account_id.clone(),
+                code_hash: code_hash.clone(),
+                used_at: None,
+            }));
+
+        Ok(())
+    }
+
+    async fn disable_totp(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+
+        if let Ok(row) = data.user_mut(account_id) {
+            row.totp_secret = None;
+            row.user.totp_enabled_at = None;
+            row.totp_last_step = None;
+
+            data.recovery_codes
+                .retain(|code| code.account_id != *account_id);
+        }
+
+        Ok(())
+    }
+
+    async fn use_totp_step(&self, account_id: &AccountId, step: i64) -> Result<bool, sqlx::Error> {
+        let mut data = self.data().await;
+
+        match data.user_mut(account_id) {
+            Ok(row) if row.totp_last_step.is_none_or(|last| last < step) => {
+                row.totp_last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn use_recovery_code(
+        &self,
+        account_id: &AccountId,
+        code_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let mut data = self.data().await;
+
+        match data.recovery_codes.iter_mut().find(|code| {
+            code.account_id == *account_id && code.code_hash == code_hash && code.used_at.is_none()
+        }) {
+            Some(code) => {
+                code.used_at = Some(Utc::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn add_login_challenge(
+        &self,
+        token_hash: &str,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut data = self.data().await;
+        data.require_user(account_id)?;
+
+        if data.login_challenges.contains_key(token_hash) {
+            return Err(violation(
+                Constraint::Unique,
+                "login challenge already exists".to_string(),
+            ));
+        }
+
+        data.login_challenges.insert(
+            token_hash.to_string(),
+            LoginChallenge {
+                account_id: account_id.clone(),
+                user_agent,
+                expires_at,
+                failed_attempts: 0,
+                used_at: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get_login_challenge(
+        &self,
+        token_hash: &str,
+        max_failed_attempts: i32,
+    ) -> Result<(AccountId, Option<String>), sqlx::Error> {
+        match self.data().await.login_challenges.get(token_hash) {
+            Some(challenge)
+                if challenge.used_at.is_none()
+                    && challenge.expires_at > Utc::now()
+                    && challenge.failed_attempts < max_failed_attempts =>
+            {
+                Ok((challenge.account_id.clone(), challenge.user_agent.clone()))
+            }
+            _ => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    async fn fail_login_challenge(&self, token_hash: &str) -> Result<(), sqlx::Error> {
+        if let Some(challenge) = self.data().await.login_challenges.get_mut(token_hash) {
+            challenge.failed_attempts += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn complete_login_challenge(&self, token_hash: &str) -> Result<bool, sqlx::Error> {
+        match self.data().await.login_challenges.get_mut(token_hash) {
+            Some(challenge) if challenge.used_at.is_none() => {
+                challenge.used_at = Some(Utc::now());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_login_failure(&self, key: &str) -> Result<(), sqlx::Error> {
+        if let Some(entry) = self.data().await.login_failures.get_mut(key) {
+            entry.failures = (entry.failures - 1).max(0);
+        }
+
+        Ok(())
+    }
+
+    async fn lock_login(&self, key: &str, until: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        if let Some(entry) = self.data().await.login_failures.get_mut(key) {
+            entry.failures = 0;
+            entry.locked_until = Some(until);
+        }
+
+        Ok(())
+    }
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), sqlx::Error> {
+        self.data().await.login_failures.remove(key);
+
+        Ok(())
+    }
+
+    async fn add_api_key(
+        &self,
+        account_id: &AccountId,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey, sqlx::Error> {
+        let mut data = self.data().await;
+        data.require_user(account_id)?;
+
+        if data.api_keys.values().any(|row| row.key_hash == key_hash) {
+            return Err(violation(
+                Constraint::Unique,
+                "api key already exists".to_string(),
+            ));
+        }
+
+        let api_key = ApiKey {
+            id: ApiKeyId(data.next_id("api_keys")),
+            name: name.to_string(),
+            prefix: prefix.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: Utc::now(),
+            last_used_at: None,
+            expires_at,
+        };
+
+        data.api_keys.insert(
+            api_key.id.0,
+            ApiKeyRow {
+                api_key: api_key.clone(),
+                account_id: account_id.clone(),
+                key_hash: key_hash.to_string(),
+                revoked_at: None,
+            },
+        );
+
+        Ok(api_key)
+    }
+
+    async fn get_api_keys(&self, account_id: &AccountId) -> Result<Vec<ApiKey>, sqlx::Error> {
+        Ok(self
+            .data()
+            .await
+            .api_keys
+            .values()
+            .filter(|row| row.account_id == *account_id && row.revoked_at.is_none())
+            .map(|row| row.api_key.clone())
+            .collect())
+    }
+
+    async fn revoke_api_key(
+        &self,
+        account_id: &AccountId,
+        api_key_id: ApiKeyId,
+    ) -> Result<(), sqlx::Error> {
+        match self.data().await.api_keys.get_mut(&api_key_id.0) {
+            Some(row) if row.account_id == *account_id && row.revoked_at.is_none() => {
+                row.revoked_at = Some(Utc::now());
+                Ok(())
+            }
+            _ => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    async fn revoke_api_keys(&self, account_id: &AccountId) -> Result<u64, sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+        let mut revoked = 0;
+
+        for row in data
+            .api_keys
+            .values_mut()
+            .filter(|row| row.account_id == *account_id && row.revoked_at.is_none())
+        {
+            row.revoked_at = Some(now);
+            revoked += 1;
+        }
+
+        Ok(revoked)
+    }
+
+    async fn use_api_key(
+        &self,
+        key_hash: &str,
+    ) -> Result<(ApiKeyId, AccountId, Vec<ApiScope>), sqlx::Error> {
+        let mut data = self.data().await;
+        let now = Utc::now();
+
+        let row = data
+            .api_keys
+            .values_mut()
+            .find(|row| {
+                row.key_hash == key_hash
+                    && row.revoked_at.is_none()
+                    && row
+                        .api_key
+                        .expires_at
+                        .is_none_or(|expires_at| expires_at > now)
+            })
+            .ok_or(sqlx::Error::RowNotFound)?;
+        row.api_key.last_used_at = Some(now);
+
+        Ok((
+            row.api_key.id,
+            row.account_id.clone(),
+            row.api_key.scopes.clone(),
+        ))
+    }
+
+    async fn begin(&self) -> Result<Box<dyn UnitOfWork>, sqlx::Error> {
+        let data = self.data.clone().lock_owned().await;
+
+        let undo = Undo {
+            questions: data.questions.clone(),
+            answers: data.answers.clone(),
+            review_queue_len: data.review_queue.len(),
+            login_failures: data.login_failures.clone(),
+        };
+
+        Ok(Box::new(MemoryUnitOfWork {
+            data,
+            file: self.file.clone(),
+            unsaved: false,
+            undo: Some(undo),
+        }))
+    }
+}
+
+/// Holds the store's lock from `begin` until it ends, so nothing else sees
+/// or changes anything halfway through. Unless committed, puts questions,
+/// answers, the review queue and login failures back as they were when
+/// dropped.
+struct MemoryUnitOfWork {
+    data: OwnedMutexGuard<Data>,
+    file: Option<Arc<JsonFile>>,
+    /// Whether questions or answers changed, so the file needs saving
+    unsaved: bool,
+    undo: Option<Undo>,
+}
+
+/// What a unit of work may change, as it was before it started. Ids aren't
+/// given back, just as a Postgres sequence doesn't roll back.
+struct Undo {
+    questions: BTreeMap<i32, Question>,
+    answers: BTreeMap<i32, Answer>,
+    review_queue_len: usize,
+    login_failures: HashMap<String, LoginFailures>,
+}
+
+impl Drop for MemoryUnitOfWork {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            self.data.questions = undo.questions;
+            self.data.answers = undo.answers;
+            self.data.review_queue.truncate(undo.review_queue_len);
+            self.data.login_failures = undo.login_failures;
+        }
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for MemoryUnitOfWork {
+    async fn get_question_by_id(&mut self, question_id: i32) -> Result<Question, sqlx::Error> {
+        self.data
+            .questions
+            .get(&question_id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn add_question(
+        &mut self,
+        new_question: NewQuestion,
+        account_id: &AccountId,
+    ) -> Result<Question, sqlx::Error> {
+        let data = &mut *self.data;
+
+        data.require_user(account_id)?;
+
+        let now = Utc::now();
+        let question = Question {
+            id: QuestionId(data.next_id("questions")),
+            title: new_question.title,
+            content: new_question.content,
+            tags: new_question.tags,
+            account_id: Some(account_id.clone()),
+            created_at: Some(now),
+            updated_at: Some(now),
+            closed_at: None,
+        };
+
+        data.questions.insert(question.id.0, question.clone());
+        self.unsaved = true;
+
+        Ok(question)
+    }
+
+    async fn update_question(
+        &mut self,
+        question: Question,
+        question_id: i32,
+    ) -> Result<Question, sqlx::Error> {
+        let stored = self
+            .data
+            .questions
+            .get_mut(&question_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        stored.title = question.title;
+        stored.content = question.content;
+        stored.tags = question.tags;
+        stored.updated_at = Some(Utc::now());
+        let updated = stored.clone();
+        self.unsaved = true;
+
+        Ok(updated)
+    }
+
+    async fn delete_question(&mut self, question_id: i32) -> Result<(), sqlx::Error> {
+        let data = &mut *self.data;
+
+        if !data.questions.contains_key(&question_id) {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        if data
+            .answers
+            .values()
+            .any(|answer| answer.question_id.0 == question_id)
+        {
+            return Err(violation(
+                Constraint::ForeignKey,
+                format!("question {} has answers", question_id),
+            ));
+        }
+
+        data.questions.remove(&question_id);
+        self.unsaved = true;
+
+        Ok(())
+    }
+
+    async fn get_answer_by_id(&mut self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        self.data
+            .answers
+            .get(&answer_id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn add_answer(
+        &mut self,
+        question_id: i32,
+        answer: NewAnswer,
+        account_id: &AccountId,
+    ) -> Result<Answer, sqlx::Error> {
+        let data = &mut *self.data;
+
+        data.require_user(account_id)?;
+
+        if !data.questions.contains_key(&question_id) {
+            return Err(violation(
+                Constraint::ForeignKey,
+                format!("question {} does not exist", question_id),
+            ));
+        }
+
+        let now = Utc::now();
+        let answer = Answer {
+            id: AnswerId(data.next_id("answers")),
+            content: answer.content,
+            question_id: QuestionId(question_id),
+            account_id: Some(account_id.clone()),
+            created_at: Some(now),
+            updated_at: Some(now),
+        };
+
+        data.answers.insert(answer.id.0, answer.clone());
+        self.unsaved = true;
+
+        Ok(answer)
+    }
+
+    async fn update_answer(
+        &mut self,
+        answer: Answer,
+        answer_id: i32,
+    ) -> Result<Answer, sqlx::Error> {
+        let stored = self
+            .data
+            .answers
+            .get_mut(&answer_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        stored.content = answer.content;
+        stored.updated_at = Some(Utc::now());
+        let updated = stored.clone();
+        self.unsaved = true;
+
+        Ok(updated)
+    }
+
+    async fn delete_answer(&mut self, answer_id: i32) -> Result<(), sqlx::Error> {
+        match self.data.answers.remove(&answer_id) {
+            Some(_) => {
+                self.unsaved = true;
+                Ok(())
+            }
+            None => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    async fn queue_for_review(
+        &mut self,
+        item_type: &str,
+        item_id: i32,
+        flags: Vec<Flag>,
+    ) -> Result<(), sqlx::Error> {
+        for flag in flags {
+            self.data.review_queue.push(ReviewItem {
+                item_type: item_type.to_string(),
+                item_id,
+                field: flag.field.as_str(),
+                matched_words: flag.matched_words,
+                created_at: Utc::now(),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn get_login_failures(
+        &mut self,
+        keys: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<LoginFailures>, sqlx::Error> {
+        Ok(self.data.login_failures(keys, since))
+    }
+
+    async fn add_login_failure(
+        &mut self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<i32, sqlx::Error> {
+        Ok(self.data.add_login_failure(key, since))
+    }
+
+    /// Saves questions and answers to the file, if there is one and they
+    /// changed, and rolls back if that fails.
+    async fn commit(mut self: Box<Self>) -> Result<(), sqlx::Error> {
+        if let Some(file) = self.file.as_ref().filter(|_| self.unsaved) {
+            if let Err(e) = file.save(self.data.questions.values(), self.data.answers.values()) {
+                tracing::error!(error = %e, "could not save questions and answers");
+
+                return Err(sqlx::Error::Io(e));
+            }
+        }
+
+        self.undo = None;
+
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error> {
+        // dropping it puts everything back
+        Ok(())
+    }
+}
+
+fn created_between(
+    created_at: Option<DateTime<Utc>>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> bool {
+    let created_at = created_at.unwrap_or_default();
+
+    since.is_none_or(|since| created_at >= since) && until.is_none_or(|until| created_at < until)
+}
+
+/// A page of `(item, id, created_at, updated_at)` rows, the way
+/// `list_query` selects one in Postgres.
+fn list_page<T>(
+    rows: impl Iterator<Item = (T, i32, Option<DateTime<Utc>>, Option<DateTime<Utc>>)>,
+    pagination: &Pagination,
+) -> Vec<(T, Cursor)> {
+    let sort = pagination.sort;
+
+    let mut rows: Vec<(T, Cursor)> = rows
+        .filter(|(_, _, created_at, _)| {
+            created_between(*created_at, pagination.since, pagination.until)
+        })
+        .map(|(item, id, created_at, updated_at)| {
+            (item, Cursor::new(sort, id, created_at, updated_at))
+        })
+        .filter(|(_, cursor)| match pagination.after {
+            Some(after) if sort.is_descending() => (cursor.at, cursor.id) < (after.at, after.id),
+            Some(after) => (cursor.at, cursor.id) > (after.at, after.id),
+            None => true,
+        })
+        .collect();
+
+    rows.sort_by_key(|(_, cursor)| (cursor.at, cursor.id));
+
+    if sort.is_descending() {
+        rows.reverse();
+    }
+
+    rows.into_iter()
+        .skip(pagination.offset as usize)
+        .take(pagination.fetch_limit() as usize)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Constraint {
+    Unique,
+    ForeignKey,
+}
+
+/// A violated constraint, reported like the database would.
+#[derive(Debug)]
+struct ConstraintViolation {
+    constraint: Constraint,
+    message: String,
+}
+
+fn violation(constraint: Constraint, message: String) -> sqlx::Error {
+    sqlx::Error::Database(Box::new(ConstraintViolation {
+        constraint,
+        message,
+    }))
+}
+
+impl Display for ConstraintViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl StdError for ConstraintViolation {}
+
+impl DatabaseError for ConstraintViolation {
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self.constraint {
+            Constraint::Unique => ErrorKind::UniqueViolation,
+            Constraint::ForeignKey => ErrorKind::ForeignKeyViolation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        store::abort,
+        types::{
+            pagination::{extract_pagination, CursorSigner},
+            user::NewUser,
+        },
+    };
+
+    fn pagination(sort: &str, cursor: Option<String>) -> Pagination {
+        let config = Config::for_tests().pagination;
+        let mut params = HashMap::from([
+            ("limit".to_string(), "1".to_string()),
+            ("sort".to_string(), sort.to_string()),
+        ]);
+        params.extend(cursor.map(|cursor| ("cursor".to_string(), cursor)));
+
+        extract_pagination(params, &config, &CursorSigner::new(&config.cursor_secret)).unwrap()
+    }
+
+    #[test]
+    fn pages_through_rows_created_within_a_microsecond() {
+        // Later ids a few nanoseconds earlier, all in the same microsecond
+        let at = DateTime::from_timestamp_nanos(1_700_000_000_123_456_900);
+        let rows: Vec<_> = (1..=4)
+            .map(|id| {
+                (
+                    id,
+                    id,
+                    Some(at - chrono::Duration::nanoseconds(id as i64)),
+                    None,
+                )
+            })
+            .collect();
+
+        for (sort, expected) in [("created_at", [1, 2, 3, 4]), ("-created_at", [4, 3, 2, 1])] {
+            let mut ids = Vec::new();
+            let mut cursor = None;
+
+            loop {
+                let pagination = pagination(sort, cursor);
+                let (page, next) = pagination.split(list_page(rows.iter().cloned(), &pagination));
+                ids.extend(page);
+
+                assert!(
+                    ids.len() <= rows.len(),
+                    "{} went round again: {:?}",
+                    sort,
+                    ids
+                );
+
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+
+            assert_eq!(ids, expected, "{}", sort);
+        }
+    }
+
+    #[tokio::test]
+    async fn rolls_back_work_that_fails_part_way() {
+        let store = MemoryStore::new();
+        let user = store
+            .add_user(NewUser {
+                email: "author@example.com".to_string(),
+                password: "not a real hash".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let mut work = store.begin().await.unwrap();
+        let question = work
+            .add_question(
+                NewQuestion {
+                    title: "Rolled back".to_string(),
+                    content: "Never committed".to_string(),
+                    tags: None,
+                },
+                &user.id,
+            )
+            .await
+            .unwrap();
+        let answer = work
+            .add_answer(
+                question.id.0,
+                NewAnswer {
+                    content: "Never committed either".to_string(),
+                    question_id: question.id.clone(),
+                },
+                &user.id,
+            )
+            .await
+            .unwrap();
+
+        let e = work.delete_question(question.id.0).await.unwrap_err();
+        let e = abort(work, e).await;
+        assert!(
+            matches!(&e, sqlx::Error::Database(e) if e.kind() == ErrorKind::ForeignKeyViolation),
+            "{}",
+            e
+        );
+
+        // Nothing is left behind, and the store isn't held by the aborted work
+        assert!(matches!(
+            store.get_question_by_id(question.id.0).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(matches!(
+            store.get_answer_by_id(answer.id.0).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let mut work = store.begin().await.unwrap();
+        assert!(matches!(
+            work.get_question_by_id(question.id.0).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        work.rollback().await.unwrap();
+    }
+}
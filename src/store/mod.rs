@@ -0,0 +1,431 @@
+mod json_file;
+mod memory;
+mod postgres;
+mod sqlite;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::DatabaseConfig,
+    moderation::Flag,
+    types::{
+        answer::{Answer, NewAnswer},
+        api_key::{ApiKey, ApiKeyId, ApiScope},
+        pagination::{Cursor, Pagination},
+        question::{NewQuestion, Question},
+        role::{Permission, Role, RoleAudit},
+        session::{ActiveSession, LoginFailures},
+        user::{AccountId, LinkPurpose, NewUser, User},
+    },
+};
+
+pub use json_file::JsonFile;
+pub use memory::MemoryStore;
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+/// Everything the API keeps. Backends report failures as `sqlx::Error`s the
+/// way Postgres would: `RowNotFound` for a missing row and a database error
+/// of the matching kind for a violated constraint, so handlers and
+/// error responses treat every backend the same.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn count_questions(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error>;
+
+    async fn get_questions(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Question, Cursor)>, sqlx::Error>;
+
+    async fn get_question_by_id(&self, question_id: i32) -> Result<Question, sqlx::Error>;
+
+    /// Closes a question to new answers and returns it. Closing it again
+    /// keeps the time it was first closed.
+    async fn close_question(&self, question_id: i32) -> Result<Question, sqlx::Error>;
+
+    async fn count_answers(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error>;
+
+    async fn get_answers(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error>;
+
+    async fn get_answer_by_id(&self, answer_id: i32) -> Result<Answer, sqlx::Error>;
+
+    async fn count_answers_for_question(
+        &self,
+        question_id: i32,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error>;
+
+    async fn get_answers_for_question(
+        &self,
+        question_id: i32,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error>;
+
+    async fn add_user(&self, new_user: NewUser) -> Result<User, sqlx::Error>;
+
+    async fn has_permission(
+        &self,
+        account_id: &AccountId,
+        permission: Permission,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn get_roles(&self, account_id: i32) -> Result<Vec<Role>, sqlx::Error>;
+
+    /// Grants `role` and records who did it in the audit log. Granting a role
+    /// the account already holds changes nothing.
+    async fn grant_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Revokes `role` and records who did it in the audit log.
+    async fn revoke_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error>;
+
+    async fn count_role_audit_log(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error>;
+
+    /// A page of grants and revokes. Entries are never changed, so sorting
+    /// by update time is the same as sorting by creation time.
+    async fn get_role_audit_log(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(RoleAudit, Cursor)>, sqlx::Error>;
+
+    async fn add_session(
+        &self,
+        account_id: &AccountId,
+        refresh_token_hash: &str,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+        mfa: bool,
+    ) -> Result<i32, sqlx::Error>;
+
+    /// Swaps the refresh token of an active session for a new one, returning
+    /// the session and account ids and whether it was started with a second
+    /// factor. `RowNotFound` if the old token is unknown, already used, or its
+    /// session is over.
+    async fn refresh_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(i32, AccountId, bool), sqlx::Error>;
+
+    async fn is_session_active(&self, session_id: i32) -> Result<bool, sqlx::Error>;
+
+    async fn get_active_sessions(
+        &self,
+        account_id: &AccountId,
+        current_session_id: i32,
+    ) -> Result<Vec<ActiveSession>, sqlx::Error>;
+
+    /// Revokes every active session of an account, returning their ids.
+    async fn revoke_sessions(&self, account_id: &AccountId) -> Result<Vec<i32>, sqlx::Error>;
+
+    async fn revoke_session(&self, session_id: i32) -> Result<(), sqlx::Error>;
+
+    async fn get_user_by_email(&self, email: &str) -> Result<User, sqlx::Error>;
+
+    async fn get_user(&self, account_id: &AccountId) -> Result<User, sqlx::Error>;
+
+    async fn add_link_token(
+        &self,
+        purpose: LinkPurpose,
+        account_id: &AccountId,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Spends a token, along with any other unused ones the account has for
+    /// the same purpose, returning the account it was issued to.
+    /// `RowNotFound` if it is unknown, used or expired.
+    async fn redeem_link_token(
+        &self,
+        purpose: LinkPurpose,
+        token_hash: &str,
+    ) -> Result<AccountId, sqlx::Error>;
+
+    async fn set_email_verified(&self, account_id: &AccountId) -> Result<(), sqlx::Error>;
+
+    /// Sets a new password hash. Only reachable through a mailed link, so it
+    /// also counts as verifying the email address.
+    async fn update_password(
+        &self,
+        account_id: &AccountId,
+        password_hash: &str,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Remembers a login sent off to the OpenID Connect provider, clearing
+    /// out ones that were never finished.
+    async fn add_oidc_login(
+        &self,
+        state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Removes a pending login, returning its PKCE verifier and nonce.
+    /// `RowNotFound` if the state is unknown, used or expired.
+    async fn take_oidc_login(&self, state: &str) -> Result<(String, String), sqlx::Error>;
+
+    /// The account a provider identity signs in to, noting the login.
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, sqlx::Error>;
+
+    async fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        account_id: &AccountId,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Creates a password-less account for a provider identity.
+    async fn add_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<User, sqlx::Error>;
+
+    /// The account's TOTP secret, whether or not enrollment was confirmed.
+    async fn get_totp_secret(&self, account_id: &AccountId) -> Result<Option<String>, sqlx::Error>;
+
+    /// Starts (or restarts) enrollment with a new secret. `RowNotFound` if
+    /// two-factor authentication is already on.
+    async fn set_totp_secret(
+        &self,
+        account_id: &AccountId,
+        secret: &str,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Finishes enrollment, replacing any recovery codes with `code_hashes`.
+    /// `RowNotFound` if there is no enrollment in progress.
+    async fn enable_totp(
+        &self,
+        account_id: &AccountId,
+        step: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error>;
+
+    async fn disable_totp(&self, account_id: &AccountId) -> Result<(), sqlx::Error>;
+
+    /// Records that the code for time step `step` was used. False if it, or
+    /// a later one, already was.
+    async fn use_totp_step(&self, account_id: &AccountId, step: i64) -> Result<bool, sqlx::Error>;
+
+    /// Spends a recovery code. False if the account has no such unused code.
+    async fn use_recovery_code(
+        &self,
+        account_id: &AccountId,
+        code_hash: &str,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn add_login_challenge(
+        &self,
+        token_hash: &str,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The account and user agent of a challenge that can still be answered.
+    /// `RowNotFound` if it is unknown, used, expired or failed too often.
+    async fn get_login_challenge(
+        &self,
+        token_hash: &str,
+        max_failed_attempts: i32,
+    ) -> Result<(AccountId, Option<String>), sqlx::Error>;
+
+    async fn fail_login_challenge(&self, token_hash: &str) -> Result<(), sqlx::Error>;
+
+    /// Marks a challenge answered. False if it already was, so two requests
+    /// racing with the same code can't both log in.
+    async fn complete_login_challenge(&self, token_hash: &str) -> Result<bool, sqlx::Error>;
+
+    /// Takes back one failure counted against `key` for an attempt that
+    /// turned out not to be one.
+    async fn remove_login_failure(&self, key: &str) -> Result<(), sqlx::Error>;
+
+    /// Locks `key` until `until`, with its failures counted from zero
+    /// afterwards.
+    async fn lock_login(&self, key: &str, until: DateTime<Utc>) -> Result<(), sqlx::Error>;
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), sqlx::Error>;
+
+    async fn add_api_key(
+        &self,
+        account_id: &AccountId,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey, sqlx::Error>;
+
+    /// The account's keys that haven't been revoked, expired ones included
+    /// so they can be told apart from ones that never existed.
+    async fn get_api_keys(&self, account_id: &AccountId) -> Result<Vec<ApiKey>, sqlx::Error>;
+
+    /// `RowNotFound` if the account has no such key, or it was already
+    /// revoked.
+    async fn revoke_api_key(
+        &self,
+        account_id: &AccountId,
+        api_key_id: ApiKeyId,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Revokes every active key of an account, returning how many there
+    /// were.
+    async fn revoke_api_keys(&self, account_id: &AccountId) -> Result<u64, sqlx::Error>;
+
+    /// Notes a request made with a key, returning the key, its account and
+    /// scopes. `RowNotFound` if it is unknown, revoked or expired.
+    async fn use_api_key(
+        &self,
+        key_hash: &str,
+    ) -> Result<(ApiKeyId, AccountId, Vec<ApiScope>), sqlx::Error>;
+
+    /// Starts a unit of work, for changes made in several steps that must
+    /// all take effect or none of them.
+    async fn begin(&self) -> Result<Box<dyn UnitOfWork>, sqlx::Error>;
+}
+
+/// Changes to questions and answers that take effect together on `commit`.
+/// Until then nothing else sees them, and dropping the unit of work without
+/// committing rolls them back, so a handler returning early on an error
+/// leaves nothing half done.
+#[async_trait]
+pub trait UnitOfWork: Send {
+    /// Also keeps the question from being changed or deleted until the unit
+    /// of work ends, so it can safely be checked before changing it or adding
+    /// answers to it.
+    async fn get_question_by_id(&mut self, question_id: i32) -> Result<Question, sqlx::Error>;
+
+    async fn add_question(
+        &mut self,
+        new_question: NewQuestion,
+        account_id: &AccountId,
+    ) -> Result<Question, sqlx::Error>;
+
+    async fn update_question(
+        &mut self,
+        question: Question,
+        question_id: i32,
+    ) -> Result<Question, sqlx::Error>;
+
+    /// Fails with a foreign key violation while the question has answers.
+    async fn delete_question(&mut self, question_id: i32) -> Result<(), sqlx::Error>;
+
+    /// Like `get_question_by_id`, keeps the answer as it is until the unit
+    /// of work ends.
+    async fn get_answer_by_id(&mut self, answer_id: i32) -> Result<Answer, sqlx::Error>;
+
+    async fn add_answer(
+        &mut self,
+        question_id: i32,
+        answer: NewAnswer,
+        account_id: &AccountId,
+    ) -> Result<Answer, sqlx::Error>;
+
+    async fn update_answer(
+        &mut self,
+        answer: Answer,
+        answer_id: i32,
+    ) -> Result<Answer, sqlx::Error>;
+
+    async fn delete_answer(&mut self, answer_id: i32) -> Result<(), sqlx::Error>;
+
+    async fn queue_for_review(
+        &mut self,
+        item_type: &str,
+        item_id: i32,
+        flags: Vec<Flag>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The failures of each of `keys` that has failed since `since` or is
+    /// locked. Also keeps them from changing until the unit of work ends, so
+    /// a login attempt can be checked against them and counted as one step.
+    async fn get_login_failures(
+        &mut self,
+        keys: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<LoginFailures>, sqlx::Error>;
+
+    /// Counts a failed login against `key`, starting over if its last
+    /// failure was before `since`, and returns its failures. Clears out keys
+    /// that have nothing left to remember.
+    async fn add_login_failure(
+        &mut self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<i32, sqlx::Error>;
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error>;
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error>;
+}
+
+/// Rolls `work` back after it failed with `e`, handing `e` back for the
+/// caller to report. A failed rollback is only logged, since `e` is what
+/// went wrong and the backend drops the unfinished work either way.
+pub async fn abort<E>(work: Box<dyn UnitOfWork>, e: E) -> E {
+    if let Err(rollback_error) = work.rollback().await {
+        tracing::error!(error = %rollback_error, "could not roll back unit of work");
+    }
+
+    e
+}
+
+/// Connects to the backend named by the scheme of `database.url`:
+/// `postgres://`, `sqlite://` followed by the path of the database file (e.g.
+/// `sqlite://q-and-a.db`), `memory://`, which starts out empty and forgets
+/// everything on exit, or `file://` followed by the path of a JSON file like
+/// `questions.json` to keep questions and answers in (e.g.
+/// `file://questions.json`, or `file:///var/lib/q-and-a/questions.json`).
+pub async fn from_config(config: &DatabaseConfig) -> Result<Arc<dyn Store>, sqlx::Error> {
+    if config.url.starts_with("sqlite:") {
+        return Ok(Arc::new(
+            SqliteStore::connect(&config.url, config.max_connections).await?,
+        ));
+    }
+
+    if config.url.starts_with("memory://") {
+        return Ok(Arc::new(MemoryStore::new()));
+    }
+
+    if let Some(path) = config.url.strip_prefix("file://") {
+        return Ok(Arc::new(
+            MemoryStore::open(JsonFile::new(path.into())).map_err(sqlx::Error::Io)?,
+        ));
+    }
+
+    Ok(Arc::new(
+        PgStore::connect(&config.url, config.max_connections).await?,
+    ))
+}
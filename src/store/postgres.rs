@@ -0,0 +1,1501 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgPool, PgPoolOptions, Postgres};
+use sqlx::Transaction;
+
+use super::{Store, UnitOfWork};
+use crate::moderation::Flag;
+use crate::types::question::NewQuestion;
+use crate::types::{
+    answer::{Answer, AnswerId, NewAnswer},
+    api_key::{ApiKey, ApiKeyId, ApiScope},
+    pagination::{Cursor, Pagination, Sort},
+    question::{Question, QuestionId},
+    role::{Permission, Role, RoleAudit},
+    session::{ActiveSession, LoginFailures},
+    user::{AccountId, LinkPurpose, NewUser, User},
+};
+
+// Queries are checked against the schema at compile time. The results are
+// kept in `.sqlx`, so building needs no database; after changing a query or
+// a migration, run `cargo sqlx prepare` against a migrated database and
+// commit what it writes there.
+
+/// Fetches a page of `$record`s from `$table`. `query_as!` only takes
+/// literal SQL, so there is one query per sort order. `$1` to `$6` are the
+/// pagination parameters; `$condition` may narrow the rows further using
+/// parameters from `$7` on, bound from `$args`.
+macro_rules! fetch_page {
+    ($record:ident, $columns:literal, $table:literal, $condition:literal, $pagination:expr, $connection:expr $(, $args:expr)*) => {{
+        let pagination: &Pagination = $pagination;
+        let after_at = pagination.after.map(|cursor| cursor.at);
+        let after_id = pagination.after.map(|cursor| cursor.id);
+
+        match pagination.sort {
+            Sort::CreatedAsc => sqlx::query_as!(
+                $record,
+                "select " + $columns + " from " + $table + "
+                where ($3::timestamptz is null or created_at >= $3)
+                and ($4::timestamptz is null or created_at < $4)
+                and ($5::timestamptz is null or (created_at, id) > ($5, $6)) " + $condition + "
+                order by created_at asc, id asc
+                limit $1 offset $2",
+                i64::from(pagination.fetch_limit()),
+                i64::from(pagination.offset),
+                pagination.since,
+                pagination.until,
+                after_at,
+                after_id,
+                $($args),*
+            )
+            .fetch_all($connection)
+            .await,
+            Sort::CreatedDesc => sqlx::query_as!(
+                $record,
+                "select " + $columns + " from " + $table + "
+                where ($3::timestamptz is null or created_at >= $3)
+                and ($4::timestamptz is null or created_at < $4)
+                and ($5::timestamptz is null or (created_at, id) < ($5, $6)) " + $condition + "
+                order by created_at desc, id desc
+                limit $1 offset $2",
+                i64::from(pagination.fetch_limit()),
+                i64::from(pagination.offset),
+                pagination.since,
+                pagination.until,
+                after_at,
+                after_id,
+                $($args),*
+            )
+            .fetch_all($connection)
+            .await,
+            Sort::UpdatedAsc => sqlx::query_as!(
+                $record,
+                "select " + $columns + " from " + $table + "
+                where ($3::timestamptz is null or created_at >= $3)
+                and ($4::timestamptz is null or created_at < $4)
+                and ($5::timestamptz is null or (updated_at, id) > ($5, $6)) " + $condition + "
+                order by updated_at asc, id asc
+                limit $1 offset $2",
+                i64::from(pagination.fetch_limit()),
+                i64::from(pagination.offset),
+                pagination.since,
+                pagination.until,
+                after_at,
+                after_id,
+                $($args),*
+            )
+            .fetch_all($connection)
+            .await,
+            Sort::UpdatedDesc => sqlx::query_as!(
+                $record,
+                "select " + $columns + " from " + $table + "
+                where ($3::timestamptz is null or created_at >= $3)
+                and ($4::timestamptz is null or created_at < $4)
+                and ($5::timestamptz is null or (updated_at, id) < ($5, $6)) " + $condition + "
+                order by updated_at desc, id desc
+                limit $1 offset $2",
+                i64::from(pagination.fetch_limit()),
+                i64::from(pagination.offset),
+                pagination.since,
+                pagination.until,
+                after_at,
+                after_id,
+                $($args),*
+            )
+            .fetch_all($connection)
+            .await,
+        }
+    }};
+}
+
+#[derive(Clone)]
+pub struct PgStore {
+    pub connection: PgPool,
+}
+
+impl PgStore {
+    /// Connects and brings the schema up to date.
+    pub async fn connect(db_url: &str, max_connections: u32) -> Result<Self, sqlx::Error> {
+        let connection = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(db_url)
+            .await?;
+
+        sqlx::migrate!()
+            .run(&connection)
+            .await
+            .map_err(|e| sqlx::Error::Migrate(Box::new(e)))?;
+
+        Ok(PgStore { connection })
+    }
+}
+
+/// An `api_keys` row before its scopes are parsed.
+struct ApiKeyRow {
+    id: ApiKeyId,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: parse_scopes(&row.scopes),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+// async_trait hands each body to a boxed future, where clippy starts
+// flagging the `Ok(x) => Ok(x)` matches it accepted on inherent methods
+#[allow(clippy::needless_match)]
+#[async_trait]
+impl Store for PgStore {
+    async fn count_questions(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select count(*) as "count!" from questions
+            where ($1::timestamptz is null or created_at >= $1)
+            and ($2::timestamptz is null or created_at < $2)"#,
+            since,
+            until,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_questions(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Question, Cursor)>, sqlx::Error> {
+        match fetch_page!(
+            Question,
+            r#"id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at"#,
+            "questions",
+            "",
+            pagination,
+            &self.connection
+        ) {
+            Ok(questions) => Ok(with_cursors(questions, pagination.sort, question_cursor)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_question_by_id(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as!(
+            Question,
+            r#"select id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at
+            from questions where id=$1"#,
+            question_id,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(question) => Ok(question),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn close_question(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as!(
+            Question,
+            r#"update questions set closed_at=coalesce(closed_at, now()) where id=$1
+            returning id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at"#,
+            question_id,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(question) => Ok(question),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn count_answers(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select count(*) as "count!" from answers
+            where ($1::timestamptz is null or created_at >= $1)
+            and ($2::timestamptz is null or created_at < $2)"#,
+            since,
+            until,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answers(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        match fetch_page!(
+            Answer,
+            r#"id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?""#,
+            "answers",
+            "",
+            pagination,
+            &self.connection
+        ) {
+            Ok(answers) => Ok(with_cursors(answers, pagination.sort, answer_cursor)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answer_by_id(&self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as!(
+            Answer,
+            r#"select id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?"
+            from answers where id=$1"#,
+            answer_id,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn count_answers_for_question(
+        &self,
+        question_id: i32,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select count(*) as "count!" from answers
+            where question_id=$1
+            and ($2::timestamptz is null or created_at >= $2)
+            and ($3::timestamptz is null or created_at < $3)"#,
+            question_id,
+            since,
+            until,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answers_for_question(
+        &self,
+        question_id: i32,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        match fetch_page!(
+            Answer,
+            r#"id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?""#,
+            "answers",
+            "and question_id=$7",
+            pagination,
+            &self.connection,
+            question_id
+        ) {
+            Ok(answers) => Ok(with_cursors(answers, pagination.sort, answer_cursor)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_user(&self, new_user: NewUser) -> Result<User, sqlx::Error> {
+        match sqlx::query_as!(
+            User,
+            r#"insert into users (email, password) values ($1, $2)
+            returning id as "id: AccountId", email, password, created_at,
+            email_verified_at, totp_enabled_at"#,
+            new_user.email,
+            new_user.password,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn has_permission(
+        &self,
+        account_id: &AccountId,
+        permission: Permission,
+    ) -> Result<bool, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select exists (
+                select 1 from user_roles
+                join role_permissions on role_permissions.role = user_roles.role
+                where user_roles.account_id=$1 and role_permissions.permission=$2
+            ) as "exists!""#,
+            account_id.0,
+            permission.as_str(),
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(has_permission) => Ok(has_permission),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_roles(&self, account_id: i32) -> Result<Vec<Role>, sqlx::Error> {
+        match sqlx::query_as!(
+            Role,
+            r#"select role as name, granted_by as "granted_by: AccountId", granted_at
+            from user_roles where account_id=$1 order by role"#,
+            account_id,
+        )
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(roles) => Ok(roles),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn grant_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "with granted as (
+                insert into user_roles (account_id, role, granted_by) values ($1, $2, $3)
+                on conflict do nothing
+                returning account_id, role
+            )
+            insert into role_audit_log (actor_id, account_id, role, action)
+            select $3, account_id, role, 'grant' from granted",
+            account_id,
+            role,
+            actor.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "with revoked as (
+                delete from user_roles where account_id=$1 and role=$2
+                returning account_id, role
+            )
+            insert into role_audit_log (actor_id, account_id, role, action)
+            select $3, account_id, role, 'revoke' from revoked",
+            account_id,
+            role,
+            actor.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn count_role_audit_log(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select count(*) as "count!" from role_audit_log
+            where ($1::timestamptz is null or created_at >= $1)
+            and ($2::timestamptz is null or created_at < $2)"#,
+            since,
+            until,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_role_audit_log(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(RoleAudit, Cursor)>, sqlx::Error> {
+        // Entries are never updated, so they stand in their creation time.
+        match fetch_page!(
+            RoleAudit,
+            r#"id as "id!", actor_id as "actor_id: AccountId",
+            account_id as "account_id!: AccountId", role as "role!", action as "action!",
+            created_at as "created_at!""#,
+            "(select *, created_at as updated_at from role_audit_log) entries",
+            "",
+            pagination,
+            &self.connection
+        ) {
+            Ok(entries) => Ok(with_cursors(entries, pagination.sort, role_audit_cursor)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_session(
+        &self,
+        account_id: &AccountId,
+        refresh_token_hash: &str,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+        mfa: bool,
+    ) -> Result<i32, sqlx::Error> {
+        match sqlx::query_scalar!(
+            "insert into sessions (account_id, refresh_token_hash, user_agent, expires_at, mfa)
+            values ($1, $2, $3, $4, $5) returning id",
+            account_id.0,
+            refresh_token_hash,
+            user_agent,
+            expires_at,
+            mfa,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(session_id) => Ok(session_id),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(i32, AccountId, bool), sqlx::Error> {
+        match sqlx::query!(
+            r#"update sessions set refresh_token_hash=$2, expires_at=$3, last_used_at=now()
+            where refresh_token_hash=$1 and revoked_at is null and expires_at > now()
+            returning id, account_id as "account_id: AccountId", mfa"#,
+            refresh_token_hash,
+            new_refresh_token_hash,
+            expires_at,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(session) => Ok((session.id, session.account_id, session.mfa)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn is_session_active(&self, session_id: i32) -> Result<bool, sqlx::Error> {
+        match sqlx::query_scalar!(
+            r#"select exists (
+                select 1 from sessions
+                where id=$1 and revoked_at is null and expires_at > now()
+            ) as "exists!""#,
+            session_id,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(active) => Ok(active),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_active_sessions(
+        &self,
+        account_id: &AccountId,
+        current_session_id: i32,
+    ) -> Result<Vec<ActiveSession>, sqlx::Error> {
+        match sqlx::query_as!(
+            ActiveSession,
+            r#"select id, user_agent, created_at, last_used_at, expires_at,
+            id=$2 as "current!"
+            from sessions
+            where account_id=$1 and revoked_at is null and expires_at > now()
+            order by last_used_at desc"#,
+            account_id.0,
+            current_session_id,
+        )
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(sessions) => Ok(sessions),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_sessions(&self, account_id: &AccountId) -> Result<Vec<i32>, sqlx::Error> {
+        match sqlx::query_scalar!(
+            "update sessions set revoked_at=now()
+            where account_id=$1 and revoked_at is null
+            returning id",
+            account_id.0,
+        )
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(session_ids) => Ok(session_ids),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_session(&self, session_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update sessions set revoked_at=now() where id=$1 and revoked_at is null",
+            session_id,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<User, sqlx::Error> {
+        match sqlx::query_as!(
+            User,
+            r#"select id as "id: AccountId", email, password, created_at,
+            email_verified_at, totp_enabled_at
+            from users where email=$1"#,
+            email,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user(&self, account_id: &AccountId) -> Result<User, sqlx::Error> {
+        match sqlx::query_as!(
+            User,
+            r#"select id as "id: AccountId", email, password, created_at,
+            email_verified_at, totp_enabled_at
+            from users where id=$1"#,
+            account_id.0,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_link_token(
+        &self,
+        purpose: LinkPurpose,
+        account_id: &AccountId,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let result = match purpose {
+            LinkPurpose::VerifyEmail => {
+                sqlx::query!(
+                    "insert into email_verification_tokens (token_hash, account_id, expires_at)
+                    values ($1, $2, $3)",
+                    token_hash,
+                    account_id.0,
+                    expires_at,
+                )
+                .execute(&self.connection)
+                .await
+            }
+            LinkPurpose::ResetPassword => {
+                sqlx::query!(
+                    "insert into password_reset_tokens (token_hash, account_id, expires_at)
+                    values ($1, $2, $3)",
+                    token_hash,
+                    account_id.0,
+                    expires_at,
+                )
+                .execute(&self.connection)
+                .await
+            }
+        };
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn redeem_link_token(
+        &self,
+        purpose: LinkPurpose,
+        token_hash: &str,
+    ) -> Result<AccountId, sqlx::Error> {
+        let result = match purpose {
+            LinkPurpose::VerifyEmail => {
+                sqlx::query_scalar!(
+                    r#"with redeemed as (
+                        update email_verification_tokens set used_at=now()
+                        where token_hash=$1 and used_at is null and expires_at > now()
+                        returning account_id
+                    ), spent as (
+                        update email_verification_tokens set used_at=now() from redeemed
+                        where email_verification_tokens.account_id=redeemed.account_id
+                        and email_verification_tokens.token_hash<>$1
+                        and email_verification_tokens.used_at is null
+                    )
+                    select account_id as "account_id!: AccountId" from redeemed"#,
+                    token_hash,
+                )
+                .fetch_one(&self.connection)
+                .await
+            }
+            LinkPurpose::ResetPassword => {
+                sqlx::query_scalar!(
+                    r#"with redeemed as (
+                        update password_reset_tokens set used_at=now()
+                        where token_hash=$1 and used_at is null and expires_at > now()
+                        returning account_id
+                    ), spent as (
+                        update password_reset_tokens set used_at=now() from redeemed
+                        where password_reset_tokens.account_id=redeemed.account_id
+                        and password_reset_tokens.token_hash<>$1
+                        and password_reset_tokens.used_at is null
+                    )
+                    select account_id as "account_id!: AccountId" from redeemed"#,
+                    token_hash,
+                )
+                .fetch_one(&self.connection)
+                .await
+            }
+        };
+
+        match result {
+            Ok(account_id) => Ok(account_id),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_email_verified(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update users set email_verified_at=coalesce(email_verified_at, now()), updated_at=now()
+            where id=$1",
+            account_id.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_password(
+        &self,
+        account_id: &AccountId,
+        password_hash: &str,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update users set password=$2, email_verified_at=coalesce(email_verified_at, now()),
+            updated_at=now() where id=$1",
+            account_id.0,
+            password_hash,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_oidc_login(
+        &self,
+        state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "with expired as (
+                delete from oidc_logins where expires_at <= now()
+            )
+            insert into oidc_logins (state, pkce_verifier, nonce, expires_at)
+            values ($1, $2, $3, $4)",
+            state,
+            pkce_verifier,
+            nonce,
+            expires_at,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn take_oidc_login(&self, state: &str) -> Result<(String, String), sqlx::Error> {
+        match sqlx::query!(
+            "delete from oidc_logins where state=$1 and expires_at > now()
+            returning pkce_verifier, nonce",
+            state,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(login) => Ok((login.pkce_verifier, login.nonce)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, sqlx::Error> {
+        match sqlx::query_as!(
+            User,
+            r#"with identity as (
+                update user_identities set last_login_at=now()
+                where issuer=$1 and subject=$2
+                returning account_id
+            )
+            select id as "id: AccountId", email, password, created_at,
+            email_verified_at, totp_enabled_at
+            from users join identity on users.id=identity.account_id"#,
+            issuer,
+            subject,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        account_id: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "insert into user_identities (issuer, subject, account_id) values ($1, $2, $3)",
+            issuer,
+            subject,
+            account_id.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<User, sqlx::Error> {
+        match sqlx::query_as!(
+            User,
+            r#"with account as (
+                insert into users (email, email_verified_at)
+                values ($3, case when $4 then now() end)
+                returning id, email, password, created_at, email_verified_at, totp_enabled_at
+            ), identity as (
+                insert into user_identities (issuer, subject, account_id)
+                select $1, $2, id from account
+            )
+            select id as "id!: AccountId", email as "email!", password,
+            created_at as "created_at!", email_verified_at, totp_enabled_at
+            from account"#,
+            issuer,
+            subject,
+            email,
+            email_verified,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_totp_secret(&self, account_id: &AccountId) -> Result<Option<String>, sqlx::Error> {
+        match sqlx::query_scalar!("select totp_secret from users where id=$1", account_id.0)
+            .fetch_one(&self.connection)
+            .await
+        {
+            Ok(secret) => Ok(secret),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_totp_secret(
+        &self,
+        account_id: &AccountId,
+        secret: &str,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update users set totp_secret=$2, totp_last_step=null, updated_at=now()
+            where id=$1 and totp_enabled_at is null",
+            account_id.0,
+            secret,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn enable_totp(
+        &self,
+        account_id: &AccountId,
+        step: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "with enabled as (
+                update users set totp_enabled_at=now(), totp_last_step=$2, updated_at=now()
+                where id=$1 and totp_secret is not null and totp_enabled_at is null
+                returning id
+            ), cleared as (
+                delete from recovery_codes where account_id in (select id from enabled)
+            )
+            insert into recovery_codes (account_id, code_hash)
+            select enabled.id, code_hash from enabled, unnest($3::varchar[]) as code_hash",
+            account_id.0,
+            step,
+            code_hashes,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn disable_totp(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "with disabled as (
+                update users
+                set totp_secret=null, totp_enabled_at=null, totp_last_step=null, updated_at=now()
+                where id=$1
+                returning id
+            )
+            delete from recovery_codes where account_id in (select id from disabled)",
+            account_id.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn use_totp_step(&self, account_id: &AccountId, step: i64) -> Result<bool, sqlx::Error> {
+        match sqlx::query!(
+            "update users set totp_last_step=$2
+            where id=$1 and (totp_last_step is null or totp_last_step < $2)",
+            account_id.0,
+            step,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn use_recovery_code(
+        &self,
+        account_id: &AccountId,
+        code_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        match sqlx::query!(
+            "update recovery_codes set used_at=now()
+            where account_id=$1 and code_hash=$2 and used_at is null",
+            account_id.0,
+            code_hash,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_login_challenge(
+        &self,
+        token_hash: &str,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "insert into login_challenges (token_hash, account_id, user_agent, expires_at)
+            values ($1, $2, $3, $4)",
+            token_hash,
+            account_id.0,
+            user_agent,
+            expires_at,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_login_challenge(
+        &self,
+        token_hash: &str,
+        max_failed_attempts: i32,
+    ) -> Result<(AccountId, Option<String>), sqlx::Error> {
+        match sqlx::query!(
+            r#"select account_id as "account_id: AccountId", user_agent from login_challenges
+            where token_hash=$1 and used_at is null and expires_at > now()
+            and failed_attempts < $2"#,
+            token_hash,
+            max_failed_attempts,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(challenge) => Ok((challenge.account_id, challenge.user_agent)),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn fail_login_challenge(&self, token_hash: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update login_challenges set failed_attempts=failed_attempts + 1 where token_hash=$1",
+            token_hash,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn complete_login_challenge(&self, token_hash: &str) -> Result<bool, sqlx::Error> {
+        match sqlx::query!(
+            "update login_challenges set used_at=now() where token_hash=$1 and used_at is null",
+            token_hash,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn remove_login_failure(&self, key: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update login_failures set failures=failures - 1 where key=$1 and failures > 0",
+            key,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn lock_login(&self, key: &str, until: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update login_failures set failures=0, locked_until=$2 where key=$1",
+            key,
+            until,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query!("delete from login_failures where key=$1", key)
+            .execute(&self.connection)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_api_key(
+        &self,
+        account_id: &AccountId,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey, sqlx::Error> {
+        match sqlx::query_as!(
+            ApiKeyRow,
+            r#"insert into api_keys (account_id, name, prefix, key_hash, scopes, expires_at)
+            values ($1, $2, $3, $4, $5::varchar[], $6)
+            returning id as "id: ApiKeyId", name, prefix, scopes as "scopes: Vec<String>",
+            created_at, last_used_at, expires_at"#,
+            account_id.0,
+            name,
+            prefix,
+            key_hash,
+            &scope_names(scopes),
+            expires_at,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(api_key) => Ok(api_key.into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_api_keys(&self, account_id: &AccountId) -> Result<Vec<ApiKey>, sqlx::Error> {
+        match sqlx::query_as!(
+            ApiKeyRow,
+            r#"select id as "id: ApiKeyId", name, prefix, scopes as "scopes: Vec<String>",
+            created_at, last_used_at, expires_at
+            from api_keys where account_id=$1 and revoked_at is null order by id"#,
+            account_id.0,
+        )
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(api_keys) => Ok(api_keys.into_iter().map(ApiKey::from).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_api_key(
+        &self,
+        account_id: &AccountId,
+        api_key_id: ApiKeyId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query!(
+            "update api_keys set revoked_at=now()
+            where id=$1 and account_id=$2 and revoked_at is null",
+            api_key_id.0,
+            account_id.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_api_keys(&self, account_id: &AccountId) -> Result<u64, sqlx::Error> {
+        match sqlx::query!(
+            "update api_keys set revoked_at=now() where account_id=$1 and revoked_at is null",
+            account_id.0,
+        )
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn use_api_key(
+        &self,
+        key_hash: &str,
+    ) -> Result<(ApiKeyId, AccountId, Vec<ApiScope>), sqlx::Error> {
+        match sqlx::query!(
+            r#"update api_keys set last_used_at=now()
+            where key_hash=$1 and revoked_at is null
+            and (expires_at is null or expires_at > now())
+            returning id as "id: ApiKeyId", account_id as "account_id: AccountId",
+            scopes as "scopes: Vec<String>""#,
+            key_hash,
+        )
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(api_key) => Ok((
+                api_key.id,
+                api_key.account_id,
+                parse_scopes(&api_key.scopes),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn begin(&self) -> Result<Box<dyn UnitOfWork>, sqlx::Error> {
+        match self.connection.begin().await {
+            Ok(tx) => Ok(Box::new(PgUnitOfWork { tx })),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A transaction, ended by `commit` or `rollback`, or rolled back by sqlx
+/// when dropped.
+struct PgUnitOfWork {
+    tx: Transaction<'static, Postgres>,
+}
+
+#[allow(clippy::needless_match)]
+#[async_trait]
+impl UnitOfWork for PgUnitOfWork {
+    async fn get_question_by_id(&mut self, question_id: i32) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as!(
+            Question,
+            r#"select id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at
+            from questions where id=$1 for update"#,
+            question_id,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(question) => Ok(question),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_question(
+        &mut self,
+        new_question: NewQuestion,
+        account_id: &AccountId,
+    ) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as!(
+            Question,
+            r#"insert into questions (title, content, tags, account_id) values ($1, $2, $3, $4)
+            returning id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at"#,
+            new_question.title,
+            new_question.content,
+            new_question.tags.as_deref(),
+            account_id.0,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(question) => Ok(question),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_question(
+        &mut self,
+        question: Question,
+        question_id: i32,
+    ) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as!(
+            Question,
+            r#"update questions set title=$2, content=$3, tags=$4, updated_at=now() where id=$1
+            returning id as "id: QuestionId", title, content as "content!", tags,
+            account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?", closed_at"#,
+            question_id,
+            question.title,
+            question.content,
+            question.tags.as_deref(),
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(question) => Ok(question),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete_question(&mut self, question_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query!("delete from questions where id=$1", question_id)
+            .execute(&mut *self.tx)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answer_by_id(&mut self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as!(
+            Answer,
+            r#"select id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?"
+            from answers where id=$1 for update"#,
+            answer_id,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_answer(
+        &mut self,
+        question_id: i32,
+        answer: NewAnswer,
+        account_id: &AccountId,
+    ) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as!(
+            Answer,
+            r#"insert into answers (content, question_id, account_id) values ($1, $2, $3)
+            returning id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?""#,
+            answer.content,
+            question_id,
+            account_id.0,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_answer(
+        &mut self,
+        answer: Answer,
+        answer_id: i32,
+    ) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as!(
+            Answer,
+            r#"update answers set content=$2, updated_at=now() where id=$1
+            returning id as "id: AnswerId", content as "content!",
+            question_id as "question_id!: QuestionId", account_id as "account_id: AccountId",
+            created_at as "created_at?", updated_at as "updated_at?""#,
+            answer_id,
+            answer.content,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answers) => Ok(answers),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete_answer(&mut self, answer_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query!("delete from answers where id=$1", answer_id)
+            .execute(&mut *self.tx)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn queue_for_review(
+        &mut self,
+        item_type: &str,
+        item_id: i32,
+        flags: Vec<Flag>,
+    ) -> Result<(), sqlx::Error> {
+        for flag in flags {
+            sqlx::query!(
+                "insert into review_queue (item_type, item_id, field, matched_words) values ($1, $2, $3, $4)",
+                item_type,
+                item_id,
+                flag.field.as_str(),
+                &flag.matched_words,
+            )
+            .execute(&mut *self.tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Locks the keys' rows, adding any that are missing, so no other login
+    /// attempt can count against them until this one is done. Keys are
+    /// locked in the order given, so attempts can't deadlock each other.
+    async fn get_login_failures(
+        &mut self,
+        keys: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<LoginFailures>, sqlx::Error> {
+        sqlx::query!(
+            "insert into login_failures (key, failures, last_failure_at)
+            select key, 0, now() from unnest($1::varchar[]) as key
+            on conflict (key) do update set key = excluded.key",
+            keys,
+        )
+        .execute(&mut *self.tx)
+        .await?;
+
+        match sqlx::query_as!(
+            LoginFailures,
+            "select key, failures, last_failure_at, locked_until from login_failures
+            where key = any($1) and (last_failure_at > $2 or locked_until > now())",
+            keys,
+            since,
+        )
+        .fetch_all(&mut *self.tx)
+        .await
+        {
+            Ok(failures) => Ok(failures),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Leaves stale keys that other attempts hold for later, rather than
+    /// waiting on them.
+    async fn add_login_failure(
+        &mut self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<i32, sqlx::Error> {
+        match sqlx::query_scalar!(
+            "with stale as (
+                delete from login_failures where key in (
+                    select key from login_failures
+                    where key <> $1 and last_failure_at <= $2
+                    and (locked_until is null or locked_until <= now())
+                    for update skip locked
+                )
+            )
+            insert into login_failures (key, failures, last_failure_at)
+            values ($1, 1, now())
+            on conflict (key) do update set
+                failures = case
+                    when login_failures.last_failure_at > $2 then login_failures.failures + 1
+                    else 1
+                end,
+                last_failure_at = now()
+            returning failures",
+            key,
+            since,
+        )
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(failures) => Ok(failures),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+}
+
+fn with_cursors<T>(
+    items: Vec<T>,
+    sort: Sort,
+    cursor: impl Fn(&T, Sort) -> Cursor,
+) -> Vec<(T, Cursor)> {
+    items
+        .into_iter()
+        .map(|item| {
+            let cursor = cursor(&item, sort);
+            (item, cursor)
+        })
+        .collect()
+}
+
+fn question_cursor(question: &Question, sort: Sort) -> Cursor {
+    Cursor::new(
+        sort,
+        question.id.0,
+        question.created_at,
+        question.updated_at,
+    )
+}
+
+fn answer_cursor(answer: &Answer, sort: Sort) -> Cursor {
+    Cursor::new(sort, answer.id.0, answer.created_at, answer.updated_at)
+}
+
+fn role_audit_cursor(entry: &RoleAudit, sort: Sort) -> Cursor {
+    Cursor::new(
+        sort,
+        entry.id,
+        Some(entry.created_at),
+        Some(entry.created_at),
+    )
+}
+
+/// Skips scopes this version doesn't know, e.g. ones since removed.
+fn parse_scopes(names: &[String]) -> Vec<ApiScope> {
+    names
+        .iter()
+        .filter_map(|scope| ApiScope::parse(scope))
+        .collect()
+}
+
+fn scope_names(scopes: &[ApiScope]) -> Vec<String> {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect()
+}
@@ -0,0 +1,1775 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::query::QueryAs;
+use sqlx::sqlite::{
+    SqliteArguments, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool,
+    SqlitePoolOptions,
+};
+use sqlx::{Sqlite, Transaction};
+
+use super::{Store, UnitOfWork};
+use crate::moderation::Flag;
+use crate::types::question::NewQuestion;
+use crate::types::{
+    answer::{Answer, NewAnswer},
+    api_key::{ApiKey, ApiKeyId, ApiScope},
+    pagination::{Cursor, Pagination, Sort},
+    question::{Question, QuestionId},
+    role::{Permission, Role, RoleAudit},
+    session::{ActiveSession, LoginFailures},
+    user::{AccountId, LinkPurpose, NewUser, User},
+};
+
+/// The questions columns, with `tags` gathered from `question_tags` into a
+/// JSON array.
+const QUESTION_COLUMNS: &str = "id, title, content,
+    (select json_group_array(tag) from (
+        select tag from question_tags where question_id=questions.id order by position
+    )) as tags,
+    account_id, created_at, updated_at, closed_at";
+
+const ANSWER_COLUMNS: &str = "id, content, question_id, account_id, created_at, updated_at";
+
+const USER_COLUMNS: &str = "id, email, password, created_at, email_verified_at, totp_enabled_at";
+
+/// Keeps everything in a single SQLite file, for running on one machine
+/// without a database server. Times are bound from here rather than taken
+/// from SQLite, so every stored timestamp has the same format.
+///
+/// Unlike `PgStore`, queries here are only checked at runtime: sqlx checks
+/// query macros against the one database in `DATABASE_URL`, which is
+/// Postgres. Rows still map through `FromRow`, so each type's columns are
+/// named in one place.
+#[derive(Clone)]
+pub struct SqliteStore {
+    pub connection: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Opens (creating it if need be) the database at `db_url`, e.g.
+    /// `sqlite://q-and-a.db`, and brings the schema up to date.
+    pub async fn connect(db_url: &str, max_connections: u32) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(db_url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .foreign_keys(true);
+
+        let connection = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&connection)
+            .await
+            .map_err(|e| sqlx::Error::Migrate(Box::new(e)))?;
+
+        Ok(SqliteStore { connection })
+    }
+}
+
+// async_trait hands each body to a boxed future, where clippy starts
+// flagging the `Ok(x) => Ok(x)` matches it accepted on inherent methods
+#[allow(clippy::needless_match)]
+#[async_trait]
+impl Store for SqliteStore {
+    async fn count_questions(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select count(*) from questions
+            where ($1 is null or created_at >= $1)
+            and ($2 is null or created_at < $2)",
+        )
+        .bind(since.map(timestamp))
+        .bind(until.map(timestamp))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_questions(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Question, Cursor)>, sqlx::Error> {
+        let query = list_query(QUESTION_COLUMNS, "questions", None, pagination.sort);
+
+        match bind_list_query(sqlx::query_as::<_, Listed<QuestionRow>>(&query), pagination)
+            .fetch_all(&self.connection)
+            .await
+        {
+            Ok(rows) => Ok(rows
+                .into_iter()
+                .map(|row| row.into_page_item(pagination.sort))
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_question_by_id(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        match sqlx::query_as::<_, QuestionRow>(&format!(
+            "select {} from questions where id=$1",
+            QUESTION_COLUMNS
+        ))
+        .bind(question_id)
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(question) => Ok(question.into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn close_question(&self, question_id: i32) -> Result<Question, sqlx::Error> {
+        let mut connection = self.connection.acquire().await?;
+
+        let closed =
+            sqlx::query("update questions set closed_at=coalesce(closed_at, $2) where id=$1")
+                .bind(question_id)
+                .bind(timestamp(Utc::now()))
+                .execute(&mut *connection)
+                .await?;
+
+        if closed.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        fetch_question(&mut connection, question_id).await
+    }
+
+    async fn count_answers(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select count(*) from answers
+            where ($1 is null or created_at >= $1)
+            and ($2 is null or created_at < $2)",
+        )
+        .bind(since.map(timestamp))
+        .bind(until.map(timestamp))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answers(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        let query = list_query(ANSWER_COLUMNS, "answers", None, pagination.sort);
+
+        match bind_list_query(sqlx::query_as::<_, Listed<Answer>>(&query), pagination)
+            .fetch_all(&self.connection)
+            .await
+        {
+            Ok(rows) => Ok(rows
+                .into_iter()
+                .map(|row| row.into_page_item(pagination.sort))
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answer_by_id(&self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as::<_, Answer>(&format!(
+            "select {} from answers where id=$1",
+            ANSWER_COLUMNS
+        ))
+        .bind(answer_id)
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn count_answers_for_question(
+        &self,
+        question_id: i32,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select count(*) from answers
+            where question_id=$1
+            and ($2 is null or created_at >= $2)
+            and ($3 is null or created_at < $3)",
+        )
+        .bind(question_id)
+        .bind(since.map(timestamp))
+        .bind(until.map(timestamp))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answers_for_question(
+        &self,
+        question_id: i32,
+        pagination: &Pagination,
+    ) -> Result<Vec<(Answer, Cursor)>, sqlx::Error> {
+        let query = list_query(
+            ANSWER_COLUMNS,
+            "answers",
+            Some("question_id=$7"),
+            pagination.sort,
+        );
+
+        match bind_list_query(sqlx::query_as::<_, Listed<Answer>>(&query), pagination)
+            .bind(question_id)
+            .fetch_all(&self.connection)
+            .await
+        {
+            Ok(rows) => Ok(rows
+                .into_iter()
+                .map(|row| row.into_page_item(pagination.sort))
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_user(&self, new_user: NewUser) -> Result<User, sqlx::Error> {
+        match sqlx::query_as::<_, User>(&format!(
+            "insert into users (email, password, created_at, updated_at) values ($1, $2, $3, $3)
+            returning {}",
+            USER_COLUMNS
+        ))
+        .bind(new_user.email)
+        .bind(new_user.password)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn has_permission(
+        &self,
+        account_id: &AccountId,
+        permission: Permission,
+    ) -> Result<bool, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select exists (
+                select 1 from user_roles
+                join role_permissions on role_permissions.role = user_roles.role
+                where user_roles.account_id=$1 and role_permissions.permission=$2
+            )",
+        )
+        .bind(account_id.0)
+        .bind(permission.as_str())
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(has_permission) => Ok(has_permission),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_roles(&self, account_id: i32) -> Result<Vec<Role>, sqlx::Error> {
+        match sqlx::query_as::<_, Role>(
+            "select role as name, granted_by, granted_at from user_roles
+            where account_id=$1 order by role",
+        )
+        .bind(account_id)
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(roles) => Ok(roles),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn grant_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+        let now = Utc::now();
+
+        let granted = sqlx::query(
+            "insert into user_roles (account_id, role, granted_by, granted_at)
+            values ($1, $2, $3, $4)
+            on conflict do nothing",
+        )
+        .bind(account_id)
+        .bind(role)
+        .bind(actor.0)
+        .bind(timestamp(now))
+        .execute(&mut *tx)
+        .await?;
+
+        if granted.rows_affected() > 0 {
+            audit_role(&mut tx, actor, account_id, role, "grant", now).await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn revoke_role(
+        &self,
+        account_id: i32,
+        role: &str,
+        actor: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+
+        let revoked = sqlx::query("delete from user_roles where account_id=$1 and role=$2")
+            .bind(account_id)
+            .bind(role)
+            .execute(&mut *tx)
+            .await?;
+
+        if revoked.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        audit_role(&mut tx, actor, account_id, role, "revoke", Utc::now()).await?;
+
+        tx.commit().await
+    }
+
+    async fn count_role_audit_log(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<i64, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select count(*) from role_audit_log
+            where ($1 is null or created_at >= $1)
+            and ($2 is null or created_at < $2)",
+        )
+        .bind(since.map(timestamp))
+        .bind(until.map(timestamp))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_role_audit_log(
+        &self,
+        pagination: &Pagination,
+    ) -> Result<Vec<(RoleAudit, Cursor)>, sqlx::Error> {
+        // Entries are never updated, so they stand in their creation time.
+        let query = list_query(
+            "id, actor_id, account_id, role, action, created_at",
+            "(select *, created_at as updated_at from role_audit_log)",
+            None,
+            pagination.sort,
+        );
+
+        match bind_list_query(sqlx::query_as::<_, Listed<RoleAudit>>(&query), pagination)
+            .fetch_all(&self.connection)
+            .await
+        {
+            Ok(rows) => Ok(rows
+                .into_iter()
+                .map(|row| row.into_page_item(pagination.sort))
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_session(
+        &self,
+        account_id: &AccountId,
+        refresh_token_hash: &str,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+        mfa: bool,
+    ) -> Result<i32, sqlx::Error> {
+        match sqlx::query_scalar(
+            "insert into sessions
+            (account_id, refresh_token_hash, user_agent, created_at, last_used_at, expires_at, mfa)
+            values ($1, $2, $3, $4, $4, $5, $6) returning id",
+        )
+        .bind(account_id.0)
+        .bind(refresh_token_hash)
+        .bind(user_agent)
+        .bind(timestamp(Utc::now()))
+        .bind(timestamp(expires_at))
+        .bind(mfa)
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(session_id) => Ok(session_id),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn refresh_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(i32, AccountId, bool), sqlx::Error> {
+        match sqlx::query_as(
+            "update sessions set refresh_token_hash=$2, expires_at=$3, last_used_at=$4
+            where refresh_token_hash=$1 and revoked_at is null and expires_at > $4
+            returning id, account_id, mfa",
+        )
+        .bind(refresh_token_hash)
+        .bind(new_refresh_token_hash)
+        .bind(timestamp(expires_at))
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(session) => Ok(session),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn is_session_active(&self, session_id: i32) -> Result<bool, sqlx::Error> {
+        match sqlx::query_scalar(
+            "select exists (
+                select 1 from sessions
+                where id=$1 and revoked_at is null and expires_at > $2
+            )",
+        )
+        .bind(session_id)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(active) => Ok(active),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_active_sessions(
+        &self,
+        account_id: &AccountId,
+        current_session_id: i32,
+    ) -> Result<Vec<ActiveSession>, sqlx::Error> {
+        match sqlx::query_as::<_, ActiveSession>(
+            "select id, user_agent, created_at, last_used_at, expires_at, id=$3 as current
+            from sessions
+            where account_id=$1 and revoked_at is null and expires_at > $2
+            order by last_used_at desc",
+        )
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .bind(current_session_id)
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(sessions) => Ok(sessions),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_sessions(&self, account_id: &AccountId) -> Result<Vec<i32>, sqlx::Error> {
+        match sqlx::query_scalar(
+            "update sessions set revoked_at=$2
+            where account_id=$1 and revoked_at is null
+            returning id",
+        )
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(session_ids) => Ok(session_ids),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_session(&self, session_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query("update sessions set revoked_at=$2 where id=$1 and revoked_at is null")
+            .bind(session_id)
+            .bind(timestamp(Utc::now()))
+            .execute(&self.connection)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<User, sqlx::Error> {
+        match sqlx::query_as::<_, User>(&format!(
+            "select {} from users where email=$1",
+            USER_COLUMNS
+        ))
+        .bind(email)
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user(&self, account_id: &AccountId) -> Result<User, sqlx::Error> {
+        match sqlx::query_as::<_, User>(&format!("select {} from users where id=$1", USER_COLUMNS))
+            .bind(account_id.0)
+            .fetch_one(&self.connection)
+            .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_link_token(
+        &self,
+        purpose: LinkPurpose,
+        account_id: &AccountId,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(&format!(
+            "insert into {} (token_hash, account_id, created_at, expires_at) values ($1, $2, $3, $4)",
+            link_token_table(purpose)
+        ))
+        .bind(token_hash)
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .bind(timestamp(expires_at))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn redeem_link_token(
+        &self,
+        purpose: LinkPurpose,
+        token_hash: &str,
+    ) -> Result<AccountId, sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+        let now = Utc::now();
+
+        let account_id: AccountId = sqlx::query_scalar(&format!(
+            "update {} set used_at=$2
+            where token_hash=$1 and used_at is null and expires_at > $2
+            returning account_id",
+            link_token_table(purpose)
+        ))
+        .bind(token_hash)
+        .bind(timestamp(now))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Spend the account's other links, so only the newest ever works
+        sqlx::query(&format!(
+            "update {} set used_at=$2 where account_id=$1 and used_at is null",
+            link_token_table(purpose)
+        ))
+        .bind(account_id.0)
+        .bind(timestamp(now))
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(account_id)
+    }
+
+    async fn set_email_verified(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update users set email_verified_at=coalesce(email_verified_at, $2), updated_at=$2
+            where id=$1",
+        )
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_password(
+        &self,
+        account_id: &AccountId,
+        password_hash: &str,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update users set password=$2, email_verified_at=coalesce(email_verified_at, $3),
+            updated_at=$3 where id=$1",
+        )
+        .bind(account_id.0)
+        .bind(password_hash)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_oidc_login(
+        &self,
+        state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+        let now = Utc::now();
+
+        sqlx::query("delete from oidc_logins where expires_at <= $1")
+            .bind(timestamp(now))
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            "insert into oidc_logins (state, pkce_verifier, nonce, created_at, expires_at)
+            values ($1, $2, $3, $4, $5)",
+        )
+        .bind(state)
+        .bind(pkce_verifier)
+        .bind(nonce)
+        .bind(timestamp(now))
+        .bind(timestamp(expires_at))
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    async fn take_oidc_login(&self, state: &str) -> Result<(String, String), sqlx::Error> {
+        match sqlx::query_as(
+            "delete from oidc_logins where state=$1 and expires_at > $2
+            returning pkce_verifier, nonce",
+        )
+        .bind(state)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(login) => Ok(login),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+
+        let account_id: i32 = sqlx::query_scalar(
+            "update user_identities set last_login_at=$3
+            where issuer=$1 and subject=$2
+            returning account_id",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let user =
+            sqlx::query_as::<_, User>(&format!("select {} from users where id=$1", USER_COLUMNS))
+                .bind(account_id)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    async fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        account_id: &AccountId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "insert into user_identities (issuer, subject, account_id, created_at, last_login_at)
+            values ($1, $2, $3, $4, $4)",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<User, sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+        let now = Utc::now();
+
+        let user = sqlx::query_as::<_, User>(&format!(
+            "insert into users (email, email_verified_at, created_at, updated_at)
+            values ($1, $2, $3, $3)
+            returning {}",
+            USER_COLUMNS
+        ))
+        .bind(email)
+        .bind(email_verified.then_some(now).map(timestamp))
+        .bind(timestamp(now))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "insert into user_identities (issuer, subject, account_id, created_at, last_login_at)
+            values ($1, $2, $3, $4, $4)",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(user.id.0)
+        .bind(timestamp(now))
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    async fn get_totp_secret(&self, account_id: &AccountId) -> Result<Option<String>, sqlx::Error> {
+        match sqlx::query_scalar("select totp_secret from users where id=$1")
+            .bind(account_id.0)
+            .fetch_one(&self.connection)
+            .await
+        {
+            Ok(secret) => Ok(secret),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_totp_secret(
+        &self,
+        account_id: &AccountId,
+        secret: &str,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update users set totp_secret=$2, totp_last_step=null, updated_at=$3
+            where id=$1 and totp_enabled_at is null",
+        )
+        .bind(account_id.0)
+        .bind(secret)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn enable_totp(
+        &self,
+        account_id: &AccountId,
+        step: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+        let now = Utc::now();
+
+        let enabled = sqlx::query(
+            "update users set totp_enabled_at=$3, totp_last_step=$2, updated_at=$3
+            where id=$1 and totp_secret is not null and totp_enabled_at is null",
+        )
+        .bind(account_id.0)
+        .bind(step)
+        .bind(timestamp(now))
+        .execute(&mut *tx)
+        .await?;
+
+        if enabled.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query("delete from recovery_codes where account_id=$1")
+            .bind(account_id.0)
+            .execute(&mut *tx)
+            .await?;
+
+        for code_hash in code_hashes {
+            sqlx::query(
+                "insert into recovery_codes (account_id, code_hash, created_at) values ($1, $2, $3)",
+            )
+            .bind(account_id.0)
+            .bind(code_hash)
+            .bind(timestamp(now))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn disable_totp(&self, account_id: &AccountId) -> Result<(), sqlx::Error> {
+        let mut tx = self.connection.begin().await?;
+
+        sqlx::query(
+            "update users
+            set totp_secret=null, totp_enabled_at=null, totp_last_step=null, updated_at=$2
+            where id=$1",
+        )
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("delete from recovery_codes where account_id=$1")
+            .bind(account_id.0)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    async fn use_totp_step(&self, account_id: &AccountId, step: i64) -> Result<bool, sqlx::Error> {
+        match sqlx::query(
+            "update users set totp_last_step=$2
+            where id=$1 and (totp_last_step is null or totp_last_step < $2)",
+        )
+        .bind(account_id.0)
+        .bind(step)
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn use_recovery_code(
+        &self,
+        account_id: &AccountId,
+        code_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        match sqlx::query(
+            "update recovery_codes set used_at=$3
+            where account_id=$1 and code_hash=$2 and used_at is null",
+        )
+        .bind(account_id.0)
+        .bind(code_hash)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_login_challenge(
+        &self,
+        token_hash: &str,
+        account_id: &AccountId,
+        user_agent: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "insert into login_challenges (token_hash, account_id, user_agent, created_at, expires_at)
+            values ($1, $2, $3, $4, $5)",
+        )
+        .bind(token_hash)
+        .bind(account_id.0)
+        .bind(user_agent)
+        .bind(timestamp(Utc::now()))
+        .bind(timestamp(expires_at))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_login_challenge(
+        &self,
+        token_hash: &str,
+        max_failed_attempts: i32,
+    ) -> Result<(AccountId, Option<String>), sqlx::Error> {
+        match sqlx::query_as(
+            "select account_id, user_agent from login_challenges
+            where token_hash=$1 and used_at is null and expires_at > $3
+            and failed_attempts < $2",
+        )
+        .bind(token_hash)
+        .bind(max_failed_attempts)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok(challenge) => Ok(challenge),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn fail_login_challenge(&self, token_hash: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update login_challenges set failed_attempts=failed_attempts + 1 where token_hash=$1",
+        )
+        .bind(token_hash)
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn complete_login_challenge(&self, token_hash: &str) -> Result<bool, sqlx::Error> {
+        match sqlx::query(
+            "update login_challenges set used_at=$2 where token_hash=$1 and used_at is null",
+        )
+        .bind(token_hash)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() == 1),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn remove_login_failure(&self, key: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update login_failures set failures=failures - 1 where key=$1 and failures > 0",
+        )
+        .bind(key)
+        .execute(&self.connection)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn lock_login(&self, key: &str, until: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        match sqlx::query("update login_failures set failures=0, locked_until=$2 where key=$1")
+            .bind(key)
+            .bind(timestamp(until))
+            .execute(&self.connection)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query("delete from login_failures where key=$1")
+            .bind(key)
+            .execute(&self.connection)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_api_key(
+        &self,
+        account_id: &AccountId,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey, sqlx::Error> {
+        match sqlx::query_as::<_, ApiKeyRow>(
+            "insert into api_keys (account_id, name, prefix, key_hash, scopes, created_at, expires_at)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            returning id, name, prefix, scopes, created_at, last_used_at, expires_at",
+        )
+        .bind(account_id.0)
+        .bind(name)
+        .bind(prefix)
+        .bind(key_hash)
+        .bind(json_array(&scope_names(scopes)))
+        .bind(timestamp(Utc::now()))
+        .bind(expires_at.map(timestamp))
+                .fetch_one(&self.connection)
+        .await
+        {
+            Ok(api_key) => Ok(api_key.into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_api_keys(&self, account_id: &AccountId) -> Result<Vec<ApiKey>, sqlx::Error> {
+        match sqlx::query_as::<_, ApiKeyRow>(
+            "select id, name, prefix, scopes, created_at, last_used_at, expires_at
+            from api_keys where account_id=$1 and revoked_at is null order by id",
+        )
+        .bind(account_id.0)
+        .fetch_all(&self.connection)
+        .await
+        {
+            Ok(api_keys) => Ok(api_keys.into_iter().map(ApiKey::from).collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_api_key(
+        &self,
+        account_id: &AccountId,
+        api_key_id: ApiKeyId,
+    ) -> Result<(), sqlx::Error> {
+        match sqlx::query(
+            "update api_keys set revoked_at=$3
+            where id=$1 and account_id=$2 and revoked_at is null",
+        )
+        .bind(api_key_id.0)
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn revoke_api_keys(&self, account_id: &AccountId) -> Result<u64, sqlx::Error> {
+        match sqlx::query(
+            "update api_keys set revoked_at=$2 where account_id=$1 and revoked_at is null",
+        )
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .execute(&self.connection)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn use_api_key(
+        &self,
+        key_hash: &str,
+    ) -> Result<(ApiKeyId, AccountId, Vec<ApiScope>), sqlx::Error> {
+        match sqlx::query_as::<_, (ApiKeyId, AccountId, String)>(
+            "update api_keys set last_used_at=$2
+            where key_hash=$1 and revoked_at is null
+            and (expires_at is null or expires_at > $2)
+            returning id, account_id, scopes",
+        )
+        .bind(key_hash)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.connection)
+        .await
+        {
+            Ok((api_key_id, account_id, scopes)) => {
+                Ok((api_key_id, account_id, parse_scopes(&scopes)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn begin(&self) -> Result<Box<dyn UnitOfWork>, sqlx::Error> {
+        match self.connection.begin().await {
+            Ok(tx) => Ok(Box::new(SqliteUnitOfWork { tx })),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A transaction, ended by `commit` or `rollback`, or rolled back by sqlx
+/// when dropped. SQLite takes the write lock at the first write, so a
+/// question read here could still be deleted before then; the foreign key
+/// on `answers` turns that into a failed insert rather than an orphan.
+struct SqliteUnitOfWork {
+    tx: Transaction<'static, Sqlite>,
+}
+
+#[allow(clippy::needless_match)]
+#[async_trait]
+impl UnitOfWork for SqliteUnitOfWork {
+    async fn get_question_by_id(&mut self, question_id: i32) -> Result<Question, sqlx::Error> {
+        fetch_question(&mut self.tx, question_id).await
+    }
+
+    async fn add_question(
+        &mut self,
+        new_question: NewQuestion,
+        account_id: &AccountId,
+    ) -> Result<Question, sqlx::Error> {
+        let now = Utc::now();
+
+        let question_id: i32 = sqlx::query_scalar(
+            "insert into questions (title, content, account_id, created_at, updated_at)
+            values ($1, $2, $3, $4, $4) returning id",
+        )
+        .bind(new_question.title)
+        .bind(new_question.content)
+        .bind(account_id.0)
+        .bind(timestamp(now))
+        .fetch_one(&mut *self.tx)
+        .await?;
+
+        set_tags(&mut self.tx, question_id, new_question.tags.as_deref()).await?;
+        fetch_question(&mut self.tx, question_id).await
+    }
+
+    async fn update_question(
+        &mut self,
+        question: Question,
+        question_id: i32,
+    ) -> Result<Question, sqlx::Error> {
+        let result =
+            sqlx::query("update questions set title=$2, content=$3, updated_at=$4 where id=$1")
+                .bind(question_id)
+                .bind(question.title)
+                .bind(question.content)
+                .bind(timestamp(Utc::now()))
+                .execute(&mut *self.tx)
+                .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        set_tags(&mut self.tx, question_id, question.tags.as_deref()).await?;
+        fetch_question(&mut self.tx, question_id).await
+    }
+
+    async fn delete_question(&mut self, question_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query("delete from questions where id=$1")
+            .bind(question_id)
+            .execute(&mut *self.tx)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_answer_by_id(&mut self, answer_id: i32) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as::<_, Answer>(&format!(
+            "select {} from answers where id=$1",
+            ANSWER_COLUMNS
+        ))
+        .bind(answer_id)
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_answer(
+        &mut self,
+        question_id: i32,
+        answer: NewAnswer,
+        account_id: &AccountId,
+    ) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as::<_, Answer>(&format!(
+            "insert into answers (content, question_id, account_id, created_at, updated_at)
+            values ($1, $2, $3, $4, $4) returning {}",
+            ANSWER_COLUMNS
+        ))
+        .bind(answer.content)
+        .bind(question_id)
+        .bind(account_id.0)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_answer(
+        &mut self,
+        answer: Answer,
+        answer_id: i32,
+    ) -> Result<Answer, sqlx::Error> {
+        match sqlx::query_as::<_, Answer>(&format!(
+            "update answers set content=$2, updated_at=$3 where id=$1 returning {}",
+            ANSWER_COLUMNS
+        ))
+        .bind(answer_id)
+        .bind(answer.content)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&mut *self.tx)
+        .await
+        {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete_answer(&mut self, answer_id: i32) -> Result<(), sqlx::Error> {
+        match sqlx::query("delete from answers where id=$1")
+            .bind(answer_id)
+            .execute(&mut *self.tx)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn queue_for_review(
+        &mut self,
+        item_type: &str,
+        item_id: i32,
+        flags: Vec<Flag>,
+    ) -> Result<(), sqlx::Error> {
+        for flag in flags {
+            sqlx::query(
+                "insert into review_queue (item_type, item_id, field, matched_words, created_at)
+                values ($1, $2, $3, $4, $5)",
+            )
+            .bind(item_type)
+            .bind(item_id)
+            .bind(flag.field.as_str())
+            .bind(json_array(&flag.matched_words))
+            .bind(timestamp(Utc::now()))
+            .execute(&mut *self.tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes before reading, so the transaction holds SQLite's write lock
+    /// from the start and no other login attempt can count in between.
+    async fn get_login_failures(
+        &mut self,
+        keys: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<LoginFailures>, sqlx::Error> {
+        let now = Utc::now();
+
+        sqlx::query(
+            "insert into login_failures (key, failures, last_failure_at)
+            select value, 0, $2 from json_each($1) where true
+            on conflict (key) do nothing",
+        )
+        .bind(json_array(keys))
+        .bind(timestamp(now))
+        .execute(&mut *self.tx)
+        .await?;
+
+        match sqlx::query_as::<_, LoginFailures>(
+            "select key, failures, last_failure_at, locked_until from login_failures
+            where key in (select value from json_each($1))
+            and (last_failure_at > $2 or locked_until > $3)",
+        )
+        .bind(json_array(keys))
+        .bind(timestamp(since))
+        .bind(timestamp(now))
+        .fetch_all(&mut *self.tx)
+        .await
+        {
+            Ok(failures) => Ok(failures),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn add_login_failure(
+        &mut self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<i32, sqlx::Error> {
+        let now = Utc::now();
+
+        let failures = sqlx::query_scalar(
+            "insert into login_failures (key, failures, last_failure_at)
+            values ($1, 1, $3)
+            on conflict (key) do update set
+                failures = case
+                    when login_failures.last_failure_at > $2 then login_failures.failures + 1
+                    else 1
+                end,
+                last_failure_at = $3
+            returning failures",
+        )
+        .bind(key)
+        .bind(timestamp(since))
+        .bind(timestamp(now))
+        .fetch_one(&mut *self.tx)
+        .await?;
+
+        sqlx::query(
+            "delete from login_failures
+            where key <> $1 and last_failure_at <= $2
+            and (locked_until is null or locked_until <= $3)",
+        )
+        .bind(key)
+        .bind(timestamp(since))
+        .bind(timestamp(now))
+        .execute(&mut *self.tx)
+        .await?;
+
+        Ok(failures)
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+}
+
+/// Replaces the question's tags. `None` and an empty list are stored alike,
+/// as no tags.
+async fn set_tags(
+    connection: &mut SqliteConnection,
+    question_id: i32,
+    tags: Option<&[String]>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("delete from question_tags where question_id=$1")
+        .bind(question_id)
+        .execute(&mut *connection)
+        .await?;
+
+    for (position, tag) in tags.unwrap_or_default().iter().enumerate() {
+        sqlx::query("insert into question_tags (question_id, position, tag) values ($1, $2, $3)")
+            .bind(question_id)
+            .bind(position as i32)
+            .bind(tag)
+            .execute(&mut *connection)
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn fetch_question(
+    connection: &mut SqliteConnection,
+    question_id: i32,
+) -> Result<Question, sqlx::Error> {
+    sqlx::query_as::<_, QuestionRow>(&format!(
+        "select {} from questions where id=$1",
+        QUESTION_COLUMNS
+    ))
+    .bind(question_id)
+    .fetch_one(connection)
+    .await
+    .map(Question::from)
+}
+
+async fn audit_role(
+    connection: &mut SqliteConnection,
+    actor: &AccountId,
+    account_id: i32,
+    role: &str,
+    action: &str,
+    at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "insert into role_audit_log (actor_id, account_id, role, action, created_at)
+        values ($1, $2, $3, $4, $5)",
+    )
+    .bind(actor.0)
+    .bind(account_id)
+    .bind(role)
+    .bind(action)
+    .bind(timestamp(at))
+    .execute(connection)
+    .await
+    .map(|_| ())
+}
+
+fn link_token_table(purpose: LinkPurpose) -> &'static str {
+    match purpose {
+        LinkPurpose::VerifyEmail => "email_verification_tokens",
+        LinkPurpose::ResetPassword => "password_reset_tokens",
+    }
+}
+
+/// Builds a page query over `table`, like the Postgres one. `$1` to `$6` are
+/// bound by `bind_list_query`; an extra `condition` may use parameters from
+/// `$7` on. Only ever interpolates column names from `Sort`, never user
+/// input.
+fn list_query(columns: &str, table: &str, condition: Option<&str>, sort: Sort) -> String {
+    let (comparison, direction) = if sort.is_descending() {
+        ("<", "desc")
+    } else {
+        (">", "asc")
+    };
+
+    format!(
+        "select {columns}, {sort} as sort_key from {table}
+        where ($3 is null or created_at >= $3)
+        and ($4 is null or created_at < $4)
+        and ($5 is null or ({sort}, id) {comparison} ($5, $6))
+        {condition}
+        order by {sort} {direction}, id {direction}
+        limit $1 offset $2",
+        columns = columns,
+        table = table,
+        sort = sort.column(),
+        comparison = comparison,
+        direction = direction,
+        condition = condition
+            .map(|condition| format!("and {}", condition))
+            .unwrap_or_default(),
+    )
+}
+
+fn bind_list_query<'q, O>(
+    query: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+    pagination: &Pagination,
+) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+    query
+        .bind(pagination.fetch_limit())
+        .bind(pagination.offset)
+        .bind(pagination.since.map(timestamp))
+        .bind(pagination.until.map(timestamp))
+        .bind(pagination.after.map(|cursor| timestamp(cursor.at)))
+        .bind(pagination.after.map(|cursor| cursor.id))
+}
+
+/// How timestamps are stored: UTC with exactly six decimals and a `Z`, e.g.
+/// `2024-01-03T09:00:00.123456Z`. Fixed width, so they compare correctly as
+/// text, and cut to the microseconds Postgres keeps and cursors carry.
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn json_array(items: &[String]) -> String {
+    serde_json::Value::from(items).to_string()
+}
+
+fn from_json_array(json: &str) -> Vec<String> {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+/// Skips scopes this version doesn't know, e.g. ones since removed.
+fn parse_scopes(json: &str) -> Vec<ApiScope> {
+    from_json_array(json)
+        .iter()
+        .filter_map(|scope| ApiScope::parse(scope))
+        .collect()
+}
+
+fn scope_names(scopes: &[ApiScope]) -> Vec<String> {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect()
+}
+
+/// A question as stored: tags come as a JSON array, and the timestamps are
+/// always set.
+#[derive(sqlx::FromRow)]
+struct QuestionRow {
+    id: QuestionId,
+    title: String,
+    content: String,
+    tags: String,
+    account_id: Option<AccountId>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    closed_at: Option<DateTime<Utc>>,
+}
+
+impl From<QuestionRow> for Question {
+    fn from(row: QuestionRow) -> Self {
+        let tags = from_json_array(&row.tags);
+
+        Question {
+            id: row.id,
+            title: row.title,
+            content: row.content,
+            tags: (!tags.is_empty()).then_some(tags),
+            account_id: row.account_id,
+            created_at: Some(row.created_at),
+            updated_at: Some(row.updated_at),
+            closed_at: row.closed_at,
+        }
+    }
+}
+
+/// An API key as stored, with its scopes as a JSON array.
+#[derive(sqlx::FromRow)]
+struct ApiKeyRow {
+    id: ApiKeyId,
+    name: String,
+    prefix: String,
+    scopes: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: parse_scopes(&row.scopes),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+/// A row of a page from `list_query`: the item, and the `sort_key` and `id`
+/// its cursor is made of.
+#[derive(sqlx::FromRow)]
+struct Listed<T> {
+    #[sqlx(flatten)]
+    item: T,
+    sort_key: DateTime<Utc>,
+    id: i32,
+}
+
+impl<T> Listed<T> {
+    fn into_page_item<U: From<T>>(self, sort: Sort) -> (U, Cursor) {
+        (
+            self.item.into(),
+            Cursor {
+                sort,
+                at: self.sort_key,
+                id: self.id,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::{auth::LoginThrottle, config::Config, error::Error, routes::testing::TestApp};
+
+    fn db_url(dir: &TempDir) -> String {
+        format!("sqlite://{}", dir.path().join("q-and-a.db").display())
+    }
+
+    /// An app on a new database file, which goes away with the `TempDir`,
+    /// and a pool for looking at the tables directly.
+    async fn app() -> (TempDir, TestApp, SqlitePool) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::connect(&db_url(&dir), 5).await.unwrap();
+        let connection = store.connection.clone();
+        let app = TestApp {
+            config: Config::for_tests(),
+            store: Arc::new(store),
+        };
+
+        (dir, app, connection)
+    }
+
+    async fn add_question(app: &TestApp, authorization: &str, title: &str, tags: &[&str]) {
+        let (status, body) = app
+            .request(
+                "POST",
+                "/questions",
+                Some(authorization),
+                Some(json!({ "title": title, "content": "content", "tags": tags })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn grants_the_first_admin_as_the_schema_says() {
+        let (_dir, app, connection) = app().await;
+        let (alice, _) = app.user("alice@example.com").await;
+
+        // The example in the migration, run as written
+        let schema = include_str!("../../migrations/sqlite/20240101090000_initial.up.sql");
+        let example: String = schema
+            .lines()
+            .skip_while(|line| !line.starts_with("-- The first admin"))
+            .skip(1)
+            .take_while(|line| line.starts_with("--   "))
+            .map(|line| line.trim_start_matches("--   "))
+            .collect::<Vec<_>>()
+            .join(" ");
+        sqlx::query(&example.replace("values (1,", &format!("values ({},", alice)))
+            .execute(&connection)
+            .await
+            .unwrap();
+
+        let granted_at: String =
+            sqlx::query_scalar("select granted_at from user_roles where account_id=$1")
+                .bind(alice.0)
+                .fetch_one(&connection)
+                .await
+                .unwrap();
+        let parsed: DateTime<Utc> = granted_at.parse().unwrap();
+        assert_eq!(granted_at, timestamp(parsed));
+
+        let roles = app.store.get_roles(alice.0).await.unwrap();
+        assert_eq!(roles[0].name, "admin");
+        assert_eq!(roles[0].granted_at, parsed);
+    }
+
+    #[tokio::test]
+    async fn keeps_questions_answers_and_their_tags() {
+        let (_dir, app, connection) = app().await;
+        let (_, authorization) = app.user("alice@example.com").await;
+        add_question(&app, &authorization, "title", &["rust", "sqlite"]).await;
+
+        let (_, body) = app.request("GET", "/questions/1", None, None).await;
+        assert_eq!(body["data"]["Question"]["tags"], json!(["rust", "sqlite"]));
+
+        let (status, body) = app
+            .request(
+                "PUT",
+                "/questions/1",
+                Some(&authorization),
+                Some(json!({
+                    "id": 1,
+                    "title": "new title",
+                    "content": "content",
+                    "tags": ["sqlite", "databases", "rust"],
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(
+            body["data"]["Question"]["tags"],
+            json!(["sqlite", "databases", "rust"])
+        );
+
+        let (status, _) = app
+            .request(
+                "POST",
+                "/answers",
+                Some(&authorization),
+                Some(json!({ "question_id": 1, "content": "answer" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, problem) = app
+            .request("DELETE", "/questions/1", Some(&authorization), None)
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "invalid_reference");
+
+        for path in ["/answer/1", "/questions/1"] {
+            let (status, _) = app
+                .request("DELETE", path, Some(&authorization), None)
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+        }
+
+        let (status, _) = app.request("GET", "/questions/1", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let tags: i64 = sqlx::query_scalar("select count(*) from question_tags")
+            .fetch_one(&connection)
+            .await
+            .unwrap();
+        assert_eq!(tags, 0);
+    }
+
+    #[tokio::test]
+    async fn pages_to_the_end_in_every_order() {
+        let (_dir, app, _) = app().await;
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        for title in ["first", "second", "third", "fourth"] {
+            add_question(&app, &authorization, title, &[]).await;
+        }
+
+        for (sort, expected) in [
+            ("created_at", [1, 2, 3, 4]),
+            ("-created_at", [4, 3, 2, 1]),
+            ("updated_at", [1, 2, 3, 4]),
+            ("-updated_at", [4, 3, 2, 1]),
+        ] {
+            let path = format!("/questions?limit=1&sort={}", sort);
+
+            assert_eq!(app.page_through(&path, "Questions").await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_login_attempts_in_one_step() {
+        let (_dir, app, _) = app().await;
+        let mut config = app.config.login_throttle.clone();
+        config.base_delay_secs = 5;
+        config.max_delay_secs = 5;
+        let throttle = LoginThrottle::new(&config, app.store.clone());
+
+        let first = throttle.reserve("alice@example.com", None).await.unwrap();
+
+        assert!(matches!(
+            throttle.reserve("alice@example.com", None).await,
+            Err(Error::TooManyAttempts(5))
+        ));
+
+        throttle.record_success(first).await.unwrap();
+
+        assert!(throttle.reserve("alice@example.com", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn marks_the_current_session() {
+        let (_dir, app, _) = app().await;
+        let (alice, _) = app.user("alice@example.com").await;
+        let expires_at = Utc::now() + chrono::Duration::hours(1);
+
+        let first = app
+            .store
+            .add_session(&alice, "first", None, expires_at, false)
+            .await
+            .unwrap();
+        let second = app
+            .store
+            .add_session(&alice, "second", None, expires_at, false)
+            .await
+            .unwrap();
+
+        let sessions = app.store.get_active_sessions(&alice, second).await.unwrap();
+        let current: Vec<_> = sessions
+            .iter()
+            .map(|session| (session.id, session.current))
+            .collect();
+        assert!(current.contains(&(first, false)));
+        assert!(current.contains(&(second, true)));
+        assert_eq!(current.iter().filter(|(_, current)| *current).count(), 1);
+    }
+}
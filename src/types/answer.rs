@@ -1,15 +1,26 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::question::QuestionId;
+use super::{question::QuestionId, user::AccountId};
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone, sqlx::Type)]
+#[sqlx(transparent)]
 pub struct AnswerId(pub i32);
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 pub struct Answer {
     pub id: AnswerId,
     pub content: String,
     pub question_id: QuestionId,
+    /// Author, set by the store; `None` for rows written before accounts
+    /// existed
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+    /// Set by the store; ignored when sent in a request body
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -0,0 +1,75 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// What an API key may be used for. A key can never do more than its
+/// account could, and never anything that needs a permission, since those
+/// need a login with two-factor authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiScope {
+    /// Add, update and delete questions
+    #[serde(rename = "questions.write")]
+    QuestionsWrite,
+    /// Add, update and delete answers
+    #[serde(rename = "answers.write")]
+    AnswersWrite,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::QuestionsWrite => "questions.write",
+            ApiScope::AnswersWrite => "answers.write",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [ApiScope::QuestionsWrite, ApiScope::AnswersWrite]
+            .into_iter()
+            .find(|scope| scope.as_str() == value)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone, Copy, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct ApiKeyId(pub i32);
+
+impl Display for ApiKeyId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An API key that hasn't been revoked. The key itself is only shown when
+/// it is created; `prefix` is its first characters, to tell keys apart.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: ApiKeyId,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// `None` for keys that don't expire
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Body of `POST /account/api-keys`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewApiKey {
+    /// What the key is for, e.g. the tool using it
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once when a key is created. Send the key in the `X-Api-Key`
+/// header.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
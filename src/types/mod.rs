@@ -1,4 +1,10 @@
 pub mod answer;
+pub mod api_key;
 pub mod pagination;
+pub mod problem;
 pub mod question;
 pub mod response;
+pub mod role;
+pub mod session;
+pub mod totp;
+pub mod user;
@@ -1,41 +1,564 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-use crate::error::Error;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, SubsecRound, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use warp::{Filter, Rejection};
 
-#[derive(Debug)]
+use crate::{config::PaginationConfig, error::Error};
+
+/// Order of a list endpoint, from the `sort` query parameter: `created_at`
+/// (the default) or `updated_at`, prefixed with `-` for newest first. Ties
+/// are broken by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sort {
+    #[default]
+    CreatedAsc,
+    CreatedDesc,
+    UpdatedAsc,
+    UpdatedDesc,
+}
+
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::CreatedAsc => "created_at",
+            Sort::CreatedDesc => "-created_at",
+            Sort::UpdatedAsc => "updated_at",
+            Sort::UpdatedDesc => "-updated_at",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [
+            Sort::CreatedAsc,
+            Sort::CreatedDesc,
+            Sort::UpdatedAsc,
+            Sort::UpdatedDesc,
+        ]
+        .into_iter()
+        .find(|sort| sort.as_str() == value)
+    }
+
+    /// The timestamp column rows are ordered by.
+    pub fn column(&self) -> &'static str {
+        match self {
+            Sort::CreatedAsc | Sort::CreatedDesc => "created_at",
+            Sort::UpdatedAsc | Sort::UpdatedDesc => "updated_at",
+        }
+    }
+
+    pub fn is_descending(&self) -> bool {
+        matches!(self, Sort::CreatedDesc | Sort::UpdatedDesc)
+    }
+}
+
+/// Position of a row in the `(sort column, id)` ordering of a list endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub sort: Sort,
+    pub at: DateTime<Utc>,
+    pub id: i32,
+}
+
+impl Cursor {
+    /// Where a row with these timestamps sits when sorted by `sort`. Only
+    /// microseconds are kept, as in a token, so a row compares the same
+    /// against a decoded cursor as it did when the cursor was issued.
+    pub fn new(
+        sort: Sort,
+        id: i32,
+        created_at: Option<DateTime<Utc>>,
+        updated_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        let at = match sort {
+            Sort::CreatedAsc | Sort::CreatedDesc => created_at,
+            Sort::UpdatedAsc | Sort::UpdatedDesc => updated_at,
+        };
+
+        Cursor {
+            sort,
+            at: at.unwrap_or_default().trunc_subsecs(6),
+            id,
+        }
+    }
+}
+
+/// Turns cursors into opaque tokens and back. Tokens are signed so clients
+/// can't forge positions.
+#[derive(Clone)]
+pub struct CursorSigner {
+    secret: Arc<[u8]>,
+}
+
+impl CursorSigner {
+    pub fn new(secret: &str) -> Self {
+        CursorSigner {
+            secret: secret.as_bytes().into(),
+        }
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts keys of any size");
+        mac.update(payload);
+        mac
+    }
+
+    pub fn encode(&self, cursor: &Cursor) -> String {
+        let payload = format!(
+            "{}:{}:{}",
+            cursor.sort.as_str(),
+            cursor.at.timestamp_micros(),
+            cursor.id
+        );
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    pub fn decode(&self, token: &str) -> Result<Cursor, Error> {
+        let invalid = || Error::InvalidCursor("malformed or tampered cursor".to_string());
+
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+
+        self.mac(&payload)
+            .verify_slice(&signature)
+            .map_err(|_| invalid())?;
+
+        let payload = String::from_utf8(payload).map_err(|_| invalid())?;
+        let mut parts = payload.splitn(3, ':');
+
+        let (sort, micros, id) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(sort), Some(micros), Some(id)) => (sort, micros, id),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Cursor {
+            sort: Sort::parse(sort).ok_or_else(invalid)?,
+            at: micros
+                .parse::<i64>()
+                .ok()
+                .and_then(DateTime::from_timestamp_micros)
+                .ok_or_else(invalid)?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct Pagination {
-    pub limit: Option<i32>,
+    pub limit: i32,
     pub offset: i32,
+    /// Only return rows after this one
+    pub after: Option<Cursor>,
+    pub sort: Sort,
+    /// Only return rows created at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only return rows created before this time
+    pub until: Option<DateTime<Utc>>,
+    signer: CursorSigner,
 }
 
-impl Default for Pagination {
-    fn default() -> Self {
-        Pagination {
-            limit: None,
-            offset: 0,
+/// Paging details returned alongside a page of results.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page {
+    pub total: i64,
+    pub limit: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i32>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+impl Pagination {
+    /// The number of rows to fetch: one more than the page size, so we can
+    /// tell whether there is a next page.
+    pub fn fetch_limit(&self) -> i32 {
+        self.limit + 1
+    }
+
+    /// Drops the extra row fetched by `fetch_limit`, returning the page and
+    /// the cursor token for the next page if there is one.
+    pub fn split<T>(&self, mut rows: Vec<(T, Cursor)>) -> (Vec<T>, Option<String>) {
+        let has_more = rows.len() > self.limit as usize;
+        rows.truncate(self.limit as usize);
+
+        let next_cursor = if has_more {
+            rows.last().map(|(_, cursor)| self.signer.encode(cursor))
+        } else {
+            None
+        };
+
+        (
+            rows.into_iter().map(|(item, _)| item).collect(),
+            next_cursor,
+        )
+    }
+
+    /// Query string for links to other pages, carrying over everything but
+    /// the position.
+    fn link(&self, path: &str, position: &str) -> String {
+        let mut link = format!("{}?limit={}&{}", path, self.limit, position);
+
+        if self.sort != Sort::default() {
+            link.push_str(&format!("&sort={}", self.sort.as_str()));
+        }
+
+        if let Some(since) = self.since {
+            link.push_str(&format!("&since={}", timestamp_param(&since)));
+        }
+
+        if let Some(until) = self.until {
+            link.push_str(&format!("&until={}", timestamp_param(&until)));
         }
+
+        link
     }
+
+    /// Describes this page of a `total` item collection served at `path`.
+    pub fn page(&self, path: &str, total: i64, next_cursor: Option<&str>) -> Page {
+        if self.after.is_some() {
+            return Page {
+                total,
+                limit: self.limit,
+                offset: None,
+                next: next_cursor.map(|cursor| self.link(path, &format!("cursor={}", cursor))),
+                prev: None,
+            };
+        }
+
+        let next_offset = self.offset as i64 + self.limit as i64;
+
+        Page {
+            total,
+            limit: self.limit,
+            offset: Some(self.offset),
+            next: (next_offset < total)
+                .then(|| self.link(path, &format!("offset={}", next_offset))),
+            prev: (self.offset > 0).then(|| {
+                self.link(
+                    path,
+                    &format!("offset={}", (self.offset - self.limit).max(0)),
+                )
+            }),
+        }
+    }
+}
+
+/// RFC 3339 in UTC with a `Z` suffix, so it needs no escaping in a link.
+fn timestamp_param(at: &DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
 }
 
-pub fn extract_pagination(params: HashMap<String, String>) -> Result<Pagination, Error> {
-    if params.contains_key("start") && params.contains_key("end") {
-        return Ok(Pagination {
-            limit: Some(
-                params
-                    .get("limit")
-                    .unwrap()
-                    .parse::<i32>()
-                    .map_err(Error::Parse)?,
-            ),
-            offset: params
-                .get("offset")
-                .unwrap()
-                .parse::<i32>()
-                .map_err(Error::Parse)?,
-        });
+fn parse_timestamp(
+    params: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    params
+        .get(key)
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|at| at.with_timezone(&Utc))
+                .map_err(|_| Error::InvalidTimestamp(key.to_string()))
+        })
+        .transpose()
+}
+
+fn parse(params: &HashMap<String, String>, key: &str) -> Result<Option<i32>, Error> {
+    params
+        .get(key)
+        .map(|value| value.parse::<i32>().map_err(Error::Parse))
+        .transpose()
+}
+
+/// Reads `limit` with either `offset`, an opaque `cursor` from a previous
+/// response, or the legacy `start` and `end` item indices (`end` exclusive),
+/// along with the `sort` order and the `since`/`until` creation time filters.
+pub fn extract_pagination(
+    params: HashMap<String, String>,
+    config: &PaginationConfig,
+    signer: &CursorSigner,
+) -> Result<Pagination, Error> {
+    let (limit, offset) = match (parse(&params, "start")?, parse(&params, "end")?) {
+        (Some(start), Some(end)) => {
+            if start < 0 {
+                return Err(Error::OutOfRange("start must not be negative".to_string()));
+            }
+
+            let limit = end
+                .checked_sub(start)
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| Error::OutOfRange("end must be greater than start".to_string()))?;
+
+            (Some(limit), Some(start))
+        }
+        (None, None) => (parse(&params, "limit")?, parse(&params, "offset")?),
+        _ => {
+            return Err(Error::MissingParameters(
+                "start and end must be given together".to_string(),
+            ))
+        }
+    };
+
+    let sort = match params.get("sort") {
+        Some(value) => Sort::parse(value).ok_or_else(|| {
+            Error::OutOfRange(
+                "sort must be one of created_at, -created_at, updated_at, -updated_at".to_string(),
+            )
+        })?,
+        None => Sort::default(),
+    };
+
+    let after = match params.get("cursor") {
+        Some(_) if offset.is_some() => {
+            return Err(Error::InvalidCursor(
+                "cursor can't be combined with offset".to_string(),
+            ))
+        }
+        Some(token) => Some(signer.decode(token)?),
+        None => None,
     };
 
-    Err(Error::MissingParameters(
-        "start and/or end parameters missing".to_string(),
-    ))
+    if after.is_some_and(|cursor| cursor.sort != sort) {
+        return Err(Error::InvalidCursor(
+            "cursor was issued for a different sort order".to_string(),
+        ));
+    }
+
+    let since = parse_timestamp(&params, "since")?;
+    let until = parse_timestamp(&params, "until")?;
+
+    if let (Some(since), Some(until)) = (since, until) {
+        if until <= since {
+            return Err(Error::OutOfRange("until must be after since".to_string()));
+        }
+    }
+
+    let limit = limit.unwrap_or(config.default_page_size);
+    let offset = offset.unwrap_or(0);
+
+    if limit < 1 || limit > config.max_page_size {
+        return Err(Error::OutOfRange(format!(
+            "limit must be between 1 and {}",
+            config.max_page_size
+        )));
+    }
+
+    if offset < 0 {
+        return Err(Error::OutOfRange("offset must not be negative".to_string()));
+    }
+
+    Ok(Pagination {
+        limit,
+        offset,
+        after,
+        sort,
+        since,
+        until,
+        signer: signer.clone(),
+    })
+}
+
+/// Extracts the pagination for a list endpoint from the query string.
+pub fn pagination(
+    config: PaginationConfig,
+) -> impl Filter<Extract = (Pagination,), Error = Rejection> + Clone {
+    let signer = CursorSigner::new(&config.cursor_secret);
+
+    warp::query::<HashMap<String, String>>().and_then(move |params| {
+        let result = extract_pagination(params, &config, &signer);
+        async move { result.map_err(warp::reject::custom) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PaginationConfig {
+        PaginationConfig {
+            default_page_size: 20,
+            max_page_size: 100,
+            cursor_secret: "test-cursor-secret-0123456789abcdef".to_string(),
+        }
+    }
+
+    fn extract(query: &[(&str, &str)]) -> Result<Pagination, Error> {
+        let params = query
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        extract_pagination(
+            params,
+            &config(),
+            &CursorSigner::new(&config().cursor_secret),
+        )
+    }
+
+    #[test]
+    fn defaults_to_first_page() {
+        let pagination = extract(&[]).unwrap();
+
+        assert_eq!((pagination.limit, pagination.offset), (20, 0));
+    }
+
+    #[test]
+    fn start_and_end_become_limit_and_offset() {
+        let pagination = extract(&[("start", "10"), ("end", "15")]).unwrap();
+
+        assert_eq!((pagination.limit, pagination.offset), (5, 10));
+    }
+
+    #[test]
+    fn names_the_bad_timestamp() {
+        let Err(error) = extract(&[("since", "2024-01-01T00:00:00Z"), ("until", "yesterday")])
+        else {
+            panic!("accepted a bad timestamp");
+        };
+
+        assert_eq!(error.to_string(), "until must be an RFC 3339 timestamp");
+    }
+
+    #[test]
+    fn rejects_bad_start_and_end() {
+        for query in [
+            [("start", "5"), ("end", "5")],
+            [("start", "5"), ("end", "1")],
+            [("start", "-1"), ("end", "5")],
+            [("start", "-2147483648"), ("end", "1")],
+            [("start", "0"), ("end", "2147483647")],
+        ] {
+            assert!(
+                matches!(extract(&query), Err(Error::OutOfRange(_))),
+                "{:?}",
+                query
+            );
+        }
+
+        assert!(matches!(
+            extract(&[("start", "1")]),
+            Err(Error::MissingParameters(_))
+        ));
+        assert!(matches!(
+            extract(&[("start", "a"), ("end", "1")]),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn limits_page_size() {
+        assert_eq!(extract(&[("limit", "100")]).unwrap().limit, 100);
+
+        for limit in ["0", "-1", "101"] {
+            assert!(matches!(
+                extract(&[("limit", limit)]),
+                Err(Error::OutOfRange(_))
+            ));
+        }
+
+        assert!(matches!(
+            extract(&[("start", "0"), ("end", "101")]),
+            Err(Error::OutOfRange(_))
+        ));
+        assert!(matches!(
+            extract(&[("offset", "-1")]),
+            Err(Error::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn links_to_neighbouring_pages() {
+        let pagination = extract(&[("limit", "10"), ("offset", "5")]).unwrap();
+        let page = pagination.page("/questions", 30, None);
+
+        assert_eq!(page.next.as_deref(), Some("/questions?limit=10&offset=15"));
+        assert_eq!(page.prev.as_deref(), Some("/questions?limit=10&offset=0"));
+
+        let page = pagination.page("/questions", 15, None);
+
+        assert_eq!(page.next, None);
+    }
+
+    fn cursor(sort: Sort) -> Cursor {
+        Cursor {
+            sort,
+            at: DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+            id: 42,
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let signer = CursorSigner::new(&config().cursor_secret);
+
+        for sort in [
+            Sort::CreatedAsc,
+            Sort::CreatedDesc,
+            Sort::UpdatedAsc,
+            Sort::UpdatedDesc,
+        ] {
+            let token = signer.encode(&cursor(sort));
+
+            assert_eq!(signer.decode(&token).unwrap(), cursor(sort));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_cursors() {
+        let signer = CursorSigner::new(&config().cursor_secret);
+        let token = signer.encode(&cursor(Sort::CreatedAsc));
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        let forged = URL_SAFE_NO_PAD.encode("created_at:0:1");
+        let other_key = CursorSigner::new("another-secret-0123456789abcdefghij");
+
+        for token in [
+            format!("{}.{}", forged, signature),
+            format!("{}.{}", payload, URL_SAFE_NO_PAD.encode([0u8; 32])),
+            other_key.encode(&cursor(Sort::CreatedAsc)),
+            payload.to_string(),
+            "not a cursor".to_string(),
+        ] {
+            assert!(
+                matches!(signer.decode(&token), Err(Error::InvalidCursor(_))),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn cursors_only_work_with_their_sort() {
+        let signer = CursorSigner::new(&config().cursor_secret);
+        let token = signer.encode(&cursor(Sort::CreatedDesc));
+
+        let pagination = extract(&[("cursor", &token), ("sort", "-created_at")]).unwrap();
+        assert_eq!(pagination.after, Some(cursor(Sort::CreatedDesc)));
+
+        for sort in ["created_at", "-updated_at"] {
+            assert!(matches!(
+                extract(&[("cursor", &token), ("sort", sort)]),
+                Err(Error::InvalidCursor(_))
+            ));
+        }
+
+        assert!(matches!(
+            extract(&[("cursor", &token)]),
+            Err(Error::InvalidCursor(_))
+        ));
+        assert!(matches!(
+            extract(&[("cursor", &token), ("sort", "-created_at"), ("offset", "1")]),
+            Err(Error::InvalidCursor(_))
+        ));
+    }
 }
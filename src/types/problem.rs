@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+
+/// A single invalid field in a request body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+    /// JSON pointer (RFC 6901) to the offending field, e.g. `/tags/0`
+    pub pointer: String,
+    pub reason: String,
+}
+
+/// An `application/problem+json` error body as described in RFC 7807.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// The id of the request that failed
+    pub instance: String,
+    /// Stable machine-readable error code, see `Error::code`
+    pub code: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, detail: String, instance: String) -> Self {
+        Problem {
+            problem_type: format!("/problems/{}", code.replace('_', "-")),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            instance,
+            code: code.to_string(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn with_errors(self, errors: Vec<FieldError>) -> Self {
+        Problem { errors, ..self }
+    }
+}
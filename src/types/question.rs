@@ -1,8 +1,12 @@
 use std::fmt::Display;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone)]
+use super::user::AccountId;
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone, sqlx::Type)]
+#[sqlx(transparent)]
 pub struct QuestionId(pub i32);
 
 impl Display for QuestionId {
@@ -17,6 +21,19 @@ pub struct Question {
     pub title: String,
     pub content: String,
     pub tags: Option<Vec<String>>,
+    /// Author, set by the store; `None` for rows written before accounts
+    /// existed
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+    /// Set by the store; ignored when sent in a request body
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Set by the store when a moderator closes the question, which then
+    /// takes no new answers
+    #[serde(default)]
+    pub closed_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -1,6 +1,15 @@
 use serde::{Deserialize, Serialize};
 
-use super::{answer::Answer, question::Question};
+use super::{
+    answer::Answer,
+    api_key::{ApiKey, CreatedApiKey},
+    pagination::Page,
+    question::Question,
+    role::{Role, RoleAudit},
+    session::ActiveSession,
+    totp::{LoginChallenge, TotpEnrollment},
+    user::{AccessToken, User},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResponseType {
@@ -8,6 +17,18 @@ pub enum ResponseType {
     Question(Question),
     Answers(Vec<Answer>),
     Answer(Answer),
+    User(User),
+    Token(AccessToken),
+    Roles(Vec<Role>),
+    RoleAudit(Vec<RoleAudit>),
+    Sessions(Vec<ActiveSession>),
+    Challenge(LoginChallenge),
+    TotpEnrollment(TotpEnrollment),
+    /// Shown once, only hashes are kept
+    RecoveryCodes(Vec<String>),
+    /// Shown once, only a hash is kept
+    CreatedApiKey(CreatedApiKey),
+    ApiKeys(Vec<ApiKey>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +36,11 @@ pub struct JsonResponse {
     pub error: bool,
     pub message: Option<String>,
     pub data: Option<ResponseType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<Page>,
+    /// Pass as `cursor` to fetch the page after this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
 impl JsonResponse {
@@ -23,6 +49,16 @@ impl JsonResponse {
             error,
             message,
             data,
+            page: None,
+            next_cursor: None,
+        }
+    }
+
+    pub fn with_page(self, page: Page, next_cursor: Option<String>) -> Self {
+        JsonResponse {
+            page: Some(page),
+            next_cursor,
+            ..self
         }
     }
 }
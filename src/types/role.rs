@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::user::AccountId;
+
+/// Something a role allows. Which roles grant which permissions is kept in
+/// the `role_permissions` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Edit or delete any question or answer
+    ModerateContent,
+    /// Grant and revoke roles, and read the audit log of both
+    ManageRoles,
+    /// Close questions to new answers
+    CloseQuestions,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ModerateContent => "content.moderate",
+            Permission::ManageRoles => "roles.manage",
+            Permission::CloseQuestions => "questions.close",
+        }
+    }
+}
+
+/// A role held by an account.
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Role {
+    pub name: String,
+    /// `None` if granted outside the API, or the granting account is gone
+    pub granted_by: Option<AccountId>,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// Body of `POST /admin/users/{id}/roles`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoleGrant {
+    pub role: String,
+}
+
+/// A grant or revoke, kept after the account or role is gone.
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RoleAudit {
+    pub id: i32,
+    /// `None` if the acting account is gone
+    pub actor_id: Option<AccountId>,
+    pub account_id: AccountId,
+    pub role: String,
+    /// `grant` or `revoke`
+    pub action: String,
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A login that hasn't been logged out, revoked or expired.
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ActiveSession {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the session was last refreshed
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether this is the session of the request
+    pub current: bool,
+}
+
+/// Recent failed logins for one account or client address.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct LoginFailures {
+    pub key: String,
+    /// Failures since the start of the window, reset by a lockout
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
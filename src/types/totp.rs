@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+/// Returned when starting enrollment. Add the secret to an authenticator app,
+/// usually by showing `otpauth_uri` as a QR code, then confirm with a code.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// Returned by `POST /login` instead of tokens when the account has
+/// two-factor authentication on. Pass the token to `POST /login/totp` along
+/// with a code.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoginChallenge {
+    pub challenge_token: String,
+    /// Seconds left to send a code before logging in has to start over
+    pub expires_in: i64,
+}
+
+/// Body of the enrollment confirmation and disable routes. Either a code
+/// from the authenticator or, where the account has them, a recovery code.
+#[derive(Clone, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// Body of `POST /login/totp`.
+#[derive(Clone, Deserialize)]
+pub struct TotpLogin {
+    pub challenge_token: String,
+    pub code: String,
+}
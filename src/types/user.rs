@@ -0,0 +1,109 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Clone, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct AccountId(pub i32);
+
+impl Display for AccountId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct User {
+    pub id: AccountId,
+    pub email: String,
+    /// Argon2 hash in PHC string format, never sent to clients. `None` for
+    /// accounts created through single sign-on.
+    #[serde(skip_serializing, default)]
+    pub password: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the account last proved it owns `email`
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// Set while logging in needs a TOTP code as well
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+}
+
+/// Body of `POST /registration`. Deliberately not `Debug`, so the password
+/// can't end up in a log line.
+#[derive(Clone, Deserialize)]
+pub struct NewUser {
+    pub email: String,
+    pub password: String,
+}
+
+/// Body of `POST /login`.
+#[derive(Clone, Deserialize)]
+pub struct Credentials {
+    pub email: String,
+    pub password: String,
+}
+
+/// Returned by `POST /login` and `POST /sessions/refresh`. Send the access
+/// token as `Authorization: Bearer <access_token>`, and trade the refresh
+/// token for a new pair before it expires.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessToken {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+    /// Single use; refreshing returns a new one
+    pub refresh_token: String,
+}
+
+/// Body of `POST /sessions/refresh`.
+#[derive(Clone, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// What a token mailed to an account lets its holder do. A token issued for
+/// one purpose is never accepted for the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl LinkPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkPurpose::VerifyEmail => "verify_email",
+            LinkPurpose::ResetPassword => "reset_password",
+        }
+    }
+}
+
+/// Body of `POST /verify-email`.
+#[derive(Clone, Deserialize)]
+pub struct EmailVerification {
+    pub token: String,
+}
+
+/// Body of `POST /password-reset`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+/// Body of `POST /password-reset/confirm`.
+#[derive(Clone, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+/// Query string the OpenID Connect provider redirects back to
+/// `GET /oidc/callback` with.
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcCallback {
+    pub state: String,
+    pub code: Option<String>,
+    /// Set instead of `code` when the user or provider cancelled the login
+    pub error: Option<String>,
+}
@@ -0,0 +1,618 @@
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use warp::{Filter, Rejection};
+
+use crate::{
+    error::Error,
+    filters,
+    types::{
+        answer::{Answer, NewAnswer},
+        api_key::NewApiKey,
+        problem::FieldError,
+        question::{NewQuestion, Question},
+        role::RoleGrant,
+        totp::{TotpCode, TotpLogin},
+        user::{
+            Credentials, EmailVerification, NewUser, PasswordReset, PasswordResetRequest,
+            RefreshRequest,
+        },
+    },
+};
+
+/// `questions.title` is a varchar(255)
+pub const MAX_TITLE_LENGTH: usize = 255;
+pub const MAX_CONTENT_LENGTH: usize = 10_000;
+pub const MAX_TAGS: usize = 5;
+pub const MAX_TAG_LENGTH: usize = 32;
+/// `users.email` is a varchar(255)
+pub const MAX_EMAIL_LENGTH: usize = 255;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Hashing cost grows with the input, so don't accept arbitrarily long ones
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+/// `roles.name` is a varchar(32)
+pub const MAX_ROLE_LENGTH: usize = 32;
+/// Room for a recovery code typed with spaces
+pub const MAX_TOTP_CODE_LENGTH: usize = 32;
+/// `api_keys.name` is a varchar(100)
+pub const MAX_API_KEY_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rule {
+    /// Not empty or only whitespace
+    Required,
+    /// At least this many characters
+    MinChars(usize),
+    /// At most this many characters
+    MaxChars(usize),
+    /// At least this many items in a list
+    MinItems(usize),
+    /// At most this many items in a list
+    MaxItems(usize),
+    /// Letters, digits and `-_.+#` only
+    Tag,
+    /// Something that looks like `local@domain.tld`
+    Email,
+}
+
+impl Rule {
+    fn check_text(&self, value: &str) -> Option<String> {
+        match *self {
+            Rule::Required if value.trim().is_empty() => Some("must not be empty".to_string()),
+            Rule::MinChars(min) if value.chars().count() < min => {
+                Some(format!("must be at least {} characters", min))
+            }
+            Rule::MaxChars(max) if value.chars().count() > max => {
+                Some(format!("must be at most {} characters", max))
+            }
+            Rule::Tag
+                if !value
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || "-_.+#".contains(c)) =>
+            {
+                Some("may only contain letters, digits and -_.+#".to_string())
+            }
+            Rule::Email if !is_email(value) => Some("must be an email address".to_string()),
+            _ => None,
+        }
+    }
+
+    fn check_list<T>(&self, values: &[T]) -> Option<String> {
+        match *self {
+            Rule::MinItems(min) if values.len() < min => {
+                Some(format!("must have at least {} item(s)", min))
+            }
+            Rule::MaxItems(max) if values.len() > max => {
+                Some(format!("must have at most {} items", max))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Surrounding whitespace is allowed, it's trimmed before the email is stored.
+fn is_email(value: &str) -> bool {
+    let value = value.trim();
+
+    match value.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|label| !label.is_empty())
+                && !value.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// Collects every rule violation in a request body.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    fn fail(&mut self, pointer: String, reason: String) {
+        self.errors.push(FieldError { pointer, reason });
+    }
+
+    pub fn text(mut self, pointer: &str, value: &str, rules: &[Rule]) -> Self {
+        if let Some(reason) = rules.iter().find_map(|rule| rule.check_text(value)) {
+            self.fail(pointer.to_string(), reason);
+        }
+
+        self
+    }
+
+    pub fn list(mut self, pointer: &str, values: &[String], rules: &[Rule]) -> Self {
+        if let Some(reason) = rules.iter().find_map(|rule| rule.check_list(values)) {
+            self.fail(pointer.to_string(), reason);
+        }
+
+        for (index, value) in values.iter().enumerate() {
+            if let Some(reason) = rules.iter().find_map(|rule| rule.check_text(value)) {
+                self.fail(format!("{}/{}", pointer, index), reason);
+            }
+        }
+
+        self
+    }
+
+    pub fn positive(mut self, pointer: &str, value: i32) -> Self {
+        if value < 1 {
+            self.fail(pointer.to_string(), "must be a positive id".to_string());
+        }
+
+        self
+    }
+
+    pub fn future(mut self, pointer: &str, value: Option<DateTime<Utc>>) -> Self {
+        if value.is_some_and(|value| value <= Utc::now()) {
+            self.fail(pointer.to_string(), "must be in the future".to_string());
+        }
+
+        self
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(self.errors))
+        }
+    }
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), Error>;
+}
+
+/// Request bodies that carry the id of the resource they update.
+pub trait Identified {
+    fn id(&self) -> i32;
+}
+
+fn question_rules(title: &str, content: &str, tags: &Option<Vec<String>>) -> Validator {
+    Validator::default()
+        .text(
+            "/title",
+            title,
+            &[Rule::Required, Rule::MaxChars(MAX_TITLE_LENGTH)],
+        )
+        .text(
+            "/content",
+            content,
+            &[Rule::Required, Rule::MaxChars(MAX_CONTENT_LENGTH)],
+        )
+        .list(
+            "/tags",
+            tags.as_deref().unwrap_or_default(),
+            &[
+                Rule::MaxItems(MAX_TAGS),
+                Rule::Required,
+                Rule::MaxChars(MAX_TAG_LENGTH),
+                Rule::Tag,
+            ],
+        )
+}
+
+impl Validate for NewQuestion {
+    fn validate(&self) -> Result<(), Error> {
+        question_rules(&self.title, &self.content, &self.tags).finish()
+    }
+}
+
+impl Validate for Question {
+    fn validate(&self) -> Result<(), Error> {
+        question_rules(&self.title, &self.content, &self.tags).finish()
+    }
+}
+
+impl Identified for Question {
+    fn id(&self) -> i32 {
+        self.id.0
+    }
+}
+
+impl Validate for NewAnswer {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/content",
+                &self.content,
+                &[Rule::Required, Rule::MaxChars(MAX_CONTENT_LENGTH)],
+            )
+            .positive("/question_id", self.question_id.0)
+            .finish()
+    }
+}
+
+impl Validate for Answer {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/content",
+                &self.content,
+                &[Rule::Required, Rule::MaxChars(MAX_CONTENT_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+impl Identified for Answer {
+    fn id(&self) -> i32 {
+        self.id.0
+    }
+}
+
+impl Validate for NewUser {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/email",
+                &self.email,
+                &[
+                    Rule::Required,
+                    Rule::MaxChars(MAX_EMAIL_LENGTH),
+                    Rule::Email,
+                ],
+            )
+            .text(
+                "/password",
+                &self.password,
+                &[
+                    Rule::MinChars(MIN_PASSWORD_LENGTH),
+                    Rule::MaxChars(MAX_PASSWORD_LENGTH),
+                ],
+            )
+            .finish()
+    }
+}
+
+/// Only checks what's needed to attempt a login, so that a password set
+/// under older rules still works.
+impl Validate for Credentials {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text("/email", &self.email, &[Rule::Required])
+            .text(
+                "/password",
+                &self.password,
+                &[Rule::Required, Rule::MaxChars(MAX_PASSWORD_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+impl Validate for RefreshRequest {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text("/refresh_token", &self.refresh_token, &[Rule::Required])
+            .finish()
+    }
+}
+
+impl Validate for EmailVerification {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text("/token", &self.token, &[Rule::Required])
+            .finish()
+    }
+}
+
+impl Validate for PasswordResetRequest {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/email",
+                &self.email,
+                &[Rule::Required, Rule::MaxChars(MAX_EMAIL_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+impl Validate for PasswordReset {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text("/token", &self.token, &[Rule::Required])
+            .text(
+                "/password",
+                &self.password,
+                &[
+                    Rule::MinChars(MIN_PASSWORD_LENGTH),
+                    Rule::MaxChars(MAX_PASSWORD_LENGTH),
+                ],
+            )
+            .finish()
+    }
+}
+
+impl Validate for TotpCode {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/code",
+                &self.code,
+                &[Rule::Required, Rule::MaxChars(MAX_TOTP_CODE_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+impl Validate for TotpLogin {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text("/challenge_token", &self.challenge_token, &[Rule::Required])
+            .text(
+                "/code",
+                &self.code,
+                &[Rule::Required, Rule::MaxChars(MAX_TOTP_CODE_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+impl Validate for NewApiKey {
+    fn validate(&self) -> Result<(), Error> {
+        let scopes: Vec<String> = self
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+
+        Validator::default()
+            .text(
+                "/name",
+                &self.name,
+                &[Rule::Required, Rule::MaxChars(MAX_API_KEY_NAME_LENGTH)],
+            )
+            .list("/scopes", &scopes, &[Rule::MinItems(1)])
+            .future("/expires_at", self.expires_at)
+            .finish()
+    }
+}
+
+impl Validate for RoleGrant {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::default()
+            .text(
+                "/role",
+                &self.role,
+                &[Rule::Required, Rule::MaxChars(MAX_ROLE_LENGTH)],
+            )
+            .finish()
+    }
+}
+
+/// A JSON request body that has passed its validation rules.
+pub fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    filters::json_body::<T>().and_then(|body: T| async move {
+        body.validate().map_err(warp::reject::custom)?;
+        Ok::<_, Rejection>(body)
+    })
+}
+
+/// Checks that the `id` of a body updating a resource matches the one in
+/// the path.
+pub fn matching_id<T: Identified>(id: i32, body: &T) -> Result<(), Error> {
+    if body.id() != id {
+        return Err(Error::Validation(vec![FieldError {
+            pointer: "/id".to_string(),
+            reason: format!("must match the id in the path ({})", id),
+        }]));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::{
+        routes::testing::TestApp,
+        types::{answer::AnswerId, question::QuestionId},
+    };
+
+    fn pointers(result: Result<(), Error>) -> Vec<(String, String)> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(Error::Validation(errors)) => errors
+                .into_iter()
+                .map(|error| (error.pointer, error.reason))
+                .collect(),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    fn new_question(title: &str, content: &str, tags: Option<Vec<&str>>) -> NewQuestion {
+        NewQuestion {
+            title: title.to_string(),
+            content: content.to_string(),
+            tags: tags.map(|tags| tags.into_iter().map(str::to_owned).collect()),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_question() {
+        let question = new_question("title", "content", Some(vec!["rust", "c++", "c#"]));
+
+        assert!(question.validate().is_ok());
+        assert!(new_question("title", "content", None).validate().is_ok());
+    }
+
+    #[test]
+    fn requires_text() {
+        assert_eq!(
+            pointers(new_question(" ", "", None).validate()),
+            vec![
+                ("/title".to_string(), "must not be empty".to_string()),
+                ("/content".to_string(), "must not be empty".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_length_in_characters() {
+        let title = "é".repeat(MAX_TITLE_LENGTH);
+        assert!(new_question(&title, "content", None).validate().is_ok());
+
+        let title = "é".repeat(MAX_TITLE_LENGTH + 1);
+        let content = "x".repeat(MAX_CONTENT_LENGTH + 1);
+
+        assert_eq!(
+            pointers(new_question(&title, &content, None).validate()),
+            vec![
+                (
+                    "/title".to_string(),
+                    "must be at most 255 characters".to_string()
+                ),
+                (
+                    "/content".to_string(),
+                    "must be at most 10000 characters".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_tag_count() {
+        let tags = vec!["a"; MAX_TAGS + 1];
+
+        assert_eq!(
+            pointers(new_question("title", "content", Some(tags)).validate()),
+            vec![("/tags".to_string(), "must have at most 5 items".to_string())]
+        );
+    }
+
+    #[test]
+    fn points_at_each_bad_tag() {
+        let long = "x".repeat(MAX_TAG_LENGTH + 1);
+        let tags = vec!["ok", "", "has space", &long];
+
+        assert_eq!(
+            pointers(new_question("title", "content", Some(tags)).validate()),
+            vec![
+                ("/tags/1".to_string(), "must not be empty".to_string()),
+                (
+                    "/tags/2".to_string(),
+                    "may only contain letters, digits and -_.+#".to_string()
+                ),
+                (
+                    "/tags/3".to_string(),
+                    "must be at most 32 characters".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_a_positive_question_id() {
+        let answer = NewAnswer {
+            content: "content".to_string(),
+            question_id: QuestionId(0),
+        };
+
+        assert_eq!(
+            pointers(answer.validate()),
+            vec![(
+                "/question_id".to_string(),
+                "must be a positive id".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn checks_emails_and_passwords() {
+        let user = NewUser {
+            email: "not-an-email".to_string(),
+            password: "short".to_string(),
+        };
+
+        assert_eq!(
+            pointers(user.validate()),
+            vec![
+                ("/email".to_string(), "must be an email address".to_string()),
+                (
+                    "/password".to_string(),
+                    "must be at least 8 characters".to_string()
+                ),
+            ]
+        );
+
+        for email in ["a@b.c", " a@b.co "] {
+            assert!(is_email(email), "{}", email);
+        }
+
+        for email in ["a@b", "@b.c", "a@@b.c", "a@b..c", "a b@c.d"] {
+            assert!(!is_email(email), "{}", email);
+        }
+    }
+
+    #[test]
+    fn body_id_must_match_path() {
+        let answer = Answer {
+            id: AnswerId(2),
+            content: "content".to_string(),
+            question_id: QuestionId(1),
+            account_id: None,
+            created_at: None,
+            updated_at: None,
+        };
+
+        assert!(matching_id(2, &answer).is_ok());
+        assert_eq!(
+            pointers(matching_id(3, &answer)),
+            vec![(
+                "/id".to_string(),
+                "must match the id in the path (3)".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_pointers_in_the_problem() {
+        let app = TestApp::new();
+        let (_, authorization) = app.user("alice@example.com").await;
+
+        let (status, problem) = app
+            .request(
+                "POST",
+                "/questions",
+                Some(&authorization),
+                Some(json!({ "title": "", "content": "content", "tags": ["ok", "not ok"] })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(
+            problem["errors"],
+            json!([
+                { "pointer": "/title", "reason": "must not be empty" },
+                {
+                    "pointer": "/tags/1",
+                    "reason": "may only contain letters, digits and -_.+#"
+                },
+            ])
+        );
+
+        let (status, problem) = app
+            .request(
+                "PUT",
+                "/questions/1",
+                Some(&authorization),
+                Some(json!({ "id": 2, "title": "title", "content": "content" })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["pointer"], "/id");
+    }
+}